@@ -1,19 +1,30 @@
 use anyhow::{bail, Context, Result};
 use log::{error, trace, LevelFilter, Log, SetLoggerError};
-use services::PluginService;
+use services::settings::{SettingValue, SettingsLayer};
+use services::{PluginService, SettingsResult};
 use std::path::{Path, PathBuf};
-use std::ffi::CStr;
-use vfs::Vfs;
+use std::ffi::{CStr, CString};
+use vfs::{Vfs, VfsConfig};
 use std::os::raw::c_char;
 
+pub mod metadata_api;
 pub mod output;
 pub mod playback;
 pub mod plugin_handler;
 pub mod playlist;
+pub mod query;
+pub mod scanner;
+pub mod songlengths;
+pub mod stil;
+pub mod vfs_plugin;
 
 use plugin_handler::Plugins;
 use playlist::Playlist;
-use playback::Playback;
+use metadata_api::{CoreMetadata, CoreQueryResult};
+use playback::{Playback, PlaybackMessage};
+use scanner::{ScanContext, Scanner};
+use songlengths::SongLengths;
+use stil::Stil;
 use output::Output;
 
 
@@ -27,40 +38,119 @@ pub struct Args {
     pub play: Vec<String>,
     /// Randomize the playing
     pub randomize: bool,
+    /// Rewrite the metadata database without replaced records on startup
+    pub compact_metadata: bool,
 }
 
+// Name of the metadata database in the data directory
+const METADATA_DB_NAME: &str = "metadata.jsonl";
+// Directory in the data directory where plugin settings that differs from the defaults are stored
+const SETTINGS_DIR_NAME: &str = "settings";
+// Song length databases loaded from the data directory (if present)
+const SONG_LENGTHS_NAMES: &[&str] = &["Songlengths.md5", "songlengths.txt"];
+// HVSC tune information loaded from the data directory (if present)
+const STIL_NAMES: &[&str] = &["STIL.txt", "BUGlist.txt"];
+
 pub struct Core {
     pub plugin_service: PluginService,
     pub plugins: Plugins,
     pub playlist: Playlist,
     pub vfs: Vfs,
     pub output: Output,
+    pub playback: Playback,
+    /// Collects metadata for the library in the background
+    pub scanner: Scanner,
+    pub song_lengths: SongLengths,
+    pub stil: Stil,
+    scan_context: ScanContext,
 }
 
 impl Core {
     pub fn new(args: &Args) -> Box<Core> {
         // TODO: Fix unwraps
         let mut plugins = Plugins::default();
-        let vfs = Vfs::new();
+        // Metadata is keyed by content so files are hashed when loaded
+        let vfs = Vfs::new_with_config(VfsConfig {
+            hash_md5: true,
+            ..Default::default()
+        });
+
         let plugin_service = PluginService::new("core", vfs.clone());
+        let metadata = plugin_service.metadata();
+
+        match metadata.open_db(&args.data_dir.join(METADATA_DB_NAME)) {
+            Err(e) => error!("Unable to open metadata database: {:?}", e),
+            Ok(()) if args.compact_metadata => {
+                if let Err(e) = metadata.compact() {
+                    error!("Unable to compact metadata database: {:?}", e);
+                }
+            }
+            Ok(()) => (),
+        }
+
+        // Plugins register their settings when loaded and get the stored values at that point
+        plugin_service.settings().set_dir(&args.data_dir.join(SETTINGS_DIR_NAME));
 
         // Add plugins
         for path in &args.plugin_paths {
             plugins.add_plugins_from_path(path, &plugin_service);
         }
 
+        vfs_plugin::register_vfs_plugins(&plugins.vfs_plugins, &vfs);
+
+        let song_lengths = SongLengths::new();
+
+        for name in SONG_LENGTHS_NAMES {
+            let path = args.data_dir.join(name);
+
+            if path.exists() {
+                if let Err(e) = song_lengths.load(&path) {
+                    error!("{:?}", e);
+                }
+            }
+        }
+
+        let stil = Stil::new();
+
+        for name in STIL_NAMES {
+            let path = args.data_dir.join(name);
+
+            if path.exists() {
+                if let Err(e) = stil.load(&path) {
+                    error!("{:?}", e);
+                }
+            }
+        }
+
+        stil.apply_all(metadata);
+
         let playback = Playback::new(plugins.resample_plugins.clone()).unwrap();
-        let playlist = Playlist::new(&vfs, &playback, plugins.decoder_plugins.clone()).unwrap();
+        let playlist = Playlist::new(&vfs, &playback, plugins.decoder_plugins.clone(), &song_lengths).unwrap();
         let mut output = Output::new(&playback, plugins.output_plugins.clone());
 
         output.create_default_output();
 
+        let scan_context = ScanContext {
+            vfs: vfs.clone(),
+            plugins: plugins.decoder_plugins.clone(),
+            service: plugin_service.clone(),
+            song_lengths: song_lengths.clone(),
+            stil: stil.clone(),
+        };
+
+        let scanner = Scanner::new(scan_context.clone()).unwrap();
+
         Box::new(Core {
             plugin_service,
             plugins,
             vfs,
             playlist,
             output,
+            playback,
+            scanner,
+            song_lengths,
+            stil,
+            scan_context,
         })
     }
 
@@ -69,8 +159,57 @@ impl Core {
     }
 
     pub fn update(&mut self) -> u64 {
+        self.flush_metadata();
+        self.update_settings();
         self.output.get_position()
     }
+
+    /// Lets the live plugin instances know about settings changed since the last call and saves the changes
+    pub fn update_settings(&self) {
+        let changed = self.plugin_service.settings().take_changed();
+
+        if changed.is_empty() {
+            return;
+        }
+
+        trace!("Settings changed for {:?}", changed);
+        // Only fails if the playback thread has stopped in which case there is nothing to notify
+        let _ = self.playback.channel.send(PlaybackMessage::SettingsUpdated(changed.clone()));
+
+        for reg_id in &changed {
+            if let Err(e) = self.plugin_service.settings().save(reg_id) {
+                error!("Unable to save settings for {}: {:?}", reg_id, e);
+            }
+        }
+    }
+
+    /// Collects metadata for the url without playing it. See [`scanner::scan_metadata`]
+    pub fn scan_metadata(&self, url: &str) -> Result<services::metadata::SongMetadata> {
+        scanner::scan_metadata(&self.scan_context, url)
+    }
+
+    /// Searches the metadata of the library. See [`query`] for the syntax
+    pub fn query(&self, query: &str, offset: usize, limit: usize) -> Result<query::QueryResult> {
+        let query = query::Query::parse(query)?;
+        Ok(query.run(self.plugin_service.metadata(), offset, limit))
+    }
+
+    /// Writes metadata reported since the last call to the database
+    pub fn flush_metadata(&self) {
+        if let Err(e) = self.plugin_service.metadata().flush() {
+            error!("Unable to write metadata: {:?}", e);
+        }
+    }
+}
+
+impl Drop for Core {
+    fn drop(&mut self) {
+        self.flush_metadata();
+
+        if let Err(e) = self.plugin_service.settings().save_all() {
+            error!("Unable to save settings: {:?}", e);
+        }
+    }
 }
 
 /// Finds the data directory relative to the executable.
@@ -154,6 +293,7 @@ fn init_core_create() -> Result<Args> {
         plugin_paths: get_dirs_files(&mut pargs, "--plugins")?,
         play: get_dirs_files(&mut pargs, "--play")?,
         randomize: pargs.contains("--randomize"),
+        compact_metadata: pargs.contains("--compact-metadata"),
     };
 
     args.plugin_paths.push("../../../bin/plugins".to_string());
@@ -205,6 +345,392 @@ pub unsafe fn core_load_url(core: *mut Core, url: *const c_char) {
     core.load_url(&name.to_string_lossy());
 }
 
+/// Rewrites the metadata database with only the current entries. Returns false if it failed (the error is logged)
+///
+/// # Safety
+///
+/// core has to be a valid core created with `core_create`
+#[no_mangle]
+pub unsafe extern "C" fn core_compact_metadata(core: *mut Core) -> bool {
+    let core: &mut Core = &mut *core;
+
+    match core.plugin_service.metadata().compact() {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Unable to compact metadata database: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Get the metadata reported for a url. Returns null if nothing is known about it. The result has to be freed
+/// with `core_metadata_free`
+///
+/// # Safety
+///
+/// core has to be a valid core and url a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn core_get_metadata(core: *mut Core, url: *const c_char) -> *const CoreMetadata {
+    let core: &mut Core = &mut *core;
+    let url = CStr::from_ptr(url);
+
+    match core.plugin_service.get_metadata_for_url(&url.to_string_lossy()) {
+        Some(metadata) => metadata_api::create(&metadata),
+        None => std::ptr::null(),
+    }
+}
+
+/// Get the metadata for the song currently playing. Returns null if nothing is playing or nothing is known
+/// about the song. The result has to be freed with `core_metadata_free`
+///
+/// # Safety
+///
+/// core has to be a valid core
+#[no_mangle]
+pub unsafe extern "C" fn core_get_current_metadata(core: *mut Core) -> *const CoreMetadata {
+    let core: &mut Core = &mut *core;
+
+    match core.playlist.current_url().and_then(|url| core.plugin_service.get_metadata_for_url(&url)) {
+        Some(metadata) => metadata_api::create(&metadata),
+        None => std::ptr::null(),
+    }
+}
+
+/// Loads the url and lets the plugin that supports it report metadata without playing it. Returns null on
+/// failure (the reason is logged). The result has to be freed with `core_metadata_free`
+///
+/// # Safety
+///
+/// core has to be a valid core and url a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn core_scan_metadata(core: *mut Core, url: *const c_char) -> *const CoreMetadata {
+    let core: &mut Core = &mut *core;
+    let url = CStr::from_ptr(url).to_string_lossy();
+
+    match core.scan_metadata(&url) {
+        Ok(metadata) => metadata_api::create(&metadata),
+        Err(e) => {
+            error!("Unable to scan {}: {:?}", url, e);
+            std::ptr::null()
+        }
+    }
+}
+
+/// Loads a song length database (HVSC Songlengths.md5 or a list of `md5=length`). Returns the number of
+/// entries loaded or -1 on error
+///
+/// # Safety
+///
+/// core has to be a valid core and path a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn core_load_song_lengths(core: *mut Core, path: *const c_char) -> i64 {
+    let core: &mut Core = &mut *core;
+    let path = CStr::from_ptr(path).to_string_lossy();
+
+    match core.song_lengths.load(Path::new(path.as_ref())) {
+        Ok(count) => count as _,
+        Err(e) => {
+            error!("{:?}", e);
+            -1
+        }
+    }
+}
+
+/// Loads HVSC `STIL.txt` or `BUGlist.txt` and attaches the entries to the metadata of the tunes they belong
+/// to. Returns the number of entries loaded or -1 on error
+///
+/// # Safety
+///
+/// core has to be a valid core and path a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn core_load_stil(core: *mut Core, path: *const c_char) -> i64 {
+    let core: &mut Core = &mut *core;
+    let path = CStr::from_ptr(path).to_string_lossy();
+
+    match core.stil.load(Path::new(path.as_ref())) {
+        Ok(count) => {
+            core.stil.apply_all(core.plugin_service.metadata());
+            count as _
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            -1
+        }
+    }
+}
+
+/// Sets an integer setting registered by a plugin. The value has to be in the range of the setting (or one of
+/// its choices). Live plugin instances are notified on the next `core_update`
+///
+/// # Safety
+///
+/// core has to be a valid core and reg_id and id valid C strings
+#[no_mangle]
+pub unsafe extern "C" fn core_settings_set_int(core: *mut Core, reg_id: *const c_char, id: *const c_char, value: i32) -> SettingsResult {
+    let core: &mut Core = &mut *core;
+    let reg_id = CStr::from_ptr(reg_id).to_string_lossy();
+    let id = CStr::from_ptr(id).to_string_lossy();
+    core.plugin_service.settings().set_int(&reg_id, &id, value)
+}
+
+/// Sets a float setting registered by a plugin. The value has to be in the range of the setting. Live plugin
+/// instances are notified on the next `core_update`
+///
+/// # Safety
+///
+/// core has to be a valid core and reg_id and id valid C strings
+#[no_mangle]
+pub unsafe extern "C" fn core_settings_set_float(core: *mut Core, reg_id: *const c_char, id: *const c_char, value: f32) -> SettingsResult {
+    let core: &mut Core = &mut *core;
+    let reg_id = CStr::from_ptr(reg_id).to_string_lossy();
+    let id = CStr::from_ptr(id).to_string_lossy();
+    core.plugin_service.settings().set_float(&reg_id, &id, value)
+}
+
+/// Sets a bool setting registered by a plugin. Live plugin instances are notified on the next `core_update`
+///
+/// # Safety
+///
+/// core has to be a valid core and reg_id and id valid C strings
+#[no_mangle]
+pub unsafe extern "C" fn core_settings_set_bool(core: *mut Core, reg_id: *const c_char, id: *const c_char, value: bool) -> SettingsResult {
+    let core: &mut Core = &mut *core;
+    let reg_id = CStr::from_ptr(reg_id).to_string_lossy();
+    let id = CStr::from_ptr(id).to_string_lossy();
+    core.plugin_service.settings().set_bool(&reg_id, &id, value)
+}
+
+/// Sets a free text or path setting registered by a plugin or selects one of the choices of a string setting.
+/// Live plugin instances are notified on the next `core_update`
+///
+/// # Safety
+///
+/// core has to be a valid core and reg_id, id and value valid C strings
+#[no_mangle]
+pub unsafe extern "C" fn core_settings_set_string(core: *mut Core, reg_id: *const c_char, id: *const c_char, value: *const c_char) -> SettingsResult {
+    let core: &mut Core = &mut *core;
+    let reg_id = CStr::from_ptr(reg_id).to_string_lossy();
+    let id = CStr::from_ptr(id).to_string_lossy();
+    let value = CStr::from_ptr(value).to_string_lossy();
+    core.plugin_service.settings().set_string(&reg_id, &id, &value)
+}
+
+/// Layer a setting value applies to. Song values take precedence over extension values which take precedence
+/// over global values
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoreSettingsLayer {
+    Global = 0,
+    /// The key is a file extension
+    Extension = 1,
+    /// The key is the url or content hash (MD5 as hex) of a song
+    Song = 2,
+}
+
+// The key isn't used (and may be null) for the global layer
+unsafe fn settings_layer(layer: CoreSettingsLayer, key: *const c_char) -> SettingsLayer {
+    match layer {
+        CoreSettingsLayer::Global => SettingsLayer::Global,
+        CoreSettingsLayer::Extension => SettingsLayer::Extension(CStr::from_ptr(key).to_string_lossy().into_owned()),
+        CoreSettingsLayer::Song => SettingsLayer::Song(CStr::from_ptr(key).to_string_lossy().into_owned()),
+    }
+}
+
+unsafe fn settings_set(core: *mut Core, layer: CoreSettingsLayer, key: *const c_char, reg_id: *const c_char, id: *const c_char, value: SettingValue) -> SettingsResult {
+    let core: &mut Core = &mut *core;
+    let layer = settings_layer(layer, key);
+    let reg_id = CStr::from_ptr(reg_id).to_string_lossy();
+    let id = CStr::from_ptr(id).to_string_lossy();
+    core.plugin_service.settings().set(&reg_id, &layer, &id, &value)
+}
+
+/// Sets an integer setting for an extension or song (or globally). See `core_settings_set_int`
+///
+/// # Safety
+///
+/// core has to be a valid core, reg_id and id valid C strings and key a valid C string unless the layer is global
+#[no_mangle]
+pub unsafe extern "C" fn core_settings_override_int(core: *mut Core, layer: CoreSettingsLayer, key: *const c_char, reg_id: *const c_char, id: *const c_char, value: i32) -> SettingsResult {
+    settings_set(core, layer, key, reg_id, id, SettingValue::Int(value))
+}
+
+/// Sets a float setting for an extension or song (or globally). See `core_settings_set_float`
+///
+/// # Safety
+///
+/// core has to be a valid core, reg_id and id valid C strings and key a valid C string unless the layer is global
+#[no_mangle]
+pub unsafe extern "C" fn core_settings_override_float(core: *mut Core, layer: CoreSettingsLayer, key: *const c_char, reg_id: *const c_char, id: *const c_char, value: f32) -> SettingsResult {
+    settings_set(core, layer, key, reg_id, id, SettingValue::Float(value))
+}
+
+/// Sets a bool setting for an extension or song (or globally). See `core_settings_set_bool`
+///
+/// # Safety
+///
+/// core has to be a valid core, reg_id and id valid C strings and key a valid C string unless the layer is global
+#[no_mangle]
+pub unsafe extern "C" fn core_settings_override_bool(core: *mut Core, layer: CoreSettingsLayer, key: *const c_char, reg_id: *const c_char, id: *const c_char, value: bool) -> SettingsResult {
+    settings_set(core, layer, key, reg_id, id, SettingValue::Bool(value))
+}
+
+/// Sets a string setting for an extension or song (or globally). See `core_settings_set_string`
+///
+/// # Safety
+///
+/// core has to be a valid core, reg_id, id and value valid C strings and key a valid C string unless the layer
+/// is global
+#[no_mangle]
+pub unsafe extern "C" fn core_settings_override_string(core: *mut Core, layer: CoreSettingsLayer, key: *const c_char, reg_id: *const c_char, id: *const c_char, value: *const c_char) -> SettingsResult {
+    let value = CStr::from_ptr(value).to_string_lossy().into_owned();
+    settings_set(core, layer, key, reg_id, id, SettingValue::Str(value))
+}
+
+/// Removes the value of a setting for an extension or song so the value of the layer below is used. Clearing a
+/// global value restores the default of the plugin
+///
+/// # Safety
+///
+/// core has to be a valid core, reg_id and id valid C strings and key a valid C string unless the layer is global
+#[no_mangle]
+pub unsafe extern "C" fn core_settings_clear(core: *mut Core, layer: CoreSettingsLayer, key: *const c_char, reg_id: *const c_char, id: *const c_char) -> SettingsResult {
+    let core: &mut Core = &mut *core;
+    let layer = settings_layer(layer, key);
+    let reg_id = CStr::from_ptr(reg_id).to_string_lossy();
+    let id = CStr::from_ptr(id).to_string_lossy();
+    core.plugin_service.settings().clear(&reg_id, &layer, &id)
+}
+
+/// Schema of all plugin settings as JSON for building settings uis. Each entry has the `reg_id` and the
+/// `settings` registered under it with `id`, `name`, `description`, `type` (int, float, bool, int_choice,
+/// string_choice, string or path), `min` and `max` (if the setting has a range), `choices` (name and value), `default` and
+/// `value`. Returns null on error. The result has to be freed with `core_settings_schema_free`
+///
+/// # Safety
+///
+/// core has to be a valid core
+#[no_mangle]
+pub unsafe extern "C" fn core_settings_schema(core: *mut Core) -> *const c_char {
+    let core: &mut Core = &mut *core;
+
+    match core.plugin_service.settings().schema_json() {
+        // JSON escapes control characters so there can't be any nul bytes
+        Ok(json) => CString::new(json).unwrap().into_raw(),
+        Err(e) => {
+            error!("Unable to build settings schema: {:?}", e);
+            std::ptr::null()
+        }
+    }
+}
+
+/// # Safety
+///
+/// schema has to be null or returned by `core_settings_schema` and not freed before
+#[no_mangle]
+pub unsafe extern "C" fn core_settings_schema_free(schema: *const c_char) {
+    if !schema.is_null() {
+        drop(CString::from_raw(schema as *mut c_char));
+    }
+}
+
+/// Searches the metadata of the library and returns `limit` results starting at `offset`. If the query is
+/// invalid `error` in the result is set. The result has to be freed with `core_query_free`
+///
+/// # Safety
+///
+/// core has to be a valid core and query a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn core_query(core: *mut Core, query: *const c_char, offset: u64, limit: u64) -> *const CoreQueryResult {
+    let core: &mut Core = &mut *core;
+    let query = CStr::from_ptr(query).to_string_lossy();
+
+    metadata_api::create_query_result(core.query(&query, offset as _, limit as _))
+}
+
+/// # Safety
+///
+/// result has to be null or returned by `core_query` and not freed before
+#[no_mangle]
+pub unsafe extern "C" fn core_query_free(result: *const CoreQueryResult) {
+    metadata_api::free_query_result(result);
+}
+
+/// State of the library scanner. See [`scanner::ScanStatus`]
+#[repr(C)]
+pub struct CoreScanStatus {
+    pub scanned: u64,
+    pub unchanged: u64,
+    pub unsupported: u64,
+    pub failed: u64,
+    pub pending: u64,
+    pub running: bool,
+    pub paused: bool,
+}
+
+/// Adds a root (local directory, archive or ftp url) to the library and starts scanning it in the background
+///
+/// # Safety
+///
+/// core has to be a valid core and url a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn core_scanner_add_root(core: *mut Core, url: *const c_char) {
+    let core: &mut Core = &mut *core;
+    core.scanner.add_root(&CStr::from_ptr(url).to_string_lossy());
+}
+
+/// Scans all roots again. Files with unchanged content are skipped
+///
+/// # Safety
+///
+/// core has to be a valid core
+#[no_mangle]
+pub unsafe extern "C" fn core_scanner_rescan(core: *mut Core) {
+    let core: &mut Core = &mut *core;
+    core.scanner.rescan();
+}
+
+/// # Safety
+///
+/// core has to be a valid core
+#[no_mangle]
+pub unsafe extern "C" fn core_scanner_pause(core: *mut Core, pause: bool) {
+    let core: &mut Core = &mut *core;
+
+    if pause {
+        core.scanner.pause();
+    } else {
+        core.scanner.resume();
+    }
+}
+
+/// # Safety
+///
+/// core has to be a valid core
+#[no_mangle]
+pub unsafe extern "C" fn core_scanner_status(core: *mut Core) -> CoreScanStatus {
+    let core: &mut Core = &mut *core;
+    let status = core.scanner.status();
+
+    CoreScanStatus {
+        scanned: status.scanned as _,
+        unchanged: status.unchanged as _,
+        unsupported: status.unsupported as _,
+        failed: status.failed as _,
+        pending: status.pending as _,
+        running: status.is_running(),
+        paused: status.paused,
+    }
+}
+
+/// # Safety
+///
+/// metadata has to be null or returned by `core_get_metadata`/`core_get_current_metadata`/`core_scan_metadata`
+/// and not freed before
+#[no_mangle]
+pub unsafe extern "C" fn core_metadata_free(metadata: *const CoreMetadata) {
+    metadata_api::free(metadata);
+}
+
 #[no_mangle]
 pub fn core_setup_logger(
     logger: &'static dyn Log,
@@ -223,4 +749,5 @@ const HELP: &str = "  --data-dir    PATH    Override data directory.
   --plugins     PATH    Overide the paths for plugins. Both filenames and directories are supported 
   --play        PATH    Select file(s) to play. Depending on supported sources, urls may be used here as well.
   --randomize           Randomize the files to play if there are more than one.
+  --compact-metadata    Remove replaced entries from the metadata database on startup.
 ";
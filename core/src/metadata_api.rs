@@ -0,0 +1,326 @@
+//! C view of the metadata plugins has reported. All strings and arrays are owned by the returned
+//! [`CoreMetadata`] (or [`CoreQueryResult`]) and stay valid until it's freed with `core_metadata_free`
+//! (or `core_query_free`).
+
+use services::metadata::{SongMetadata, TagValue};
+use services::{
+    RV_METADATA_ARTIST_TAG, RV_METADATA_AUTHORINGTOOL_TAG, RV_METADATA_LENGTH_TAG, RV_METADATA_MESSAGE_TAG,
+    RV_METADATA_SONGTYPE_TAG, RV_METADATA_TITLE_TAG,
+};
+use crate::query::QueryResult;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::ptr;
+
+/// A tag as reported by the plugin. `str_value` is null for numeric tags
+#[repr(C)]
+pub struct CoreMetadataTag {
+    pub name: *const c_char,
+    pub str_value: *const c_char,
+    pub f64_value: f64,
+}
+
+#[repr(C)]
+pub struct CoreSubsong {
+    pub index: u32,
+    pub name: *const c_char,
+    /// Length in seconds (0 or negative if unknown)
+    pub length: f32,
+}
+
+/// Metadata for a url. The common tags are broken out (null if the plugin didn't report them) and all tags
+/// are also available in `tags`. Arrays are `*_count` entries long.
+#[repr(C)]
+pub struct CoreMetadata {
+    pub url: *const c_char,
+    pub title: *const c_char,
+    pub artist: *const c_char,
+    pub song_type: *const c_char,
+    pub authoring_tool: *const c_char,
+    pub message: *const c_char,
+    /// Length in seconds. Negative if unknown
+    pub length: f64,
+    pub tags: *const CoreMetadataTag,
+    pub tags_count: u32,
+    pub subsongs: *const CoreSubsong,
+    pub subsongs_count: u32,
+    pub samples: *const *const c_char,
+    pub samples_count: u32,
+    pub instruments: *const *const c_char,
+    pub instruments_count: u32,
+}
+
+/// A page of query results. If the query is invalid `error` describes why and there are no entries
+#[repr(C)]
+pub struct CoreQueryResult {
+    pub error: *const c_char,
+    /// Total number of matches (not only the ones in this page)
+    pub total: u64,
+    pub entries: *const CoreMetadata,
+    pub entries_count: u32,
+}
+
+/// Data the pointers in a CoreMetadata points to
+struct Storage {
+    _strings: Vec<CString>,
+    _tags: Vec<CoreMetadataTag>,
+    _subsongs: Vec<CoreSubsong>,
+    _samples: Vec<*const c_char>,
+    _instruments: Vec<*const c_char>,
+}
+
+/// Owns the data pointed to by `c`. `c` has to be the first field as the pointer to it is what's handed out
+#[repr(C)]
+struct OwnedMetadata {
+    c: CoreMetadata,
+    _storage: Storage,
+}
+
+/// Owns the data pointed to by `c`. `c` has to be the first field as the pointer to it is what's handed out
+#[repr(C)]
+struct OwnedQueryResult {
+    c: CoreQueryResult,
+    _error: Option<CString>,
+    _entries: Vec<CoreMetadata>,
+    _storage: Vec<Storage>,
+}
+
+// Keeps a copy of the string alive in strings and returns a pointer to it. Interior nul bytes are dropped
+fn store_string(strings: &mut Vec<CString>, s: &str) -> *const c_char {
+    let c = CString::new(s).unwrap_or_else(|_| CString::new(s.replace('\0', "")).unwrap());
+    // The heap buffer of the CString doesn't move when the Vec grows
+    let ptr = c.as_ptr();
+    strings.push(c);
+    ptr
+}
+
+fn tag_string(strings: &mut Vec<CString>, metadata: &SongMetadata, tag: &str) -> *const c_char {
+    match metadata.tag(tag) {
+        Some(TagValue::Str(s)) => store_string(strings, s),
+        Some(TagValue::F64(v)) => store_string(strings, &v.to_string()),
+        None => ptr::null(),
+    }
+}
+
+// Converts the metadata to the C layout. The pointers are valid as long as the storage is kept alive
+fn build(metadata: &SongMetadata) -> (CoreMetadata, Storage) {
+    let mut strings = Vec::new();
+
+    let tags: Vec<CoreMetadataTag> = metadata
+        .tags
+        .iter()
+        .map(|(name, value)| CoreMetadataTag {
+            name: store_string(&mut strings, name),
+            str_value: value.as_str().map_or(ptr::null(), |s| store_string(&mut strings, s)),
+            f64_value: value.as_f64().unwrap_or(0.0),
+        })
+        .collect();
+
+    let subsongs: Vec<CoreSubsong> = metadata
+        .subsongs
+        .iter()
+        .map(|s| CoreSubsong {
+            index: s.index,
+            name: store_string(&mut strings, &s.name),
+            length: s.length,
+        })
+        .collect();
+
+    let samples: Vec<*const c_char> = metadata.samples.iter().map(|s| store_string(&mut strings, s)).collect();
+    let instruments: Vec<*const c_char> =
+        metadata.instruments.iter().map(|s| store_string(&mut strings, s)).collect();
+
+    let length = match metadata.tag(RV_METADATA_LENGTH_TAG) {
+        Some(TagValue::F64(v)) => *v,
+        Some(TagValue::Str(s)) => s.parse().unwrap_or(-1.0),
+        None => -1.0,
+    };
+
+    let c = CoreMetadata {
+        url: store_string(&mut strings, &metadata.url),
+        title: tag_string(&mut strings, metadata, RV_METADATA_TITLE_TAG),
+        artist: tag_string(&mut strings, metadata, RV_METADATA_ARTIST_TAG),
+        song_type: tag_string(&mut strings, metadata, RV_METADATA_SONGTYPE_TAG),
+        authoring_tool: tag_string(&mut strings, metadata, RV_METADATA_AUTHORINGTOOL_TAG),
+        message: tag_string(&mut strings, metadata, RV_METADATA_MESSAGE_TAG),
+        length,
+        tags: tags.as_ptr(),
+        tags_count: tags.len() as _,
+        subsongs: subsongs.as_ptr(),
+        subsongs_count: subsongs.len() as _,
+        samples: samples.as_ptr(),
+        samples_count: samples.len() as _,
+        instruments: instruments.as_ptr(),
+        instruments_count: instruments.len() as _,
+    };
+
+    let storage = Storage {
+        _strings: strings,
+        _tags: tags,
+        _subsongs: subsongs,
+        _samples: samples,
+        _instruments: instruments,
+    };
+
+    (c, storage)
+}
+
+/// Converts the metadata to the C layout. Free the result with [`free`]
+pub(crate) fn create(metadata: &SongMetadata) -> *const CoreMetadata {
+    let (c, storage) = build(metadata);
+    let owned = Box::new(OwnedMetadata { c, _storage: storage });
+    Box::into_raw(owned) as *const CoreMetadata
+}
+
+/// Converts query results (or the error) to the C layout. Free the result with [`free_query_result`]
+pub(crate) fn create_query_result(result: anyhow::Result<QueryResult>) -> *const CoreQueryResult {
+    let (result, error) = match result {
+        Ok(result) => (result, None),
+        Err(e) => (QueryResult::default(), Some(e.to_string())),
+    };
+
+    let error = error.map(|e| CString::new(e.replace('\0', "")).unwrap());
+    let (entries, storage): (Vec<CoreMetadata>, Vec<Storage>) = result.entries.iter().map(build).unzip();
+
+    let c = CoreQueryResult {
+        error: error.as_ref().map_or(ptr::null(), |e| e.as_ptr()),
+        total: result.total as _,
+        entries: entries.as_ptr(),
+        entries_count: entries.len() as _,
+    };
+
+    let owned = Box::new(OwnedQueryResult {
+        c,
+        _error: error,
+        _entries: entries,
+        _storage: storage,
+    });
+
+    Box::into_raw(owned) as *const CoreQueryResult
+}
+
+/// # Safety
+///
+/// result has to be null or returned by [`create_query_result`] and not freed before
+pub(crate) unsafe fn free_query_result(result: *const CoreQueryResult) {
+    if !result.is_null() {
+        drop(Box::from_raw(result as *mut OwnedQueryResult));
+    }
+}
+
+/// # Safety
+///
+/// metadata has to be null or returned by [`create`] and not freed before
+pub(crate) unsafe fn free(metadata: *const CoreMetadata) {
+    if !metadata.is_null() {
+        drop(Box::from_raw(metadata as *mut OwnedMetadata));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::metadata::Subsong;
+    use std::ffi::CStr;
+    use std::slice;
+
+    unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+        (!s.is_null()).then(|| CStr::from_ptr(s).to_str().unwrap())
+    }
+
+    unsafe fn strings<'a>(ptr: *const *const c_char, count: u32) -> Vec<&'a str> {
+        slice::from_raw_parts(ptr, count as usize).iter().map(|s| to_str(*s).unwrap()).collect()
+    }
+
+    fn song() -> SongMetadata {
+        SongMetadata {
+            url: "mods/debris.mod".to_owned(),
+            hash: None,
+            file_info: None,
+            tags: vec![
+                (RV_METADATA_TITLE_TAG.to_owned(), TagValue::Str("Space Debris".to_owned())),
+                (RV_METADATA_ARTIST_TAG.to_owned(), TagValue::Str("Captain\0".to_owned())),
+                (RV_METADATA_LENGTH_TAG.to_owned(), TagValue::F64(235.5)),
+            ],
+            subsongs: vec![Subsong {
+                index: 1,
+                name: "intro".to_owned(),
+                length: 12.0,
+            }],
+            samples: vec!["bass".to_owned(), "snare".to_owned()],
+            instruments: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let metadata = create(&song());
+
+        unsafe {
+            let c = &*metadata;
+
+            assert_eq!(to_str(c.url), Some("mods/debris.mod"));
+            assert_eq!(to_str(c.title), Some("Space Debris"));
+            // Interior nul bytes are dropped
+            assert_eq!(to_str(c.artist), Some("Captain"));
+            assert_eq!(to_str(c.song_type), None);
+            assert_eq!(to_str(c.authoring_tool), None);
+            assert_eq!(to_str(c.message), None);
+            assert_eq!(c.length, 235.5);
+
+            let tags = slice::from_raw_parts(c.tags, c.tags_count as usize);
+            assert_eq!(tags.len(), 3);
+            assert_eq!(to_str(tags[0].name), Some(RV_METADATA_TITLE_TAG));
+            assert_eq!(to_str(tags[0].str_value), Some("Space Debris"));
+            assert_eq!(to_str(tags[2].name), Some(RV_METADATA_LENGTH_TAG));
+            assert_eq!(to_str(tags[2].str_value), None);
+            assert_eq!(tags[2].f64_value, 235.5);
+
+            let subsongs = slice::from_raw_parts(c.subsongs, c.subsongs_count as usize);
+            assert_eq!(subsongs.len(), 1);
+            assert_eq!(subsongs[0].index, 1);
+            assert_eq!(to_str(subsongs[0].name), Some("intro"));
+            assert_eq!(subsongs[0].length, 12.0);
+
+            assert_eq!(strings(c.samples, c.samples_count), vec!["bass", "snare"]);
+            assert_eq!(c.instruments_count, 0);
+
+            free(metadata);
+            free(ptr::null());
+        }
+    }
+
+    #[test]
+    fn query_result_round_trip() {
+        let result = create_query_result(Ok(QueryResult {
+            total: 10,
+            entries: vec![song(), SongMetadata::default()],
+        }));
+
+        unsafe {
+            let c = &*result;
+            assert!(c.error.is_null());
+            assert_eq!(c.total, 10);
+
+            let entries = slice::from_raw_parts(c.entries, c.entries_count as usize);
+            assert_eq!(entries.len(), 2);
+            assert_eq!(to_str(entries[0].title), Some("Space Debris"));
+            assert_eq!(to_str(entries[1].url), Some(""));
+            assert_eq!(entries[1].length, -1.0);
+
+            free_query_result(result);
+        }
+
+        let result = create_query_result(Err(anyhow::anyhow!("bad query")));
+
+        unsafe {
+            let c = &*result;
+            assert_eq!(to_str(c.error), Some("bad query"));
+            assert_eq!(c.total, 0);
+            assert_eq!(c.entries_count, 0);
+
+            free_query_result(result);
+            free_query_result(ptr::null());
+        }
+    }
+}
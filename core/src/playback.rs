@@ -8,7 +8,9 @@ use plugin_types::{
     ReadStatus, ConvertConfig
 };
 use crossbeam_channel::{Sender, Receiver, unbounded};
+use cfixed_string::CFixedString;
 use log::{error, trace};
+use services::{settings, PluginService, SettingsFFI, SettingsUpdate};
 use anyhow::{Result, bail};
 use std::{
     ptr,
@@ -64,7 +66,7 @@ impl Playback {
     pub fn queue_playback(&self, playback_instance: PlaybackPluginInstance) -> Result<PlaybackHandle> {
         let (thread_send, main_recv) = unbounded::<PlaybackReply>();
 
-        self.channel.send(PlaybackMessage::QueuePlayback(playback_instance, thread_send))?;
+        self.channel.send(PlaybackMessage::QueuePlayback(Box::new(playback_instance), thread_send))?;
 
         Ok(PlaybackHandle { channel: main_recv })
     }
@@ -74,12 +76,28 @@ impl Playback {
 pub struct PlaybackPluginInstance {
     pub user_data: *mut c_void,
     pub plugin: PlaybackPlugin,
+    /// Url and service used when opening the next subsong
+    pub url: String,
+    /// Content hash (MD5 as hex) used for per song settings
+    pub md5: Option<String>,
+    pub service: PluginService,
+    /// Subsong being played
+    pub subsong: u32,
+    /// Ids of the settings the plugin registered. The instance is only notified when these change
+    pub settings_ids: Vec<String>,
+    /// Lengths (in seconds) of the subsongs from the song length database. If set, playback of a subsong ends
+    /// after its length and continues with the next subsong (if there is one)
+    pub subsong_lengths: Vec<f64>,
 }
 
 #[derive(Clone, Debug)]
 pub struct ResamplePluginInstance {
     pub user_data: *mut c_void,
     pub plugin: ResamplePlugin,
+    /// Passed to the plugin when settings has changed
+    pub settings: *const SettingsFFI,
+    /// Ids of the settings the plugin registered. The instance is only notified when these change
+    pub settings_ids: Vec<String>,
 }
 
 #[derive(Default, PartialEq, PartialOrd)]
@@ -130,12 +148,17 @@ pub struct PlaybackInternal {
     plugin_format: AudioFormat,
     /// TODO: Keep a cache of these?
     last_request_format: AudioFormat,
+    /// Frames (in the internal format) generated for the current subsong
+    played_frames: u64,
 }
 
 pub enum PlaybackMessage {
-    QueuePlayback(PlaybackPluginInstance, Sender<PlaybackReply>),
+    QueuePlayback(Box<PlaybackPluginInstance>, Sender<PlaybackReply>),
     GetData(plugin_types::AudioFormat, usize, Sender<PlaybackReply>),
-    GetTrackerPosition(Sender<PlaybackReply>)
+    GetTrackerPosition(Sender<PlaybackReply>),
+    /// Settings of the registration ids has changed. Live plugin instances using them are notified and
+    /// restarted if they require it
+    SettingsUpdated(Vec<String>),
 }
 
 pub enum PlaybackReply {
@@ -177,6 +200,7 @@ impl PlaybackInternal {
             internal_format: DEFAULT_AUDIO_FORMAT,
             last_request_format: DEFAULT_AUDIO_FORMAT, 
             plugin_format: DEFAULT_AUDIO_FORMAT,
+            played_frames: 0,
         })
     }
 
@@ -203,7 +227,12 @@ impl PlaybackInternal {
         
         trace!("Created default resample plugin: {}", plugin_name);
 
-        Ok(ResamplePluginInstance { user_data, plugin: op.plugin_funcs })
+        Ok(ResamplePluginInstance {
+            user_data,
+            plugin: op.plugin_funcs,
+            settings: op.service.get_settings_c_api(),
+            settings_ids: op.settings_ids.clone(),
+        })
     }
 }
 
@@ -294,7 +323,7 @@ fn incoming_msg(state: &mut PlaybackInternal, msg: &PlaybackMessage) {
     match msg {
         // TODO: Implement
         PlaybackMessage::QueuePlayback(playback, msg) => {
-            state.players.push((playback.clone(), msg.clone()));
+            state.players.push(((**playback).clone(), msg.clone()));
         },
 
         PlaybackMessage::GetData(format, frames, msg) => {
@@ -313,6 +342,65 @@ fn incoming_msg(state: &mut PlaybackInternal, msg: &PlaybackMessage) {
             let pos = u64::from_le_bytes(output_data);
             msg.send(PlaybackReply::TrackerPosition(pos)).unwrap();
         }
+
+        PlaybackMessage::SettingsUpdated(changed) => settings_updated(state, changed),
+    }
+}
+
+/// Returns true if any of the settings registered by a plugin are among the changed ones
+fn uses_changed_settings(settings_ids: &[String], changed: &[String]) -> bool {
+    settings_ids.iter().any(|id| changed.contains(id))
+}
+
+/// Calls settings_updated on the player and resample instances that use the changed settings. Players that
+/// require a restart are reopened at the start of the subsong they are playing and resamplers get their config
+/// set again
+fn settings_updated(state: &mut PlaybackInternal, changed: &[String]) {
+    let mut i = 0;
+
+    while i < state.players.len() {
+        let player = &state.players[i].0;
+
+        if !uses_changed_settings(&player.settings_ids, changed) {
+            i += 1;
+            continue;
+        }
+
+        let update = settings::with_song(&player.url, player.md5.as_deref(), || unsafe {
+            (player.plugin.settings_updated)(player.user_data, player.service.get_c_api())
+        });
+
+        if update == SettingsUpdate::RequireRestart {
+            trace!("Restarting {} as settings has changed", player.url);
+
+            if !reopen(player, player.subsong) {
+                end_playback(state, i);
+                continue;
+            }
+
+            if i == 0 {
+                state.played_frames = 0;
+            }
+        }
+
+        i += 1;
+    }
+
+    let resamplers = [
+        (&state.output_resampler, ConvertConfig { input: DEFAULT_AUDIO_FORMAT, output: state.last_request_format }),
+        (&state.plugin_resampler, ConvertConfig { input: state.plugin_format, output: state.internal_format }),
+    ];
+
+    for (resampler, config) in resamplers {
+        if !uses_changed_settings(&resampler.settings_ids, changed) {
+            continue;
+        }
+
+        let update = unsafe { (resampler.plugin.settings_updated)(resampler.user_data, resampler.settings) };
+
+        if update == SettingsUpdate::RequireRestart {
+            unsafe { (resampler.plugin.set_config)(resampler.user_data, &config) };
+        }
     }
 }
 
@@ -320,6 +408,8 @@ fn copy_buffer_to_ring(state: &mut PlaybackInternal, frame_count: usize, buffer_
     let ring_buffer_len = state.ring_buffer.len(); 
     let byte_size = get_byte_size_format(state.internal_format, frame_count);
     let write_index = state.write_index.get();
+
+    state.played_frames += frame_count as u64;
     let input_buffer = &state.temp_gen[buffer_index];
 
     // if read index + size is smaller than the ring buffer size we can just copy the range into the ring buffer
@@ -418,17 +508,83 @@ fn update(state: &mut PlaybackInternal) -> bool {
     }
 
     // info check if we have finished reading from this plugin and if that is the case we will close it and remove it from the player list
-    if info.status == ReadStatus::Finished {
-        let player = &state.players[0].0;
-        state.players[0].1.send(PlaybackReply::PlaybackEnded).unwrap();
-        unsafe { (player.plugin.destroy)(player.user_data) };
-        state.players.remove(0);
-        trace!("Playback finished - players left {}", state.players.len());
+    // The song length database can also end the song. If it has more subsongs the next one is played instead
+    let finished = info.status == ReadStatus::Finished || length_reached(state);
+
+    if finished && !open_next_subsong(state) {
+        end_playback(state, 0);
     }
 
     false
 }
 
+/// Returns true if the current subsong has played for the length given by the song length database
+fn length_reached(state: &PlaybackInternal) -> bool {
+    let player = &state.players[0].0;
+    subsong_length_reached(&player.subsong_lengths, player.subsong, state.played_frames, state.internal_format.sample_rate)
+}
+
+/// Returns true if `played_frames` at `sample_rate` covers the length (in seconds) of the subsong
+fn subsong_length_reached(lengths: &[f64], subsong: u32, played_frames: u64, sample_rate: u32) -> bool {
+    match lengths.get(subsong as usize) {
+        // 0 is used for unknown lengths
+        Some(length) if *length > 0.0 => played_frames as f64 >= length * sample_rate as f64,
+        _ => false,
+    }
+}
+
+/// Continues with the next subsong of the current player. Returns false if there are no more subsongs
+fn open_next_subsong(state: &mut PlaybackInternal) -> bool {
+    let player = &mut state.players[0].0;
+    let subsong = player.subsong + 1;
+
+    if subsong as usize >= player.subsong_lengths.len() {
+        return false;
+    }
+
+    if !reopen(player, subsong) {
+        return false;
+    }
+
+    trace!("Playing subsong {} of {}", subsong, player.url);
+
+    player.subsong = subsong;
+    state.played_frames = 0;
+    true
+}
+
+/// Closes the player and opens its url again at the subsong. Returns false if the open failed
+fn reopen(player: &PlaybackPluginInstance, subsong: u32) -> bool {
+    let c_url = CFixedString::from_str(&player.url);
+
+    let res = settings::with_song(&player.url, player.md5.as_deref(), || unsafe {
+        (player.plugin.close)(player.user_data);
+        (player.plugin.open)(player.user_data, c_url.as_ptr(), subsong, player.service.get_c_api())
+    });
+
+    if res < 0 {
+        error!("Unable to open subsong {} of {}", subsong, player.url);
+        return false;
+    }
+
+    true
+}
+
+/// Closes a player and tells the requester that playback has ended
+fn end_playback(state: &mut PlaybackInternal, index: usize) {
+    let player = &state.players[index].0;
+    state.players[index].1.send(PlaybackReply::PlaybackEnded).unwrap();
+    unsafe { (player.plugin.destroy)(player.user_data) };
+    state.players.remove(index);
+
+    // The next player starts from the beginning
+    if index == 0 {
+        state.played_frames = 0;
+    }
+
+    trace!("Playback finished - players left {}", state.players.len());
+}
+
 impl Playback {
     pub fn new(resample_plugins: ResamplePlugins) -> Result<Playback> {
         let (channel, thread_recv) = unbounded::<PlaybackMessage>();
@@ -469,3 +625,39 @@ pub fn get_byte_size_format(format: AudioFormat, frames: usize) -> usize {
     stream_size * format.channel_count as usize * frames
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_settings() {
+        let ids = ["sid".to_owned(), "sid_filter".to_owned()];
+
+        assert!(uses_changed_settings(&ids, &["sid_filter".to_owned()]));
+        assert!(uses_changed_settings(&ids, &["mod".to_owned(), "sid".to_owned()]));
+        assert!(!uses_changed_settings(&ids, &["mod".to_owned()]));
+        assert!(!uses_changed_settings(&ids, &[]));
+        assert!(!uses_changed_settings(&[], &["sid".to_owned()]));
+    }
+
+    #[test]
+    fn subsong_length() {
+        let lengths = [2.0, 0.0, 0.5];
+
+        assert!(!subsong_length_reached(&lengths, 0, 0, 48000));
+        assert!(!subsong_length_reached(&lengths, 0, 95999, 48000));
+        assert!(subsong_length_reached(&lengths, 0, 96000, 48000));
+        assert!(subsong_length_reached(&lengths, 0, 96001, 48000));
+        assert!(subsong_length_reached(&lengths, 0, 88200, 44100));
+
+        // Unknown lengths never end the subsong
+        assert!(!subsong_length_reached(&lengths, 1, u64::MAX, 48000));
+
+        assert!(!subsong_length_reached(&lengths, 2, 23999, 48000));
+        assert!(subsong_length_reached(&lengths, 2, 24000, 48000));
+
+        // Subsongs without a length (or no lengths at all) are played until the plugin finishes
+        assert!(!subsong_length_reached(&lengths, 3, u64::MAX, 48000));
+        assert!(!subsong_length_reached(&[], 0, u64::MAX, 48000));
+    }
+}
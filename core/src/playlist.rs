@@ -1,14 +1,16 @@
 use vfs::Vfs;
 use crossbeam_channel::unbounded;
-use std::{thread};
+use std::{thread, sync::{Arc, Mutex}};
 use log::{error, trace, info};
-use vfs::{RecvMsg as VfsRecvMsg, FilesDirs};
+use vfs::{RecvMsg as VfsRecvMsg, FilesDirs, Data};
 use std::path::Path;
 use cfixed_string::CFixedString;
 use anyhow::Result;
 use rand::{thread_rng, Rng, rngs::ThreadRng};
 
 use crate::plugin_handler::{PlaybackPlugins};
+use crate::songlengths::SongLengths;
+use services::settings;
 use crate::playback::{Playback, PlaybackHandle, PlaybackPluginInstance, PlaybackReply};
 
 /// Mode of the playlist such as play next song, ranhdomize, etc 
@@ -39,6 +41,12 @@ impl VfsHandle {
     }
 }
 
+/// Song that has been queued for playback
+struct ActiveSong {
+    url: String,
+    handle: PlaybackHandle,
+}
+
 struct PlaylistInternal {
     /// Used for sending messages to the VirtualFileSystem
     vfs: Vfs,
@@ -48,8 +56,12 @@ struct PlaylistInternal {
     inprogress: Vec<VfsHandle>,
     /// Handles that are being loaded/processed
     randomize_base_dir: String,
-    /// Songs that are currently playing on the decoder thread 
-    active_songs: Vec<PlaybackHandle>,
+    /// Songs that are currently playing on the decoder thread. The first one is the one being heard
+    active_songs: Vec<ActiveSong>,
+    /// Url of the song currently playing. Shared with Playlist
+    current_url: Arc<Mutex<String>>,
+    /// Used to end songs (or go to the next subsong) that would otherwise play forever
+    song_lengths: SongLengths,
     /// List of plugins that supports playback. We loop over these and figure out if they can play something
     playback_plugins: PlaybackPlugins,
     /// State machine
@@ -79,6 +91,7 @@ pub enum PlaylistMessage {
 pub struct Playlist {
     /// for sending messages to the main-thread
     main_send: crossbeam_channel::Sender<PlaylistMessage>,
+    current_url: Arc<Mutex<String>>,
 }
 
 /// Handle to check state of message sent
@@ -87,12 +100,14 @@ pub struct PlaylistHandle {
 }
 
 impl PlaylistInternal {
-    fn new(vfs: &Vfs, playback: &Playback, playback_plugins: PlaybackPlugins) -> PlaylistInternal {
+    fn new(vfs: &Vfs, playback: &Playback, playback_plugins: PlaybackPlugins, current_url: Arc<Mutex<String>>, song_lengths: SongLengths) -> PlaylistInternal {
         PlaylistInternal { 
             vfs: vfs.clone(),
             playback: playback.clone(),
             inprogress: Vec::new(),
             active_songs: Vec::new(),
+            current_url,
+            song_lengths,
             randomize_base_dir: String::new(),
             mode: Mode::Default,
             playback_plugins,
@@ -121,7 +136,10 @@ fn incoming_msg(state: &mut PlaylistInternal, msg: &PlaylistMessage) {
 }
 
 /// Given data and a string find a player for it
-fn find_playback_plugin(state: &mut PlaylistInternal, url: &str, data: &[u8], progress_index: usize) -> bool {
+fn find_playback_plugin(state: &mut PlaylistInternal, url: &str, data: &Data, progress_index: usize) -> bool {
+    let md5 = data.hashes().md5_hex();
+    let subsong_lengths = md5.as_ref().and_then(|md5| state.song_lengths.get(md5)).unwrap_or_default();
+    let data = data.get();
     let path = Path::new(url);
     let filename = match path.file_name() {
         None => "".into(),
@@ -147,9 +165,12 @@ fn find_playback_plugin(state: &mut PlaylistInternal, url: &str, data: &[u8], pr
             }
 
             // TODO: Fix settings
-            let c_name = CFixedString::from_str(&url);
+            let c_name = CFixedString::from_str(url);
             //let open_state = unsafe { ((player.plugin_funcs).open_from_memory)(user_data, data.as_ptr(), data.len() as _, 0, ptr::null()) };
-            let open_state = unsafe { ((player.plugin_funcs).open)(user_data, c_name.as_ptr(), 0, service_funcs) };
+            // Plugins read their settings when opening so the overrides for the song are applied
+            let open_state = settings::with_song(url, md5.as_deref(), || unsafe {
+                ((player.plugin_funcs).open)(user_data, c_name.as_ptr(), 0, service_funcs)
+            });
 
             if open_state < 0 {
                 error!("{} : Unable to create playback", plugin_name); 
@@ -159,10 +180,19 @@ fn find_playback_plugin(state: &mut PlaylistInternal, url: &str, data: &[u8], pr
 
             info!("Queueing playback: {}", &state.inprogress[progress_index].url);
 
-            let instance = PlaybackPluginInstance { user_data, plugin: player.plugin_funcs };
-            let playing_track = state.playback.queue_playback(instance).unwrap();
+            let instance = PlaybackPluginInstance {
+                user_data,
+                plugin: player.plugin_funcs,
+                url: url.to_owned(),
+                md5: md5.clone(),
+                service: player.service.clone(),
+                subsong: 0,
+                settings_ids: player.settings_ids.clone(),
+                subsong_lengths,
+            };
+            let handle = state.playback.queue_playback(instance).unwrap();
 
-            state.active_songs.push(playing_track);
+            state.active_songs.push(ActiveSong { url: url.to_owned(), handle });
             
             return true;
         }
@@ -230,8 +260,8 @@ fn update_get_directory(state: &mut PlaylistInternal, files_dirs: FilesDirs, rng
     }
 }
 
-fn update_get_read_done(state: &mut PlaylistInternal, data: &[u8], progress_index: usize) {
-    trace!("Got data back from vfs (size {})", data.len());
+fn update_get_read_done(state: &mut PlaylistInternal, data: &Data, progress_index: usize) {
+    trace!("Got data back from vfs (size {})", data.get().len());
     let url = state.inprogress[progress_index].url.to_owned();
     // if we managed to find a player for the file we will remove it, otherwise if get a text song
     if find_playback_plugin(state, &url, data, progress_index) {
@@ -242,6 +272,16 @@ fn update_get_read_done(state: &mut PlaylistInternal, data: &[u8], progress_inde
     }
 }
 
+/// Shares the url of the song being heard (the first active one) with the Playlist
+fn update_current_url(state: &PlaylistInternal) {
+    let current = state.active_songs.first().map(|s| s.url.as_str()).unwrap_or("");
+    let mut current_url = state.current_url.lock().unwrap();
+
+    if *current_url != current {
+        *current_url = current.to_owned();
+    }
+}
+
 fn update(state: &mut PlaylistInternal, rng: &mut ThreadRng) {
     // Process loading in progress
     let mut i = 0;
@@ -255,12 +295,17 @@ fn update(state: &mut PlaylistInternal, rng: &mut ThreadRng) {
 
         match handle.vfs_handle.recv.try_recv() {
             Ok(VfsRecvMsg::Error(err)) => {
-                error!("Error processing vfs handle {:?}", err);
+                error!("Error processing vfs handle: {}", err);
+                state.inprogress.remove(i);
+            }
+
+            Ok(VfsRecvMsg::NotFound) => {
+                error!("Unable to find {}", handle.url);
                 state.inprogress.remove(i);
             }
 
             Ok(VfsRecvMsg::Directory(dir)) => update_get_directory(state, dir, rng, i),
-            Ok(VfsRecvMsg::ReadDone(data)) => update_get_read_done(state, data.get(), i),
+            Ok(VfsRecvMsg::ReadDone(data)) => update_get_read_done(state, &data, i),
             _  => (),
         }
 
@@ -272,17 +317,19 @@ fn update(state: &mut PlaylistInternal, rng: &mut ThreadRng) {
     }
 
     // Process active playing tunes
+    i = 0;
 
     while i < state.active_songs.len() {
-        let handle = &state.active_songs[i];
+        let song = &state.active_songs[i];
 
-        match handle.channel.try_recv() {
+        match song.handle.channel.try_recv() {
             Ok(PlaybackReply::PlaybackStarted) => {
                 trace!("Playback started");
             }
             Ok(PlaybackReply::PlaybackEnded) => {
                 trace!("Playback ended");
                 state.active_songs.remove(i);
+                continue;
             }
             _ => (),
         }
@@ -290,6 +337,8 @@ fn update(state: &mut PlaylistInternal, rng: &mut ThreadRng) {
         i += 1;
     }
 
+    update_current_url(state);
+
     //trace!("active songs {} inprogress {}", state.active_songs.len(), state.inprogress.len());
 
     if state.active_songs.len() == 1 && state.inprogress.is_empty() {
@@ -324,10 +373,22 @@ impl Playlist {
         PlaylistHandle { recv: main_recv }
     }
 
-    pub fn new(vfs: &Vfs, playback: &Playback, playback_plugins: PlaybackPlugins) -> Result<Playlist> {
+    /// Url of the song currently playing (if any)
+    pub fn current_url(&self) -> Option<String> {
+        let url = self.current_url.lock().unwrap();
+
+        if url.is_empty() {
+            None
+        } else {
+            Some(url.clone())
+        }
+    }
+
+    pub fn new(vfs: &Vfs, playback: &Playback, playback_plugins: PlaybackPlugins, song_lengths: &SongLengths) -> Result<Playlist> {
         let (main_send, thread_recv) = unbounded::<PlaylistMessage>();
+        let current_url = Arc::new(Mutex::new(String::new()));
                 
-        let mut state = PlaylistInternal::new(vfs, playback, playback_plugins);
+        let mut state = PlaylistInternal::new(vfs, playback, playback_plugins, current_url.clone(), song_lengths.clone());
 
         trace!("Playlist create");
 
@@ -350,7 +411,7 @@ impl Playlist {
 
         trace!("Playlist create: done");
 
-        Ok(Playlist { main_send })
+        Ok(Playlist { main_send, current_url })
     }
 
 }
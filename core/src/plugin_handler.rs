@@ -13,6 +13,8 @@ pub struct PlaybackPlugin {
     pub service: PluginService,
     pub plugin_path: String,
     pub plugin_funcs: plugin_types::PlaybackPlugin,
+    /// Ids of the settings the plugin registered in static_init
+    pub settings_ids: Vec<String>,
 }
 
 pub struct OutputPlugin {
@@ -20,6 +22,8 @@ pub struct OutputPlugin {
     pub service: PluginService,
     pub plugin_path: String,
     pub plugin_funcs: plugin_types::OutputPlugin,
+    /// Ids of the settings the plugin registered in static_init
+    pub settings_ids: Vec<String>,
 }
 
 pub struct ResamplePlugin {
@@ -27,17 +31,30 @@ pub struct ResamplePlugin {
     pub service: PluginService,
     pub plugin_path: String,
     pub plugin_funcs: plugin_types::ResamplePlugin,
+    /// Ids of the settings the plugin registered in static_init
+    pub settings_ids: Vec<String>,
+}
+
+pub struct VfsPlugin {
+    pub plugin: Library,
+    pub service: PluginService,
+    pub plugin_path: String,
+    pub plugin_funcs: plugin_types::VfsPlugin,
+    /// Ids of the settings the plugin registered in static_init
+    pub settings_ids: Vec<String>,
 }
 
 pub type PlaybackPlugins = Arc<RwLock<Vec<Box<PlaybackPlugin>>>>;
 pub type OutputPlugins = Arc<RwLock<Vec<Box<OutputPlugin>>>>;
 pub type ResamplePlugins = Arc<RwLock<Vec<Box<ResamplePlugin>>>>;
+pub type VfsPlugins = Arc<RwLock<Vec<Box<VfsPlugin>>>>;
 
 #[derive(Default)]
 pub struct Plugins {
     pub decoder_plugins: PlaybackPlugins,
     pub output_plugins: OutputPlugins,
     pub resample_plugins: ResamplePlugins,
+    pub vfs_plugins: VfsPlugins,
 }
 
 impl PlaybackPlugin {
@@ -66,17 +83,12 @@ impl PlaybackPlugin {
     }
 
 
-    /*
-    pub fn get_metadata(&self, filename: &str, service: &PluginService) {
-        let c_filename = CFixedString::from_str(filename);
-        unsafe {
-            if !self.plugin_funcs.metadata.is_null() {
-                let _ =
-                    (self.plugin_funcs.metadata)(c_filename.as_ptr(), service.get_c_service_api());
-            }
-        };
+    /// Asks the plugin to report metadata for the url to the metadata service. This doesn't create a
+    /// playback instance. Returns the plugin result (negative on error)
+    pub fn get_metadata(&self, url: &str) -> i32 {
+        let c_url = CFixedString::from_str(url);
+        unsafe { (self.plugin_funcs.metadata)(c_url.as_ptr(), self.service.get_c_api()) }
     }
-    */
 }
 
 macro_rules! add_plugin {
@@ -95,6 +107,7 @@ macro_rules! add_plugin {
             trace!("Loaded {} plugin {} {}", $type_name, plugin_name, version);
 
             let service = PluginService::clone_with_log_name($base_service, &full_name);
+            let reg_ids = service.settings().reg_ids();
 
             if plugin_funcs.static_init as usize != 0 {
                 unsafe {
@@ -102,6 +115,9 @@ macro_rules! add_plugin {
                 }
             }
 
+            // Plugins register their settings in static_init so the new registrations are the ones of this plugin
+            let settings_ids = service.settings().reg_ids().into_iter().filter(|id| !reg_ids.contains(id)).collect();
+
             // TODO: Fix unwrap
             let mut p = $plugins.write();
 
@@ -110,6 +126,7 @@ macro_rules! add_plugin {
                 service,
                 plugin_path: $name.to_owned(),
                 plugin_funcs,
+                settings_ids,
             }));
 
             return Ok(true);
@@ -125,6 +142,7 @@ impl Plugins {
             decoder_plugins: Arc::new(RwLock::new(Vec::new())),
             output_plugins: Arc::new(RwLock::new(Vec::new())),
             resample_plugins: Arc::new(RwLock::new(Vec::new())),
+            vfs_plugins: Arc::new(RwLock::new(Vec::new())),
         }
     }
 
@@ -137,6 +155,7 @@ impl Plugins {
         add_plugin!(self.decoder_plugins, plugin, base_service, name, "playback", b"rv_playback_plugin\0", PlaybackPlugin);
         add_plugin!(self.output_plugins, plugin, base_service, name, "output", b"rv_output_plugin\0", OutputPlugin);
         add_plugin!(self.resample_plugins, plugin, base_service, name, "resample", b"rv_resample_plugin\0", ResamplePlugin);
+        add_plugin!(self.vfs_plugins, plugin, base_service, name, "vfs", b"rv_vfs_plugin\0", VfsPlugin);
         bail!("No correct entry point found for plugin {}", name)
     }
 
@@ -0,0 +1,689 @@
+//! Query language for searching the metadata of the library. A query is a list of terms that all has to match:
+//!
+//! ```text
+//! artist:"Jester" type:protracker length>120 path:modland/* sort:-length
+//! ```
+//!
+//! * `field:value` the field contains the value (ignoring case). `*` and `?` can be used as wildcards
+//! * `field=value` the field is equal to the value (ignoring case)
+//! * `field>value`, `field>=value`, `field<value`, `field<=value` numeric compare
+//! * `field:min..max` numeric range (inclusive). Either end can be left out. Values with `..` that aren't numbers
+//!   (such as `path:../mods`) are searched for as text
+//! * `value` the url or any tag contains the value
+//! * `AND`, `OR`, `NOT` (or `-` in front of a term) and parentheses. Terms next to each other are AND:ed
+//! * `sort:field` and `sort:-field` (descending) sets the order of the results
+//!
+//! Fields are tag names (`title`, `artist`, `length` ...) with the aliases `type` (song_type), `tool`
+//! (authoring_tool) and `path` (url). `hash` is the content hash, `sample` and `instrument` search the sample
+//! and instrument texts and `subsongs` is the number of subsongs.
+
+use anyhow::{bail, Result};
+use services::metadata::{Metadata, SongMetadata, TagValue};
+use services::{MetadataId, RV_METADATA_AUTHORINGTOOL_TAG, RV_METADATA_SONGTYPE_TAG};
+use std::cmp::Ordering;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Contains,
+    Equal,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Term {
+    /// Matches the url or any tag
+    Text(String),
+    Field { field: String, op: Op, value: String },
+    Range { field: String, min: Option<f64>, max: Option<f64> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Term(Term),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Term(Term),
+    Sort(String, bool),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::And => write!(f, "AND"),
+            Token::Or => write!(f, "OR"),
+            Token::Not => write!(f, "NOT"),
+            Token::Term(Term::Text(text)) => write!(f, "{}", text),
+            Token::Term(Term::Field { field, value, .. }) => write!(f, "{}:{}", field, value),
+            Token::Term(Term::Range { field, .. }) => write!(f, "range on {}", field),
+            Token::Sort(field, _) => write!(f, "sort:{}", field),
+        }
+    }
+}
+
+/// A parsed query. Can be run any number of times
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    expr: Option<Expr>,
+    /// Field to sort by and if the order is descending
+    sort: Option<(String, bool)>,
+}
+
+/// A page of query results
+#[derive(Clone, Debug, Default)]
+pub struct QueryResult {
+    /// Total number of matches (not only the ones in this page)
+    pub total: usize,
+    pub entries: Vec<SongMetadata>,
+}
+
+// Maps aliases to the tag names
+fn field_name(field: &str) -> String {
+    match field.to_lowercase().as_str() {
+        "type" => RV_METADATA_SONGTYPE_TAG.to_owned(),
+        "tool" => RV_METADATA_AUTHORINGTOOL_TAG.to_owned(),
+        "path" => "url".to_owned(),
+        "md5" => "hash".to_owned(),
+        name => name.to_owned(),
+    }
+}
+
+fn is_term_end(c: char) -> bool {
+    c.is_whitespace() || c == '(' || c == ')'
+}
+
+struct Tokenizer<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl<'a> Tokenizer<'a> {
+    // Reads a (possibly quoted) value
+    fn value(&mut self, start: usize) -> Result<String> {
+        let mut value = String::new();
+
+        if let Some((_, '"')) = self.chars.peek() {
+            self.chars.next();
+
+            loop {
+                match self.chars.next() {
+                    Some((_, '"')) => return Ok(value),
+                    Some((_, '\\')) => match self.chars.next() {
+                        Some((_, c)) => value.push(c),
+                        None => break,
+                    },
+                    Some((_, c)) => value.push(c),
+                    None => break,
+                }
+            }
+
+            bail!("Missing end quote for string starting at {}", start);
+        }
+
+        while let Some(&(_, c)) = self.chars.peek() {
+            if is_term_end(c) {
+                break;
+            }
+
+            value.push(c);
+            self.chars.next();
+        }
+
+        Ok(value)
+    }
+
+    fn op(&mut self) -> Option<Op> {
+        let op = match self.chars.peek()?.1 {
+            ':' => Op::Contains,
+            '=' => Op::Equal,
+            '<' => Op::Less,
+            '>' => Op::Greater,
+            _ => return None,
+        };
+
+        self.chars.next();
+
+        if matches!(op, Op::Less | Op::Greater) && matches!(self.chars.peek(), Some((_, '='))) {
+            self.chars.next();
+            return Some(if op == Op::Less { Op::LessEqual } else { Op::GreaterEqual });
+        }
+
+        Some(op)
+    }
+
+    fn term(&mut self, start: usize) -> Result<Token> {
+        if let Some((_, '"')) = self.chars.peek() {
+            return Ok(Token::Term(Term::Text(self.value(start)?)));
+        }
+
+        let mut name = String::new();
+
+        while let Some(&(_, c)) = self.chars.peek() {
+            if is_term_end(c) || matches!(c, ':' | '=' | '<' | '>') {
+                break;
+            }
+
+            name.push(c);
+            self.chars.next();
+        }
+
+        let Some(op) = self.op() else {
+            return Ok(match name.as_str() {
+                "AND" => Token::And,
+                "OR" => Token::Or,
+                "NOT" => Token::Not,
+                _ => Token::Term(Term::Text(name)),
+            });
+        };
+
+        if name.is_empty() {
+            bail!("Missing field name at {}", start);
+        }
+
+        let value = self.value(start)?;
+        let field = field_name(&name);
+
+        if field == "sort" {
+            let (sort_field, descending) = match value.strip_prefix('-') {
+                Some(field) => (field, true),
+                None => (value.as_str(), false),
+            };
+
+            if op != Op::Contains || sort_field.is_empty() {
+                bail!("Expected sort:field or sort:-field at {}", start);
+            }
+
+            return Ok(Token::Sort(field_name(sort_field), descending));
+        }
+
+        match op {
+            Op::Contains => {
+                if let Some((min, max)) = value.split_once("..") {
+                    if let (Some(min), Some(max)) = (range_bound(min), range_bound(max)) {
+                        if min.is_some() || max.is_some() {
+                            return Ok(Token::Term(Term::Range { field, min, max }));
+                        }
+                    }
+                }
+            }
+            Op::Equal => (),
+            _ => {
+                if value.parse::<f64>().is_err() {
+                    bail!("Expected a number after {} at {}", name, start);
+                }
+            }
+        }
+
+        Ok(Token::Term(Term::Field { field, op, value }))
+    }
+
+    fn tokens(mut self) -> Result<Vec<Token>> {
+        let mut tokens = Vec::new();
+
+        while let Some(&(pos, c)) = self.chars.peek() {
+            match c {
+                c if c.is_whitespace() => {
+                    self.chars.next();
+                }
+                '(' => {
+                    self.chars.next();
+                    tokens.push(Token::LParen);
+                }
+                ')' => {
+                    self.chars.next();
+                    tokens.push(Token::RParen);
+                }
+                '-' => {
+                    self.chars.next();
+                    tokens.push(Token::Not);
+                }
+                _ => tokens.push(self.term(pos)?),
+            }
+        }
+
+        Ok(tokens)
+    }
+}
+
+// Parses one end of a range. Returns None if it isn't a number (and not left out) so the value isn't a range
+fn range_bound(bound: &str) -> Option<Option<f64>> {
+    if bound.is_empty() {
+        return Some(None);
+    }
+
+    bound.parse().ok().map(Some)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+
+        loop {
+            match self.peek() {
+                Some(Token::And) => self.pos += 1,
+                Some(Token::Or) | Some(Token::RParen) | None => return Ok(expr),
+                _ => (),
+            }
+
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+
+        match token {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::LParen) => {
+                let expr = self.or()?;
+
+                if self.peek() != Some(&Token::RParen) {
+                    bail!("Missing )");
+                }
+
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some(Token::Term(term)) => Ok(Expr::Term(term)),
+            Some(t) => bail!("Unexpected {}", t),
+            None => bail!("Unexpected end of query"),
+        }
+    }
+}
+
+// Matches text against a pattern with * and ? wildcards (both are expected to be lower case)
+fn wildcard_match(text: &[char], pattern: &[char]) -> bool {
+    let (mut t, mut p) = (0, 0);
+    // Position after the last * and the text position it's currently matching up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            t += 1;
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn contains(text: &str, value: &str) -> bool {
+    let text = text.to_lowercase();
+    let value = value.to_lowercase();
+
+    if !value.contains(['*', '?']) {
+        return text.contains(&value);
+    }
+
+    let text: Vec<char> = text.chars().collect();
+    let pattern: Vec<char> = format!("*{}*", value).chars().collect();
+    wildcard_match(&text, &pattern)
+}
+
+// All text values of a field
+fn field_texts<'a>(song: &'a SongMetadata, field: &str) -> Vec<&'a str> {
+    match field {
+        "url" => vec![song.url.as_str()],
+        "hash" => song.hash.iter().map(|h| h.as_str()).collect(),
+        "sample" => song.samples.iter().map(|s| s.as_str()).collect(),
+        "instrument" => song.instruments.iter().map(|s| s.as_str()).collect(),
+        _ => song.tag(field).and_then(|v| v.as_str()).into_iter().collect(),
+    }
+}
+
+fn field_number(song: &SongMetadata, field: &str) -> Option<f64> {
+    match field {
+        "subsongs" => Some(song.subsongs.len() as f64),
+        _ => match song.tag(field)? {
+            TagValue::F64(v) => Some(*v),
+            TagValue::Str(s) => s.trim().parse().ok(),
+        },
+    }
+}
+
+impl Term {
+    fn matches(&self, song: &SongMetadata) -> bool {
+        match self {
+            Term::Text(text) => {
+                contains(&song.url, text) || song.tags.iter().any(|(_, v)| v.as_str().is_some_and(|s| contains(s, text)))
+            }
+            Term::Range { field, min, max } => field_number(song, field)
+                .is_some_and(|v| min.is_none_or(|min| v >= min) && max.is_none_or(|max| v <= max)),
+            Term::Field { field, op, value } => match op {
+                Op::Contains => field_texts(song, field).iter().any(|t| contains(t, value)),
+                Op::Equal => match (field_number(song, field), value.parse::<f64>()) {
+                    (Some(v), Ok(value)) => v == value,
+                    _ => field_texts(song, field).iter().any(|t| t.to_lowercase() == value.to_lowercase()),
+                },
+                _ => {
+                    let (Some(v), Ok(value)) = (field_number(song, field), value.parse::<f64>()) else {
+                        return false;
+                    };
+
+                    match op {
+                        Op::Less => v < value,
+                        Op::LessEqual => v <= value,
+                        Op::Greater => v > value,
+                        _ => v >= value,
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl Expr {
+    fn matches(&self, song: &SongMetadata) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(song) && b.matches(song),
+            Expr::Or(a, b) => a.matches(song) || b.matches(song),
+            Expr::Not(e) => !e.matches(song),
+            Expr::Term(t) => t.matches(song),
+        }
+    }
+}
+
+/// Value used for sorting. Numbers sort before text and missing values last
+#[derive(PartialEq, PartialOrd)]
+enum SortKey {
+    Number(f64),
+    Text(String),
+    Missing,
+}
+
+fn sort_key(song: &SongMetadata, field: &str) -> SortKey {
+    if let Some(v) = field_number(song, field) {
+        return SortKey::Number(v);
+    }
+
+    match field_texts(song, field).first() {
+        Some(text) => SortKey::Text(text.to_lowercase()),
+        None => SortKey::Missing,
+    }
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Query> {
+        let tokenizer = Tokenizer { chars: text.char_indices().peekable() };
+        let mut sort = None;
+
+        // sort can be anywhere in the query and isn't part of the expression
+        let tokens = tokenizer
+            .tokens()?
+            .into_iter()
+            .filter(|t| match t {
+                Token::Sort(field, descending) => {
+                    sort = Some((field.clone(), *descending));
+                    false
+                }
+                _ => true,
+            })
+            .collect::<Vec<Token>>();
+
+        if tokens.is_empty() {
+            return Ok(Query { expr: None, sort });
+        }
+
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+
+        if let Some(t) = parser.peek() {
+            bail!("Unexpected {}", t);
+        }
+
+        Ok(Query { expr: Some(expr), sort })
+    }
+
+    /// Returns true if the song matches the query. An empty query matches everything
+    pub fn matches(&self, song: &SongMetadata) -> bool {
+        self.expr.as_ref().is_none_or(|e| e.matches(song))
+    }
+
+    /// Runs the query and returns `limit` results starting at `offset`. Without a sort field the results are
+    /// sorted by url
+    pub fn run(&self, metadata: &Metadata, offset: usize, limit: usize) -> QueryResult {
+        let sort_field = self.sort.as_ref().map(|s| s.0.as_str()).unwrap_or("url");
+        let descending = self.sort.as_ref().is_some_and(|s| s.1);
+        let mut matches: Vec<(SortKey, String, MetadataId)> = Vec::new();
+
+        metadata.for_each(|id, song| {
+            if self.matches(song) {
+                matches.push((sort_key(song, sort_field), song.url.clone(), id));
+            }
+        });
+
+        matches.sort_by(|a, b| {
+            let order = match (&a.0, &b.0) {
+                (SortKey::Missing, SortKey::Missing) => Ordering::Equal,
+                // missing values are last in both directions
+                (SortKey::Missing, _) => return Ordering::Greater,
+                (_, SortKey::Missing) => return Ordering::Less,
+                (a, b) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+            };
+
+            let order = if descending { order.reverse() } else { order };
+            order.then_with(|| a.1.cmp(&b.1))
+        });
+
+        QueryResult {
+            total: matches.len(),
+            entries: matches.iter().skip(offset).take(limit).filter_map(|m| metadata.get(m.2)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::{RV_METADATA_ARTIST_TAG, RV_METADATA_LENGTH_TAG, RV_METADATA_TITLE_TAG};
+
+    fn song(url: &str, tags: &[(&str, TagValue)]) -> SongMetadata {
+        SongMetadata {
+            url: url.to_owned(),
+            tags: tags.iter().map(|(name, value)| (name.to_string(), value.clone())).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn text(s: &str) -> TagValue {
+        TagValue::Str(s.to_owned())
+    }
+
+    fn matches(query: &str, song: &SongMetadata) -> bool {
+        Query::parse(query).unwrap().matches(song)
+    }
+
+    #[test]
+    fn example_query() {
+        let query = Query::parse(r#"artist:"Jester" type:protracker length>120 path:modland/*"#).unwrap();
+
+        let tags = |length| {
+            [
+                (RV_METADATA_ARTIST_TAG, text("Jester")),
+                (RV_METADATA_SONGTYPE_TAG, text("ProTracker")),
+                (RV_METADATA_LENGTH_TAG, TagValue::F64(length)),
+            ]
+        };
+
+        assert!(query.matches(&song("modland/Protracker/Jester/stardust.mod", &tags(200.0))));
+        assert!(!query.matches(&song("modland/Protracker/Jester/stardust.mod", &tags(120.0))));
+        assert!(!query.matches(&song("other/Jester/stardust.mod", &tags(200.0))));
+        assert!(!query.matches(&song("modland/Protracker/Jester/stardust.mod", &tags(200.0)[..2])));
+    }
+
+    #[test]
+    fn precedence() {
+        let parse = |q| Query::parse(q).unwrap();
+
+        // NOT binds tighter than AND which binds tighter than OR
+        assert_eq!(parse("a OR b c"), parse("a OR (b AND c)"));
+        assert_ne!(parse("a OR b c"), parse("(a OR b) c"));
+        assert_eq!(parse("NOT a b"), parse("(NOT a) AND b"));
+        assert_eq!(parse("-a b OR c"), parse("((NOT a) AND b) OR c"));
+        assert_eq!(parse("-(a OR b)"), parse("NOT (a OR b)"));
+
+        let only_a = song("x.mod", &[(RV_METADATA_TITLE_TAG, text("a"))]);
+        assert!(matches("a OR b c", &only_a));
+        assert!(!matches("(a OR b) c", &only_a));
+        assert!(!matches("-a", &only_a));
+        assert!(matches("-b", &only_a));
+        assert!(matches("NOT (b OR c)", &only_a));
+        assert!(!matches("NOT (a OR c)", &only_a));
+    }
+
+    #[test]
+    fn ranges() {
+        let s = song("mods/a.mod", &[(RV_METADATA_LENGTH_TAG, TagValue::F64(120.0))]);
+
+        assert!(matches("length:100..200", &s));
+        assert!(matches("length:120..120", &s));
+        assert!(!matches("length:121..200", &s));
+        assert!(matches("length:120..", &s));
+        assert!(!matches("length:121..", &s));
+        assert!(matches("length:..120", &s));
+        assert!(!matches("length:..119.5", &s));
+
+        // Strings that parse as numbers are compared as numbers
+        assert!(matches("year:1990..1999", &song("a.mod", &[("year", text("1992"))])));
+
+        // Values with .. that aren't numbers are text searches
+        assert!(matches("path:../mods", &song("music/../mods/a.mod", &[])));
+        assert!(!matches("path:../mods", &s));
+        assert!(matches("path:foo..bar", &song("foo..bar.mod", &[])));
+        assert!(matches("title:1..x", &song("a.mod", &[(RV_METADATA_TITLE_TAG, text("intro 1..x"))])));
+    }
+
+    #[test]
+    fn compare() {
+        let s = song("a.mod", &[(RV_METADATA_LENGTH_TAG, TagValue::F64(120.0)), (RV_METADATA_TITLE_TAG, text("Intro"))]);
+
+        assert!(matches("length>=120 length<=120 length=120", &s));
+        assert!(!matches("length>120", &s));
+        assert!(!matches("length<120", &s));
+        assert!(matches("title=intro", &s));
+        assert!(!matches("title=intr", &s));
+        assert!(matches("title:intr", &s));
+        // Fields that are missing never match a compare
+        assert!(!matches("year<3000", &s));
+    }
+
+    #[test]
+    fn wildcards() {
+        let s = song("modland/Protracker/Jester/stardust.mod", &[]);
+
+        assert!(matches("path:modland/*/jester", &s));
+        assert!(matches("path:*.mod", &s));
+        assert!(matches("path:star?ust", &s));
+        assert!(!matches("path:star?dust", &s));
+        assert!(!matches("path:*.xm", &s));
+        assert!(matches("stardust", &s));
+        assert!(matches("\"Jester/stardust\"", &s));
+    }
+
+    #[test]
+    fn errors() {
+        for query in ["(a", "a)", "AND", "a OR", "length>abc", r#"title:"open"#, ":x", "sort=length", "sort>1", "sort:"] {
+            assert!(Query::parse(query).is_err(), "{}", query);
+        }
+
+        assert!(Query::parse("").unwrap().matches(&song("a.mod", &[])));
+    }
+
+    // Store with songs that has the lengths 3, 1, (missing) and 2
+    fn store() -> Metadata {
+        let metadata = Metadata::new();
+
+        for (url, length) in [("c.mod", Some(3.0)), ("a.mod", Some(1.0)), ("d.mod", None), ("b.mod", Some(2.0))] {
+            let id = metadata.create_url(url);
+
+            if let Some(length) = length {
+                metadata.set_tag_f64(id, RV_METADATA_LENGTH_TAG, length);
+            }
+        }
+
+        metadata
+    }
+
+    fn urls(result: &QueryResult) -> Vec<&str> {
+        result.entries.iter().map(|e| e.url.as_str()).collect()
+    }
+
+    #[test]
+    fn sort() {
+        let metadata = store();
+        let run = |q| Query::parse(q).unwrap().run(&metadata, 0, 10);
+
+        assert_eq!(urls(&run("")), ["a.mod", "b.mod", "c.mod", "d.mod"]);
+        assert_eq!(urls(&run("sort:-path")), ["d.mod", "c.mod", "b.mod", "a.mod"]);
+
+        // Missing values are last in both directions
+        assert_eq!(urls(&run("sort:length")), ["a.mod", "b.mod", "c.mod", "d.mod"]);
+        assert_eq!(urls(&run("sort:-length")), ["c.mod", "b.mod", "a.mod", "d.mod"]);
+        assert_eq!(urls(&run("sort:-length length>1")), ["c.mod", "b.mod"]);
+    }
+
+    #[test]
+    fn paging() {
+        let metadata = store();
+        let query = Query::parse("sort:length").unwrap();
+
+        let result = query.run(&metadata, 1, 2);
+        assert_eq!(result.total, 4);
+        assert_eq!(urls(&result), ["b.mod", "c.mod"]);
+
+        let result = query.run(&metadata, 3, 10);
+        assert_eq!(result.total, 4);
+        assert_eq!(urls(&result), ["d.mod"]);
+
+        let result = query.run(&metadata, 10, 10);
+        assert_eq!(result.total, 4);
+        assert!(result.entries.is_empty());
+
+        let result = Query::parse("length>2").unwrap().run(&metadata, 0, 0);
+        assert_eq!(result.total, 1);
+        assert!(result.entries.is_empty());
+    }
+}
@@ -0,0 +1,696 @@
+//! Collecting metadata for files without playing them. [`scan_metadata`] scans a single url and [`Scanner`]
+//! walks library roots (local directories, archives and ftp) in the background and stores the results in the
+//! metadata service.
+
+use crate::plugin_handler::PlaybackPlugins;
+use crate::songlengths::SongLengths;
+use crate::stil::Stil;
+use anyhow::{bail, Result};
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use log::{error, info, trace};
+use services::metadata::SongMetadata;
+use services::settings;
+use services::{PluginService, RV_METADATA_LENGTH_TAG};
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use vfs::{Data, FileInfo, Response, Vfs};
+
+// Max time to wait for a file to be loaded (this includes downloading)
+const LOAD_TIMEOUT: Duration = Duration::from_secs(30);
+// Min time between progress events
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+// Progress events that hasn't been received are dropped after this many
+const MAX_PENDING_EVENTS: usize = 256;
+
+enum DataScan {
+    Scanned(SongMetadata),
+    /// Metadata is already stored for this content
+    Unchanged,
+    /// No plugin supports the file
+    Unsupported,
+}
+
+/// What the scanner uses for collecting metadata
+#[derive(Clone)]
+pub struct ScanContext {
+    pub vfs: Vfs,
+    pub plugins: PlaybackPlugins,
+    pub service: PluginService,
+    /// Lengths from the database replace the lengths guessed by plugins
+    pub song_lengths: SongLengths,
+    /// STIL and BUGlist entries are added as tags
+    pub stil: Stil,
+}
+
+// Lets the plugin that supports the data report metadata for it. If skip_known is set files that has
+// metadata stored for the same content are skipped.
+fn scan_data(ctx: &ScanContext, url: &str, data: &Data, skip_known: bool) -> Result<DataScan> {
+    let service = &ctx.service;
+    let metadata = service.metadata();
+    let md5 = data.hashes().md5_hex();
+
+    if let Some(md5) = &md5 {
+        if skip_known && metadata.is_known(url, md5) {
+            return Ok(DataScan::Unchanged);
+        }
+    }
+
+    let filename = Path::new(url).file_name().map(|f| f.to_string_lossy()).unwrap_or_default();
+    let players = ctx.plugins.read();
+
+    let Some(player) = players.iter().find(|p| p.probe_can_play(data.get(), data.get().len(), &filename, data.get().len() as _)) else {
+        return Ok(DataScan::Unsupported);
+    };
+
+    // Only recorded for supported files so scanning a library doesn't store a hash for every file in it
+    if let Some(md5) = &md5 {
+        metadata.set_content_hash(url, md5);
+    }
+
+    let plugin_name = player.plugin_funcs.get_name();
+    trace!("{} : getting metadata for {}", plugin_name, url);
+
+    // Plugins may read their settings while reporting so the overrides for the song are applied
+    let res = settings::with_song(url, md5.as_deref(), || player.get_metadata(url));
+
+    if res < 0 {
+        bail!("{} : unable to get metadata for {} ({})", plugin_name, url, res);
+    }
+
+    let Some(id) = service.metadata_id(url) else {
+        bail!("{} : didn't report any metadata for {}", plugin_name, url);
+    };
+
+    if let Some(length) = md5.and_then(|md5| ctx.song_lengths.length(&md5, 0)) {
+        metadata.set_tag_f64(id, RV_METADATA_LENGTH_TAG, length);
+    }
+
+    ctx.stil.apply(metadata, id);
+
+    match service.get_metadata(id) {
+        Some(song) => Ok(DataScan::Scanned(song)),
+        None => bail!("{} : didn't report any metadata for {}", plugin_name, url),
+    }
+}
+
+/// Loads the url, finds a plugin that can play it and lets the plugin report metadata for it. The plugin is
+/// only probed and asked for metadata so no playback instance is created.
+pub fn scan_metadata(ctx: &ScanContext, url: &str) -> Result<SongMetadata> {
+    let data = match ctx.vfs.load_url(url).wait(LOAD_TIMEOUT)? {
+        Response::Data(data) => data,
+        Response::Directory(_) => bail!("{} is a directory", url),
+        Response::WriteDone => bail!("Unexpected reply when loading {}", url),
+    };
+
+    match scan_data(ctx, url, &data, false)? {
+        DataScan::Scanned(song) => Ok(song),
+        DataScan::Unsupported => bail!("No plugin supports {}", url),
+        DataScan::Unchanged => unreachable!(),
+    }
+}
+
+/// State of the library scan
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct ScanStatus {
+    /// Files metadata was collected for
+    pub scanned: usize,
+    /// Files skipped as they haven't changed since they were scanned (songs and unsupported files)
+    pub unchanged: usize,
+    /// Files no plugin supports
+    pub unsupported: usize,
+    /// Files and directories that failed to load or scan
+    pub failed: usize,
+    /// Files and directories waiting to be scanned
+    pub pending: usize,
+    /// Url being scanned
+    pub current: String,
+    pub paused: bool,
+}
+
+impl ScanStatus {
+    /// True while there is work left (even if paused)
+    pub fn is_running(&self) -> bool {
+        self.pending > 0 || !self.current.is_empty()
+    }
+}
+
+pub enum ScanEvent {
+    /// Sent while scanning (at most every 100 ms)
+    Progress(ScanStatus),
+    /// A url failed to load or scan
+    Failed(String, String),
+    /// All pending urls has been scanned
+    Finished(ScanStatus),
+}
+
+enum ScannerMessage {
+    AddRoot(String),
+    Rescan,
+    Pause,
+    Resume,
+    Cancel,
+}
+
+struct ScannerInternal {
+    ctx: ScanContext,
+    roots: Vec<String>,
+    /// Urls to load with the size and modification time from the listing they were found in (if known).
+    /// Directories and archives add their entries to the front so the scan is depth first
+    pending: VecDeque<(String, Option<FileInfo>)>,
+    /// Size and modification time of files no plugin supports. They have no metadata entry to keep it in
+    unsupported: HashMap<String, FileInfo>,
+    status: Arc<Mutex<ScanStatus>>,
+    events: Sender<ScanEvent>,
+    last_progress: Instant,
+}
+
+/// Scans library roots in the background. Files with unchanged content are skipped so rescanning a large
+/// library is cheap.
+pub struct Scanner {
+    main_send: Sender<ScannerMessage>,
+    status: Arc<Mutex<ScanStatus>>,
+    /// Progress events. Progress events that aren't received are dropped, [`Scanner::status`] is always current
+    pub events: Receiver<ScanEvent>,
+}
+
+impl ScannerInternal {
+    fn update_status<F: FnOnce(&mut ScanStatus)>(&mut self, func: F) {
+        let mut status = self.status.lock().unwrap();
+        func(&mut status);
+        status.pending = self.pending.len();
+    }
+
+    fn send_event(&self, event: ScanEvent) {
+        if let Err(TrySendError::Full(_)) = self.events.try_send(event) {
+            trace!("Scanner: dropping event as nobody is receiving them");
+        }
+    }
+
+    fn send_progress(&mut self) {
+        if self.last_progress.elapsed() >= PROGRESS_INTERVAL {
+            self.last_progress = Instant::now();
+            let status = self.status.lock().unwrap().clone();
+            self.send_event(ScanEvent::Progress(status));
+        }
+    }
+
+    fn incoming_msg(&mut self, msg: ScannerMessage) {
+        match msg {
+            ScannerMessage::AddRoot(url) => {
+                info!("Scanner: adding root {}", url);
+                self.pending.push_back((url.clone(), None));
+                self.roots.push(url);
+            }
+            ScannerMessage::Rescan => {
+                // The vfs keeps the directory listings so they are dropped to see new and changed files
+                for root in &self.roots {
+                    self.ctx.vfs.unmount(root);
+                }
+
+                self.pending.extend(self.roots.iter().map(|r| (r.clone(), None)));
+            }
+            ScannerMessage::Pause => self.update_status(|s| s.paused = true),
+            ScannerMessage::Resume => self.update_status(|s| s.paused = false),
+            ScannerMessage::Cancel => self.pending.clear(),
+        }
+
+        self.update_status(|_| ());
+    }
+
+    fn scan_url(&mut self, url: &str, info: Option<FileInfo>) {
+        let metadata = self.ctx.service.metadata();
+
+        // Checked before loading so unchanged files aren't downloaded again when rescanning remote roots
+        if info.as_ref().is_some_and(|info| metadata.has_file_info(url, info) || self.unsupported.get(url) == Some(info)) {
+            self.update_status(|s| s.unchanged += 1);
+            return;
+        }
+
+        let res = self.ctx.vfs.load_url(url).wait(LOAD_TIMEOUT);
+
+        let res = match res {
+            Ok(Response::Directory(dir)) => {
+                let files = dir.files.iter().map(|name| (name, dir.file_infos.get(name)));
+                let entries = files.chain(dir.dirs.iter().map(|name| (name, None)));
+
+                // Added in reverse to the front so the entries are scanned in listing order
+                for (name, info) in entries.rev() {
+                    let entry_url = Path::new(url).join(name).to_string_lossy().into_owned();
+                    self.pending.push_front((entry_url, info.cloned()));
+                }
+
+                return;
+            }
+            Ok(Response::Data(data)) => scan_data(&self.ctx, url, &data, true),
+            Ok(Response::WriteDone) => return,
+            Err(e) => Err(e.into()),
+        };
+
+        if let Some(info) = info {
+            match res {
+                Ok(DataScan::Scanned(_) | DataScan::Unchanged) => {
+                    metadata.set_file_info(url, &info);
+                    self.unsupported.remove(url);
+                }
+                Ok(DataScan::Unsupported) => {
+                    self.unsupported.insert(url.to_owned(), info);
+                }
+                Err(_) => (),
+            }
+        }
+
+        match res {
+            Ok(DataScan::Scanned(_)) => self.update_status(|s| s.scanned += 1),
+            Ok(DataScan::Unchanged) => self.update_status(|s| s.unchanged += 1),
+            Ok(DataScan::Unsupported) => self.update_status(|s| s.unsupported += 1),
+            Err(e) => {
+                error!("Scanner: {}: {:?}", url, e);
+                self.update_status(|s| s.failed += 1);
+                self.send_event(ScanEvent::Failed(url.to_owned(), e.to_string()));
+            }
+        }
+    }
+
+    // Scans the next pending url. Returns false if there is nothing to do
+    fn update(&mut self) -> bool {
+        if self.status.lock().unwrap().paused {
+            return false;
+        }
+
+        let Some((url, info)) = self.pending.pop_front() else {
+            return false;
+        };
+
+        self.update_status(|s| s.current = url.clone());
+        self.scan_url(&url, info);
+
+        if self.pending.is_empty() {
+            self.update_status(|s| s.current.clear());
+            let status = self.status.lock().unwrap().clone();
+            info!("Scanner: done {:?}", status);
+            self.send_event(ScanEvent::Finished(status));
+        } else {
+            self.send_progress();
+        }
+
+        true
+    }
+}
+
+impl Scanner {
+    pub fn new(ctx: ScanContext) -> Result<Scanner> {
+        let (main_send, thread_recv) = unbounded::<ScannerMessage>();
+        let (events_send, events) = bounded::<ScanEvent>(MAX_PENDING_EVENTS);
+        let status = Arc::new(Mutex::new(ScanStatus::default()));
+
+        let mut state = ScannerInternal {
+            ctx,
+            roots: Vec::new(),
+            pending: VecDeque::new(),
+            unsupported: HashMap::new(),
+            status: status.clone(),
+            events: events_send,
+            last_progress: Instant::now(),
+        };
+
+        thread::Builder::new()
+            .name("scanner".to_string())
+            .spawn(move || loop {
+                while let Ok(msg) = thread_recv.try_recv() {
+                    state.incoming_msg(msg);
+                }
+
+                if state.update() {
+                    continue;
+                }
+
+                // Nothing to do so wait for a message
+                match thread_recv.recv_timeout(Duration::from_millis(100)) {
+                    Ok(msg) => state.incoming_msg(msg),
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            })?;
+
+        Ok(Scanner { main_send, status, events })
+    }
+
+    fn send(&self, msg: ScannerMessage) {
+        // Only fails if the thread has stopped in which case there is nothing to tell it
+        let _ = self.main_send.send(msg);
+    }
+
+    /// Adds a root (local directory, archive or ftp url) to the library and starts scanning it
+    pub fn add_root(&self, url: &str) {
+        self.send(ScannerMessage::AddRoot(url.to_owned()));
+    }
+
+    /// Scans all roots again. Files with unchanged content are skipped
+    pub fn rescan(&self) {
+        self.send(ScannerMessage::Rescan);
+    }
+
+    pub fn pause(&self) {
+        self.send(ScannerMessage::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.send(ScannerMessage::Resume);
+    }
+
+    /// Drops all pending urls. The roots are kept for the next rescan
+    pub fn cancel(&self) {
+        self.send(ScannerMessage::Cancel);
+    }
+
+    pub fn status(&self) -> ScanStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin_handler::PlaybackPlugin;
+    use libloading::Library;
+    use parking_lot::RwLock;
+    use plugin_types::{ProbeResult, ReadData, ReadInfo};
+    use services::metadata::TagValue;
+    use services::settings::{SettingValue, SettingsLayer};
+    use services::{
+        MetadataFFI, SBase, SInteger, ServiceFFI, Setting, SettingsFFI, SettingsUpdate,
+        RVS_INTEGER_TYPE, RV_METADATA_API_VERSION, RV_METADATA_TITLE_TAG, RV_SETTINGS_API_VERSION,
+    };
+    use std::ffi::{c_void, CStr};
+    use std::os::raw::c_char;
+    use std::path::PathBuf;
+    use std::slice;
+    use vfs::VfsConfig;
+
+    const FAKE_NAME: &CStr = c"fake_scanner";
+
+    // Supports all files starting with FAKE
+    unsafe extern "C" fn fake_probe(data: *const u8, size: u64, _filename: *const c_char, _total: u64) -> ProbeResult {
+        match slice::from_raw_parts(data, size as usize).starts_with(b"FAKE") {
+            true => ProbeResult::Supported,
+            false => ProbeResult::Unsupported,
+        }
+    }
+
+    // Reports the url as title and the volume setting it sees for the song as a tag
+    unsafe extern "C" fn fake_metadata(url: *const c_char, services: *const ServiceFFI) -> i32 {
+        let services = &*services;
+        let metadata: &MetadataFFI = &*(services.get_metadata)(services.private_data, RV_METADATA_API_VERSION);
+        let settings: &SettingsFFI = &*(services.get_settings)(services.private_data, RV_SETTINGS_API_VERSION);
+
+        let volume = (settings.get_int)(settings.private_data, FAKE_NAME.as_ptr(), c"".as_ptr(), c"volume".as_ptr());
+
+        let id = (metadata.create_url)(metadata.private_data, url);
+        (metadata.set_tag)(metadata.private_data, id, c"title".as_ptr(), url);
+        (metadata.set_tag_f64)(metadata.private_data, id, c"volume".as_ptr(), volume.value as f64);
+        0
+    }
+
+    // The scanner never creates playback instances
+    unsafe extern "C" fn unused_extensions() -> *const c_char { unreachable!() }
+    unsafe extern "C" fn unused_create(_: *const ServiceFFI) -> *mut c_void { unreachable!() }
+    unsafe extern "C" fn unused_destroy(_: *mut c_void) -> i32 { unreachable!() }
+    unsafe extern "C" fn unused_event(_: *mut c_void, _: *const u8, _: u64) { unreachable!() }
+    unsafe extern "C" fn unused_open(_: *mut c_void, _: *const c_char, _: u32, _: *const ServiceFFI) -> i32 { unreachable!() }
+    unsafe extern "C" fn unused_close(_: *mut c_void) { unreachable!() }
+    unsafe extern "C" fn unused_read(_: *mut c_void, _: ReadData) -> ReadInfo { unreachable!() }
+    unsafe extern "C" fn unused_seek(_: *mut c_void, _: i64) -> i64 { unreachable!() }
+    unsafe extern "C" fn unused_static_init(_: *const ServiceFFI) { unreachable!() }
+    unsafe extern "C" fn unused_settings_updated(_: *mut c_void, _: *const ServiceFFI) -> SettingsUpdate { unreachable!() }
+
+    fn fake_plugin(service: &PluginService) -> Box<PlaybackPlugin> {
+        // The functions live in the test binary so that is the library the plugin is "loaded" from
+        #[cfg(unix)]
+        let plugin: Library = libloading::os::unix::Library::this().into();
+        #[cfg(windows)]
+        let plugin: Library = libloading::os::windows::Library::this().unwrap().into();
+
+        Box::new(PlaybackPlugin {
+            plugin,
+            service: PluginService::clone_with_log_name(service, "fake_scanner"),
+            plugin_path: String::new(),
+            plugin_funcs: plugin_types::PlaybackPlugin {
+                api_version: plugin_types::RV_PLAYBACK_PLUGIN_API_VERSION,
+                name: FAKE_NAME.as_ptr(),
+                version: c"0.1".as_ptr(),
+                library_version: c"0.1".as_ptr(),
+                probe_can_play: fake_probe,
+                supported_extensions: unused_extensions,
+                create: unused_create,
+                destroy: unused_destroy,
+                event: unused_event,
+                open: unused_open,
+                close: unused_close,
+                read_data: unused_read,
+                seek: unused_seek,
+                metadata: fake_metadata,
+                static_init: unused_static_init,
+                settings_updated: unused_settings_updated,
+            },
+            settings_ids: vec![FAKE_NAME.to_string_lossy().into_owned()],
+        })
+    }
+
+    // Registers the settings of the fake plugin. Leaked as plugins keep theirs for as long as they are loaded
+    fn fake_settings(service: &PluginService) {
+        let settings: &'static [Setting] = Box::leak(Box::new([Setting {
+            int_value: SInteger {
+                s_base: SBase {
+                    widget_id: c"volume".as_ptr(),
+                    name: c"Volume".as_ptr(),
+                    desc: c"".as_ptr(),
+                    widget_type: RVS_INTEGER_TYPE,
+                },
+                value: 50,
+                start_range: 0,
+                end_range: 100,
+            },
+        }]));
+
+        service.settings().reg(&FAKE_NAME.to_string_lossy(), settings);
+    }
+
+    fn context() -> ScanContext {
+        let vfs = Vfs::new_with_config(VfsConfig {
+            hash_md5: true,
+            ..Default::default()
+        });
+
+        let service = PluginService::new("scanner_test", vfs.clone());
+        fake_settings(&service);
+
+        ScanContext {
+            plugins: Arc::new(RwLock::new(vec![fake_plugin(&service)])),
+            vfs,
+            service,
+            song_lengths: SongLengths::new(),
+            stil: Stil::new(),
+        }
+    }
+
+    // Creates an empty directory for a test. Returns the path as a url
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rv_core_scanner_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn url(path: &Path) -> String {
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn scan_metadata_from_plugin() {
+        let dir = temp_dir("scan_metadata");
+        let song = dir.join("song.fake");
+        let other = dir.join("other.txt");
+        std::fs::write(&song, "FAKE song").unwrap();
+        std::fs::write(&other, "not a song").unwrap();
+
+        let ctx = context();
+        let metadata = scan_metadata(&ctx, &url(&song)).unwrap();
+
+        assert_eq!(metadata.url, url(&song));
+        assert_eq!(metadata.tag(RV_METADATA_TITLE_TAG), Some(&TagValue::Str(url(&song))));
+        assert_eq!(metadata.tag("volume"), Some(&TagValue::F64(50.0)));
+        // md5 of "FAKE song"
+        assert_eq!(metadata.hash.as_deref(), Some("1fce23bfd651f5a83722582cffdaea50"));
+        assert_eq!(ctx.service.get_metadata_for_url(&url(&song)), Some(metadata));
+
+        assert!(scan_metadata(&ctx, &url(&other)).is_err());
+        assert!(scan_metadata(&ctx, &url(&dir)).is_err());
+        assert!(scan_metadata(&ctx, &url(&dir.join("missing.fake"))).is_err());
+        assert_eq!(ctx.service.metadata_id(&url(&other)), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scan_metadata_applies_song_settings() {
+        let dir = temp_dir("song_settings");
+        let song = dir.join("song.fake");
+        let other = dir.join("other.fake");
+        std::fs::write(&song, "FAKE song").unwrap();
+        std::fs::write(&other, "FAKE other").unwrap();
+
+        let ctx = context();
+        let layer = SettingsLayer::Song(url(&song));
+        let res = ctx.service.settings().set(&FAKE_NAME.to_string_lossy(), &layer, "volume", &SettingValue::Int(75));
+        assert_eq!(res, services::SettingsResult::Ok);
+
+        // The plugin sees the override for the song while reporting metadata for it, but not for other songs
+        let metadata = scan_metadata(&ctx, &url(&song)).unwrap();
+        assert_eq!(metadata.tag("volume"), Some(&TagValue::F64(75.0)));
+
+        let metadata = scan_metadata(&ctx, &url(&other)).unwrap();
+        assert_eq!(metadata.tag("volume"), Some(&TagValue::F64(50.0)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Waits for the scan to finish and returns the final status
+    fn wait_finished(scanner: &Scanner) -> ScanStatus {
+        loop {
+            match scanner.events.recv_timeout(Duration::from_secs(10)).expect("scan didn't finish") {
+                ScanEvent::Finished(status) => return status,
+                ScanEvent::Failed(url, e) => panic!("{} failed: {}", url, e),
+                ScanEvent::Progress(_) => (),
+            }
+        }
+    }
+
+    fn set_modified(path: &Path, time: std::time::SystemTime) {
+        std::fs::File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn scanner_counts_and_skips_unchanged() {
+        let dir = temp_dir("skip_unchanged");
+        let a = dir.join("a.fake");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(&a, "FAKE a").unwrap();
+        std::fs::write(dir.join("b.fake"), "FAKE b").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a song").unwrap();
+        std::fs::write(dir.join("sub/c.fake"), "FAKE c").unwrap();
+
+        let ctx = context();
+        let metadata = ctx.service.metadata();
+        let scanner = Scanner::new(ctx.clone()).unwrap();
+
+        scanner.add_root(&url(&dir));
+        let status = wait_finished(&scanner);
+
+        assert_eq!((status.scanned, status.unchanged, status.unsupported, status.failed), (3, 0, 1, 0));
+        assert!(!status.is_running());
+        assert_eq!(scanner.status(), status);
+
+        // Unsupported files don't get a content hash stored
+        assert!(metadata.id_for_url(&url(&dir.join("notes.txt"))).is_none());
+        let a_hash = metadata.get_for_url(&url(&a)).unwrap().hash;
+        assert!(a_hash.is_some());
+
+        // Nothing changed so all files (also the unsupported one) are skipped
+        scanner.rescan();
+        let status = wait_finished(&scanner);
+        assert_eq!((status.scanned, status.unchanged, status.unsupported, status.failed), (3, 4, 1, 0));
+
+        // A changed size is picked up
+        std::fs::write(dir.join("b.fake"), "FAKE bb").unwrap();
+        scanner.rescan();
+        let status = wait_finished(&scanner);
+        assert_eq!((status.scanned, status.unchanged, status.unsupported), (4, 7, 1));
+
+        // Same size and modification time isn't loaded at all, so the new content isn't seen
+        let notes = dir.join("notes.txt");
+        let modified = std::fs::metadata(&a).unwrap().modified().unwrap();
+        let notes_modified = std::fs::metadata(&notes).unwrap().modified().unwrap();
+        std::fs::write(&a, "FAKE z").unwrap();
+        std::fs::write(&notes, "FAKE notes").unwrap();
+        set_modified(&a, modified);
+        set_modified(&notes, notes_modified);
+
+        scanner.rescan();
+        let status = wait_finished(&scanner);
+        assert_eq!((status.scanned, status.unchanged, status.unsupported), (4, 11, 1));
+        assert_eq!(metadata.get_for_url(&url(&a)).unwrap().hash, a_hash);
+        assert!(metadata.id_for_url(&url(&notes)).is_none());
+
+        // A new modification time makes it load the file
+        set_modified(&a, modified + Duration::from_secs(60));
+        scanner.rescan();
+        let status = wait_finished(&scanner);
+        assert_eq!((status.scanned, status.unchanged), (5, 14));
+        assert_ne!(metadata.get_for_url(&url(&a)).unwrap().hash, a_hash);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scanner_unchanged_content() {
+        let dir = temp_dir("unchanged_content");
+        let a = dir.join("a.fake");
+        std::fs::write(&a, "FAKE a").unwrap();
+
+        let ctx = context();
+        let metadata = ctx.service.metadata();
+        let scanner = Scanner::new(ctx.clone()).unwrap();
+
+        scanner.add_root(&url(&dir));
+        assert_eq!(wait_finished(&scanner).scanned, 1);
+        let info = metadata.get_for_url(&url(&a)).unwrap().file_info.unwrap();
+        assert_eq!(info.size, 6);
+
+        // Touched but with the same content. Skipped by the hash and the new time is stored for the next scan
+        let modified = std::fs::metadata(&a).unwrap().modified().unwrap();
+        set_modified(&a, modified + Duration::from_secs(60));
+
+        scanner.rescan();
+        let status = wait_finished(&scanner);
+        assert_eq!((status.scanned, status.unchanged), (1, 1));
+        assert_ne!(metadata.get_for_url(&url(&a)).unwrap().file_info, Some(info));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scanner_pause_resume() {
+        let dir = temp_dir("pause_resume");
+        std::fs::write(dir.join("a.fake"), "FAKE a").unwrap();
+        std::fs::write(dir.join("b.fake"), "FAKE b").unwrap();
+
+        let scanner = Scanner::new(context()).unwrap();
+        scanner.pause();
+        scanner.add_root(&url(&dir));
+
+        // Wait for the scanner to get the root
+        let start = Instant::now();
+        while scanner.status().pending == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        thread::sleep(Duration::from_millis(200));
+
+        let status = scanner.status();
+        assert!(status.paused);
+        assert!(status.is_running());
+        assert_eq!((status.pending, status.scanned), (1, 0));
+        assert!(scanner.events.try_recv().is_err());
+
+        scanner.resume();
+        let status = wait_finished(&scanner);
+        assert!(!status.paused);
+        assert_eq!((status.pending, status.scanned), (0, 2));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
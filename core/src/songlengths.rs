@@ -0,0 +1,203 @@
+//! Song length database keyed by the MD5 of the file contents. Tracker and SID tunes often loop forever so
+//! lengths from the database are used to end (or go to the next subsong of) a tune.
+//!
+//! Supported formats are HVSC `Songlengths.md5` and a generic list with one `hash=length` per line:
+//!
+//! ```text
+//! [Database]
+//! ; /MUSICIANS/H/Hubbard_Rob/Commando.sid
+//! 2d8d5a1f6c4a4f6f2e2b6a9e1c0b7a33=3:57 0:12(G) 0:05.500
+//! 5b1e0f...=183.5
+//! ```
+//!
+//! Each line has the lengths of the subsongs in order as `m:ss`, `m:ss.sss`, `h:mm:ss` or seconds. Attributes in
+//! parentheses after a length (HVSC uses these for looping and sample based tunes) are ignored.
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Shared song length database. Clones refer to the same data
+#[derive(Clone, Default)]
+pub struct SongLengths {
+    lengths: Arc<RwLock<HashMap<String, Vec<f64>>>>,
+}
+
+// Parses a length in seconds from m:ss, m:ss.sss, h:mm:ss or seconds. An attribute suffix such as (G) is ignored
+fn parse_length(text: &str) -> Option<f64> {
+    let text = match text.find('(') {
+        Some(pos) => &text[..pos],
+        None => text,
+    };
+
+    let mut seconds = 0.0;
+
+    for part in text.split(':') {
+        let value: f64 = part.trim().parse().ok()?;
+
+        if value < 0.0 {
+            return None;
+        }
+
+        seconds = seconds * 60.0 + value;
+    }
+
+    Some(seconds)
+}
+
+impl SongLengths {
+    pub fn new() -> SongLengths {
+        SongLengths::default()
+    }
+
+    /// Adds the entries in the text (replacing earlier entries for the same hashes). Returns the number of entries
+    /// added. Lines that can't be parsed are skipped.
+    pub fn parse(&self, text: &str) -> usize {
+        let mut lengths = self.lengths.write();
+        let mut count = 0;
+
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') || line.starts_with('[') {
+                continue;
+            }
+
+            let entry = line.split_once('=').and_then(|(hash, values)| {
+                let hash = hash.trim().to_lowercase();
+
+                if hash.len() != 32 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return None;
+                }
+
+                let values: Option<Vec<f64>> = values.split_whitespace().map(parse_length).collect();
+
+                match values {
+                    Some(values) if !values.is_empty() => Some((hash, values)),
+                    _ => None,
+                }
+            });
+
+            match entry {
+                Some((hash, values)) => {
+                    lengths.insert(hash, values);
+                    count += 1;
+                }
+                None => warn!("Song lengths: skipping invalid line {}: {}", line_number + 1, line),
+            }
+        }
+
+        count
+    }
+
+    /// Loads a database file. Returns the number of entries added
+    pub fn load(&self, path: &Path) -> Result<usize> {
+        let data = std::fs::read(path).with_context(|| format!("Unable to read song lengths from {:?}", path))?;
+        // The files are ASCII (comments may be Latin-1) so invalid UTF-8 is fine to replace
+        let count = self.parse(&String::from_utf8_lossy(&data));
+        info!("Loaded {} song lengths from {:?}", count, path);
+        Ok(count)
+    }
+
+    /// Lengths (in seconds) of all subsongs for the content hash (MD5 as hex)
+    pub fn get(&self, md5: &str) -> Option<Vec<f64>> {
+        self.lengths.read().get(&md5.to_lowercase()).cloned()
+    }
+
+    /// Length (in seconds) of a subsong (0 is the first one)
+    pub fn length(&self, md5: &str, subsong: usize) -> Option<f64> {
+        self.lengths.read().get(&md5.to_lowercase()).and_then(|l| l.get(subsong).copied())
+    }
+
+    pub fn len(&self) -> usize {
+        self.lengths.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_A: &str = "2d8d5a1f6c4a4f6f2e2b6a9e1c0b7a33";
+    const HASH_B: &str = "5b1e0f3c9d2a4b6e8f7a1c3e5d7b9f20";
+
+    #[test]
+    fn lengths() {
+        assert_eq!(parse_length("3:57"), Some(237.0));
+        assert_eq!(parse_length("0:05.500"), Some(5.5));
+        assert_eq!(parse_length("1:02:03"), Some(3723.0));
+        assert_eq!(parse_length("183.5"), Some(183.5));
+        assert_eq!(parse_length("0:12(G)"), Some(12.0));
+        assert_eq!(parse_length("1:00(M)"), Some(60.0));
+
+        assert_eq!(parse_length(""), None);
+        assert_eq!(parse_length("abc"), None);
+        assert_eq!(parse_length("1:xx"), None);
+        assert_eq!(parse_length("-1:00"), None);
+        assert_eq!(parse_length("1::00"), None);
+    }
+
+    #[test]
+    fn hvsc() {
+        let text = format!(
+            "[Database]\n\
+             ; /MUSICIANS/H/Hubbard_Rob/Commando.sid\n\
+             {}=3:57 0:12(G) 0:05.500\n\
+             ; /MUSICIANS/X/Test.sid\n\
+             {}=1:00:00(M)\n",
+            HASH_A,
+            HASH_B.to_uppercase()
+        );
+
+        let lengths = SongLengths::new();
+        assert_eq!(lengths.parse(&text), 2);
+        assert_eq!(lengths.len(), 2);
+        assert_eq!(lengths.get(HASH_A), Some(vec![237.0, 12.0, 5.5]));
+        assert_eq!(lengths.length(&HASH_A.to_uppercase(), 2), Some(5.5));
+        assert_eq!(lengths.length(HASH_A, 3), None);
+        // Hashes are stored in lower case
+        assert_eq!(lengths.get(HASH_B), Some(vec![3600.0]));
+    }
+
+    #[test]
+    fn generic() {
+        let lengths = SongLengths::new();
+        let text = format!("# comment\n\n  {} = 183.5  \n{}=90\n", HASH_A, HASH_B);
+
+        assert_eq!(lengths.parse(&text), 2);
+        assert_eq!(lengths.length(HASH_A, 0), Some(183.5));
+        assert_eq!(lengths.length(HASH_B, 0), Some(90.0));
+
+        // Later entries replace earlier ones
+        assert_eq!(lengths.parse(&format!("{}=10", HASH_A)), 1);
+        assert_eq!(lengths.get(HASH_A), Some(vec![10.0]));
+        assert_eq!(lengths.len(), 2);
+    }
+
+    #[test]
+    fn bad_lines() {
+        let text = format!(
+            "no equals sign\n\
+             1234=1:00\n\
+             zz8d5a1f6c4a4f6f2e2b6a9e1c0b7a33=1:00\n\
+             {}=\n\
+             {}=1:00 bad\n\
+             {}=0:30\n",
+            HASH_A, HASH_A, HASH_B
+        );
+
+        let lengths = SongLengths::new();
+        assert_eq!(lengths.parse(&text), 1);
+        assert!(lengths.get(HASH_A).is_none());
+        assert_eq!(lengths.length(HASH_B, 0), Some(30.0));
+
+        assert!(SongLengths::new().is_empty());
+    }
+}
@@ -0,0 +1,344 @@
+//! HVSC `STIL.txt` and `BUGlist.txt` import. Entries are keyed by the HVSC relative path of the tune and
+//! attached as tags to the metadata of files whose url ends with that path:
+//!
+//! ```text
+//! /MUSICIANS/H/Hubbard_Rob/Commando.sid
+//!  COMMENT: Also used in the arcade conversion.
+//! (#2)
+//!    TITLE: Theme from an old film (cover)
+//!   ARTIST: Someone
+//! ```
+//!
+//! Fields for the whole file become `stil_<field>` tags (`stil_comment`, `stil_title`, `stil_bug` ...) and fields
+//! for a subsong become `stil_<field>:<subsong>` with the HVSC subsong number (starting at 1). Subsongs that
+//! the plugin didn't report are added with the STIL title (or name) as the name. Directory entries (paths
+//! ending with `/`) are skipped.
+
+use anyhow::{Context, Result};
+use log::info;
+use parking_lot::RwLock;
+use services::metadata::Metadata;
+use services::MetadataId;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use vfs::encoding::{self, LegacyEncoding};
+
+const FIELDS: &[&str] = &["NAME", "AUTHOR", "TITLE", "ARTIST", "COMMENT", "BUG"];
+
+/// Fields (lower case name and text) in the order they appear. A field can appear more than once (such as
+/// several TITLE/ARTIST pairs for a tune using more than one cover)
+pub type StilFields = Vec<(String, String)>;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StilEntry {
+    /// Fields for the whole file
+    pub fields: StilFields,
+    /// Fields for subsongs (HVSC subsong number starting at 1)
+    pub subsongs: BTreeMap<u32, StilFields>,
+}
+
+/// Shared STIL/BUGlist database. Clones refer to the same data
+#[derive(Clone, Default)]
+pub struct Stil {
+    entries: Arc<RwLock<HashMap<String, StilEntry>>>,
+}
+
+// Returns the field name and text if the line starts a field ("  TITLE: text")
+fn parse_field(line: &str) -> Option<(String, &str)> {
+    let (name, text) = line.trim_start().split_once(':')?;
+
+    if FIELDS.contains(&name) {
+        Some((name.to_lowercase(), text.trim()))
+    } else {
+        None
+    }
+}
+
+// Parses a subsong marker such as "(#2)"
+fn parse_subsong(line: &str) -> Option<u32> {
+    line.trim().strip_prefix("(#")?.strip_suffix(')')?.parse().ok()
+}
+
+// Joins the texts of fields with the same name
+fn group_fields(fields: &StilFields) -> Vec<(&str, String)> {
+    let mut grouped: Vec<(&str, String)> = Vec::new();
+
+    for (name, text) in fields {
+        match grouped.iter_mut().find(|(n, _)| n == name) {
+            Some((_, joined)) => {
+                joined.push('\n');
+                joined.push_str(text);
+            }
+            None => grouped.push((name, text.clone())),
+        }
+    }
+
+    grouped
+}
+
+// Adds the fields to the existing ones. Fields with a name in the new fields replace the existing ones with that
+// name so loading a file again doesn't duplicate them
+fn merge_fields(existing: &mut StilFields, fields: StilFields) {
+    existing.retain(|(name, _)| !fields.iter().any(|(n, _)| n == name));
+    existing.extend(fields);
+}
+
+fn field<'a>(fields: &'a StilFields, name: &str) -> Option<&'a str> {
+    fields.iter().find(|(n, _)| n == name).map(|(_, t)| t.as_str())
+}
+
+impl Stil {
+    pub fn new() -> Stil {
+        Stil::default()
+    }
+
+    /// Adds the entries in the text. Fields are added to existing entries for the same path so BUGlist
+    /// entries end up together with the STIL ones, while fields already loaded with the same name are replaced.
+    /// Returns the number of entries in the text.
+    pub fn parse(&self, text: &str) -> usize {
+        let mut entries: HashMap<String, StilEntry> = HashMap::new();
+        let mut path: Option<String> = None;
+        let mut subsong = 0;
+        let mut count = 0;
+
+        for line in text.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+
+            if line.trim().is_empty() {
+                path = None;
+                continue;
+            }
+
+            if line.starts_with('/') {
+                let p = line.trim();
+                // directory comments aren't attached to anything
+                path = if p.ends_with('/') { None } else { Some(p.to_owned()) };
+                subsong = 0;
+                count += path.is_some() as usize;
+                continue;
+            }
+
+            let Some(path) = &path else {
+                continue;
+            };
+
+            if let Some(number) = parse_subsong(line) {
+                subsong = number;
+                continue;
+            }
+
+            let entry = entries.entry(path.clone()).or_default();
+            let fields = if subsong == 0 { &mut entry.fields } else { entry.subsongs.entry(subsong).or_default() };
+
+            match parse_field(line) {
+                Some((name, text)) => fields.push((name, text.to_owned())),
+                // continuation of the previous field
+                None => {
+                    if let Some((_, text)) = fields.last_mut() {
+                        if !text.is_empty() {
+                            text.push(' ');
+                        }
+
+                        text.push_str(line.trim());
+                    }
+                }
+            }
+        }
+
+        let mut existing = self.entries.write();
+
+        for (path, entry) in entries {
+            let existing = existing.entry(path).or_default();
+            merge_fields(&mut existing.fields, entry.fields);
+
+            for (number, fields) in entry.subsongs {
+                merge_fields(existing.subsongs.entry(number).or_default(), fields);
+            }
+        }
+
+        count
+    }
+
+    /// Loads `STIL.txt` or `BUGlist.txt` (the files are ISO-8859-1). Returns the number of entries in the file
+    pub fn load(&self, path: &Path) -> Result<usize> {
+        let data = std::fs::read(path).with_context(|| format!("Unable to read {:?}", path))?;
+        let count = self.parse(&encoding::decode(&data, LegacyEncoding::Latin1));
+        info!("Loaded {} entries from {:?}", count, path);
+        Ok(count)
+    }
+
+    /// Get the entry for a HVSC relative path (such as `/MUSICIANS/H/Hubbard_Rob/Commando.sid`)
+    pub fn get(&self, hvsc_path: &str) -> Option<StilEntry> {
+        self.entries.read().get(hvsc_path).cloned()
+    }
+
+    /// Get the entry for a url (or path) that ends with the HVSC relative path of a tune
+    pub fn find_for_url(&self, url: &str) -> Option<StilEntry> {
+        let url = url.replace('\\', "/");
+        let entries = self.entries.read();
+
+        url.match_indices('/').find_map(|(pos, _)| entries.get(&url[pos..])).cloned()
+    }
+
+    /// Attaches the entry for the url of a metadata entry as tags. Returns false if there is no entry
+    pub fn apply(&self, metadata: &Metadata, id: MetadataId) -> bool {
+        let Some(song) = metadata.get(id) else {
+            return false;
+        };
+
+        let Some(entry) = self.find_for_url(&song.url) else {
+            return false;
+        };
+
+        for (name, text) in group_fields(&entry.fields) {
+            metadata.set_tag(id, &format!("stil_{}", name), &text);
+        }
+
+        for (number, fields) in &entry.subsongs {
+            for (name, text) in group_fields(fields) {
+                metadata.set_tag(id, &format!("stil_{}:{}", name, number), &text);
+            }
+
+            // Plugins number subsongs from 0
+            let index = number.saturating_sub(1);
+
+            if !song.subsongs.iter().any(|s| s.index == index) {
+                let name = field(fields, "title").or_else(|| field(fields, "name")).unwrap_or("");
+                metadata.add_subsong(id, index, name, 0.0);
+            }
+        }
+
+        true
+    }
+
+    /// Attaches entries to all metadata entries they belong to. Returns the number of metadata entries updated
+    pub fn apply_all(&self, metadata: &Metadata) -> usize {
+        if self.entries.read().is_empty() {
+            return 0;
+        }
+
+        let mut ids = Vec::new();
+        metadata.for_each(|id, _| ids.push(id));
+
+        ids.into_iter().filter(|id| self.apply(metadata, *id)).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STIL: &str = "\
+### STIL header comment
+/MUSICIANS/H/Hubbard_Rob/
+ COMMENT: Directory comment
+
+/MUSICIANS/H/Hubbard_Rob/Commando.sid
+ COMMENT: Also used in the arcade
+          conversion.
+(#2)
+   TITLE: Theme from an old film
+  ARTIST: Someone
+   TITLE: Second cover
+(#3)
+    NAME: Ending
+
+/MUSICIANS/X/Other.sid
+  AUTHOR: Someone Else
+";
+
+    const BUGLIST: &str = "\
+/MUSICIANS/H/Hubbard_Rob/Commando.sid
+     BUG: Wrong tempo in the
+          second half.
+";
+
+    fn fields(list: &[(&str, &str)]) -> StilFields {
+        list.iter().map(|(n, t)| (n.to_string(), t.to_string())).collect()
+    }
+
+    #[test]
+    fn parse() {
+        let stil = Stil::new();
+        assert_eq!(stil.parse(STIL), 2);
+
+        let entry = stil.get("/MUSICIANS/H/Hubbard_Rob/Commando.sid").unwrap();
+        assert_eq!(entry.fields, fields(&[("comment", "Also used in the arcade conversion.")]));
+        assert_eq!(entry.subsongs.len(), 2);
+        assert_eq!(
+            entry.subsongs[&2],
+            fields(&[("title", "Theme from an old film"), ("artist", "Someone"), ("title", "Second cover")])
+        );
+        assert_eq!(entry.subsongs[&3], fields(&[("name", "Ending")]));
+
+        assert_eq!(stil.get("/MUSICIANS/X/Other.sid").unwrap().fields, fields(&[("author", "Someone Else")]));
+
+        // Directory entries aren't stored
+        assert!(stil.get("/MUSICIANS/H/Hubbard_Rob/").is_none());
+        assert!(stil.get("/MUSICIANS/H/Hubbard_Rob").is_none());
+    }
+
+    #[test]
+    fn buglist_merge() {
+        let stil = Stil::new();
+        stil.parse(STIL);
+        assert_eq!(stil.parse(BUGLIST), 1);
+
+        let entry = stil.get("/MUSICIANS/H/Hubbard_Rob/Commando.sid").unwrap();
+        assert_eq!(
+            entry.fields,
+            fields(&[("comment", "Also used in the arcade conversion."), ("bug", "Wrong tempo in the second half.")])
+        );
+        assert_eq!(entry.subsongs.len(), 2);
+
+        // Loading the files again doesn't duplicate any fields
+        stil.parse(STIL);
+        stil.parse(BUGLIST);
+        assert_eq!(stil.get("/MUSICIANS/H/Hubbard_Rob/Commando.sid").unwrap(), entry);
+    }
+
+    #[test]
+    fn find_for_url() {
+        let stil = Stil::new();
+        stil.parse(STIL);
+
+        assert!(stil.find_for_url("/home/user/C64Music/MUSICIANS/X/Other.sid").is_some());
+        assert!(stil.find_for_url("C:\\C64Music\\MUSICIANS\\X\\Other.sid").is_some());
+        assert!(stil.find_for_url("/home/user/MUSICIANS/X/Another.sid").is_none());
+    }
+
+    #[test]
+    fn apply() {
+        let stil = Stil::new();
+        stil.parse(STIL);
+        stil.parse(BUGLIST);
+
+        let metadata = Metadata::new();
+        let id = metadata.create_url("/music/C64Music/MUSICIANS/H/Hubbard_Rob/Commando.sid");
+        metadata.add_subsong(id, 1, "Plugin name", 30.0);
+        let other = metadata.create_url("/music/other.mod");
+
+        assert_eq!(stil.apply_all(&metadata), 1);
+
+        let song = metadata.get(id).unwrap();
+        assert_eq!(song.tag("stil_bug").and_then(|t| t.as_str()), Some("Wrong tempo in the second half."));
+        assert_eq!(
+            song.tag("stil_title:2").and_then(|t| t.as_str()),
+            Some("Theme from an old film\nSecond cover")
+        );
+        assert_eq!(song.tag("stil_name:3").and_then(|t| t.as_str()), Some("Ending"));
+
+        // Subsongs reported by the plugin are kept and the missing ones are added
+        let names: Vec<_> = song.subsongs.iter().map(|s| (s.index, s.name.as_str())).collect();
+        assert_eq!(names, [(1, "Plugin name"), (2, "Ending")]);
+
+        assert!(metadata.get(other).unwrap().tags.is_empty());
+
+        // Applying again doesn't change anything
+        stil.apply_all(&metadata);
+        assert_eq!(metadata.get(id).unwrap(), song);
+    }
+}
@@ -0,0 +1,391 @@
+use cfixed_string::CFixedString;
+use log::trace;
+use plugin_types::{VfsLoadStatus, VfsProgress};
+use services::PluginService;
+use std::{ffi::CStr, fmt, os::raw::{c_char, c_void}, ptr};
+use vfs::{Bytes, FilesDirs, InternalError, LoadStatus, Progress, Vfs, VfsDriver, VfsDriverType};
+
+use crate::plugin_handler::VfsPlugins;
+
+// The plugin instances are only ever accessed from the vfs worker thread
+unsafe impl Send for VfsPluginDriver {}
+
+/// Wraps a `rv_vfs_plugin` so it can be used as a regular driver inside the vfs
+pub struct VfsPluginDriver {
+    name: &'static str,
+    service: PluginService,
+    plugin_funcs: plugin_types::VfsPlugin,
+    user_data: *mut c_void,
+    /// Data the instance was created from. The plugin is allowed to reference it so it has to stay alive
+    /// for as long as the instance
+    _data: Option<Bytes>,
+}
+
+impl fmt::Debug for VfsPluginDriver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VfsPluginDriver")
+            .field("name", &self.name)
+            .field("user_data", &self.user_data)
+            .finish()
+    }
+}
+
+unsafe extern "C" fn progress_set_step(self_c: *mut c_void, count: u64) {
+    let progress: &mut Progress = &mut *(self_c as *mut Progress);
+    progress.set_step(count as _);
+}
+
+unsafe extern "C" fn progress_step(self_c: *mut c_void) -> i32 {
+    let progress: &mut Progress = &mut *(self_c as *mut Progress);
+    match progress.step() {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+fn new_c_progress(progress: &mut Progress) -> VfsProgress {
+    VfsProgress {
+        private_data: progress as *mut Progress as *mut c_void,
+        set_step: progress_set_step,
+        step: progress_step,
+    }
+}
+
+fn c_strings_to_vec(strings: &[*const c_char]) -> Vec<String> {
+    strings
+        .iter()
+        .filter(|s| !s.is_null())
+        .map(|s| unsafe { CStr::from_ptr(*s) }.to_string_lossy().into_owned())
+        .collect()
+}
+
+impl VfsPluginDriver {
+    pub fn new(name: &'static str, plugin_funcs: plugin_types::VfsPlugin, service: PluginService) -> VfsPluginDriver {
+        VfsPluginDriver {
+            name,
+            service,
+            plugin_funcs,
+            user_data: ptr::null_mut(),
+            _data: None,
+        }
+    }
+
+    // Instances from create_instance aren't created by the plugin so there is nothing to load from
+    fn instance(&self) -> Result<*mut c_void, InternalError> {
+        if self.user_data.is_null() {
+            return Err(InternalError::DriverError(format!("{} : no plugin instance has been created", self.name)));
+        }
+
+        Ok(self.user_data)
+    }
+
+    fn with_instance(&self, user_data: *mut c_void, data: Option<Bytes>) -> VfsPluginDriver {
+        VfsPluginDriver {
+            name: self.name,
+            service: self.service.clone(),
+            plugin_funcs: self.plugin_funcs,
+            user_data,
+            _data: data,
+        }
+    }
+}
+
+impl Drop for VfsPluginDriver {
+    fn drop(&mut self) {
+        if !self.user_data.is_null() {
+            unsafe { (self.plugin_funcs.destroy)(self.user_data) };
+        }
+    }
+}
+
+impl VfsDriver for VfsPluginDriver {
+    fn is_remote(&self) -> bool {
+        unsafe { (self.plugin_funcs.is_remote)() }
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn supports_url(&self, url: &str) -> bool {
+        let c_url = CFixedString::from_str(url);
+        unsafe { (self.plugin_funcs.supports_url)(c_url.as_ptr()) }
+    }
+
+    fn create_instance(&self) -> VfsDriverType {
+        Box::new(self.with_instance(ptr::null_mut(), None))
+    }
+
+    fn can_load_from_data(&self, data: &[u8]) -> bool {
+        unsafe { (self.plugin_funcs.can_load_from_data)(data.as_ptr(), data.len() as _) }
+    }
+
+    fn create_from_data(&self, data: Bytes) -> Result<VfsDriverType, InternalError> {
+        let user_data = unsafe {
+            (self.plugin_funcs.create_from_data)(data.as_ptr(), data.len() as _, self.service.get_c_api())
+        };
+
+        if user_data.is_null() {
+            return Err(InternalError::DriverError(format!("{} : unable to create instance from data", self.name)));
+        }
+
+        Ok(Box::new(self.with_instance(user_data, Some(data))))
+    }
+
+    fn can_load_from_url(&self, url: &str) -> bool {
+        let c_url = CFixedString::from_str(url);
+        unsafe { (self.plugin_funcs.can_load_from_url)(c_url.as_ptr()) }
+    }
+
+    fn create_from_url(&self, url: &str) -> Result<VfsDriverType, InternalError> {
+        let c_url = CFixedString::from_str(url);
+        let user_data = unsafe { (self.plugin_funcs.create_from_url)(c_url.as_ptr(), self.service.get_c_api()) };
+
+        if user_data.is_null() {
+            return Err(InternalError::DriverError(format!("{} : unable to create instance from {}", self.name, url)));
+        }
+
+        Ok(Box::new(self.with_instance(user_data, None)))
+    }
+
+    fn load_url(&mut self, path: &str, progress: &mut Progress) -> Result<LoadStatus, InternalError> {
+        let user_data = self.instance()?;
+        let c_path = CFixedString::from_str(path);
+        let c_progress = new_c_progress(progress);
+        let res = unsafe { (self.plugin_funcs.load_url)(user_data, c_path.as_ptr(), &c_progress) };
+
+        let status = match res.status {
+            VfsLoadStatus::Data if res.data.is_null() => LoadStatus::Data(Bytes::new()),
+            VfsLoadStatus::Data => LoadStatus::Data(Bytes::copy_from_slice(res.get_data())),
+            VfsLoadStatus::Directory => LoadStatus::Directory,
+            VfsLoadStatus::NotFound => LoadStatus::NotFound,
+            VfsLoadStatus::Error => {
+                unsafe { (self.plugin_funcs.free_load_result)(user_data, res) };
+                return Err(InternalError::DriverError(format!("{} : unable to load {}", self.name, path)));
+            }
+        };
+
+        unsafe { (self.plugin_funcs.free_load_result)(user_data, res) };
+
+        Ok(status)
+    }
+
+    fn get_directory_list(&mut self, path: &str, progress: &mut Progress) -> Result<FilesDirs, InternalError> {
+        let user_data = self.instance()?;
+        let c_path = CFixedString::from_str(path);
+        let c_progress = new_c_progress(progress);
+        let list = unsafe { (self.plugin_funcs.get_directory_list)(user_data, c_path.as_ptr(), &c_progress) };
+
+        if list.status == VfsLoadStatus::Error {
+            unsafe { (self.plugin_funcs.free_directory_list)(user_data, list) };
+            return Err(InternalError::DriverError(format!("{} : unable to list {}", self.name, path)));
+        }
+
+        let mut files = if list.files.is_null() { Vec::new() } else { c_strings_to_vec(list.get_files()) };
+        let mut dirs = if list.dirs.is_null() { Vec::new() } else { c_strings_to_vec(list.get_dirs()) };
+
+        unsafe { (self.plugin_funcs.free_directory_list)(user_data, list) };
+
+        files.sort();
+        dirs.sort();
+
+        Ok(FilesDirs::new(files, dirs))
+    }
+}
+
+/// Registers all loaded vfs plugins as drivers in the vfs
+pub fn register_vfs_plugins(plugins: &VfsPlugins, vfs: &Vfs) {
+    for plugin in plugins.read().iter() {
+        let name: &'static str = Box::leak(plugin.plugin_funcs.get_name().into_owned().into_boxed_str());
+        trace!("Registering vfs driver {}", name);
+        vfs.add_driver(Box::new(VfsPluginDriver::new(name, plugin.plugin_funcs, plugin.service.clone())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ffi::CString, time::Duration};
+    use vfs::{Response, WaitError};
+
+    // Minimal plugin that serves `rv_fake_vfs/hello.txt` for any url containing `rv_fake_vfs`. The instance
+    // is the url it was created from
+    const FAKE_NAME: &str = "rv_fake_vfs";
+    const FAKE_DATA: &[u8] = b"plugin";
+
+    unsafe extern "C" fn is_remote() -> bool {
+        false
+    }
+
+    unsafe extern "C" fn supports_url(url: *const c_char) -> bool {
+        CStr::from_ptr(url).to_string_lossy().contains(FAKE_NAME)
+    }
+
+    unsafe extern "C" fn can_load_from_data(_data: *const u8, _data_size: u64) -> bool {
+        false
+    }
+
+    unsafe extern "C" fn can_load_from_url(_url: *const c_char) -> bool {
+        true
+    }
+
+    unsafe extern "C" fn create_from_data(_data: *const u8, _size: u64, _services: *const services::ServiceFFI) -> *mut c_void {
+        ptr::null_mut()
+    }
+
+    unsafe extern "C" fn create_from_url(url: *const c_char, _services: *const services::ServiceFFI) -> *mut c_void {
+        let url = CStr::from_ptr(url).to_string_lossy().into_owned();
+        Box::into_raw(Box::new(url)) as *mut c_void
+    }
+
+    unsafe extern "C" fn destroy(user_data: *mut c_void) -> i32 {
+        drop(Box::from_raw(user_data as *mut String));
+        0
+    }
+
+    unsafe fn full_path(user_data: *mut c_void, path: *const c_char) -> String {
+        let base = &*(user_data as *const String);
+        let path = CStr::from_ptr(path).to_string_lossy();
+
+        if path.is_empty() {
+            base.clone()
+        } else {
+            format!("{}/{}", base, path)
+        }
+    }
+
+    unsafe extern "C" fn load_url(user_data: *mut c_void, path: *const c_char, progress: *const VfsProgress) -> plugin_types::VfsLoadResult {
+        let progress = &*progress;
+        (progress.set_step)(progress.private_data, 1);
+        (progress.step)(progress.private_data);
+
+        let path = full_path(user_data, path);
+        let mut res = plugin_types::VfsLoadResult { status: VfsLoadStatus::NotFound, data: ptr::null(), data_size: 0 };
+
+        if path.ends_with("hello.txt") {
+            res.status = VfsLoadStatus::Data;
+            res.data = FAKE_DATA.as_ptr();
+            res.data_size = FAKE_DATA.len() as _;
+        } else if path.ends_with(FAKE_NAME) {
+            res.status = VfsLoadStatus::Directory;
+        } else if path.ends_with("broken") {
+            res.status = VfsLoadStatus::Error;
+        }
+
+        res
+    }
+
+    unsafe extern "C" fn free_load_result(_user_data: *mut c_void, _result: plugin_types::VfsLoadResult) {}
+
+    unsafe extern "C" fn get_directory_list(_user_data: *mut c_void, _path: *const c_char, _progress: *const VfsProgress) -> plugin_types::VfsDirectoryList {
+        let files: Box<[*const c_char]> = Box::new([CString::new("hello.txt").unwrap().into_raw() as *const c_char]);
+
+        plugin_types::VfsDirectoryList {
+            status: VfsLoadStatus::Directory,
+            files_size: files.len() as _,
+            files: Box::into_raw(files) as *const *const c_char,
+            dirs: ptr::null(),
+            dirs_size: 0,
+        }
+    }
+
+    unsafe extern "C" fn free_directory_list(_user_data: *mut c_void, list: plugin_types::VfsDirectoryList) {
+        let files = Box::from_raw(ptr::slice_from_raw_parts_mut(list.files as *mut *const c_char, list.files_size as _));
+
+        for f in files.iter() {
+            drop(CString::from_raw(*f as *mut c_char));
+        }
+    }
+
+    unsafe extern "C" fn static_init(_services: *const services::ServiceFFI) {}
+
+    fn fake_driver(vfs: &Vfs) -> VfsPluginDriver {
+        let plugin = plugin_types::VfsPlugin {
+            api_version: plugin_types::RV_VFS_PLUGIN_API_VERSION,
+            name: c"fake".as_ptr(),
+            version: c"0.1".as_ptr(),
+            library_version: c"0.1".as_ptr(),
+            is_remote,
+            supports_url,
+            can_load_from_data,
+            can_load_from_url,
+            create_from_data,
+            create_from_url,
+            destroy,
+            load_url,
+            free_load_result,
+            get_directory_list,
+            free_directory_list,
+            static_init,
+        };
+
+        VfsPluginDriver::new("fake", plugin, PluginService::new("fake", vfs.clone()))
+    }
+
+    fn load(vfs: &Vfs, url: &str) -> Result<Response, WaitError> {
+        vfs.load_url(url).wait(Duration::from_secs(5))
+    }
+
+    #[test]
+    fn plugin_driver_load() {
+        let vfs = Vfs::new();
+        vfs.add_driver(Box::new(fake_driver(&vfs)));
+
+        match load(&vfs, "rv_fake_vfs/hello.txt") {
+            Ok(Response::Data(data)) => assert_eq!(data.get(), FAKE_DATA),
+            r => panic!("{:?}", r),
+        }
+
+        match load(&vfs, "rv_fake_vfs") {
+            Ok(Response::Directory(files_dirs)) => {
+                assert_eq!(files_dirs.files, vec!["hello.txt".to_string()]);
+                assert!(files_dirs.dirs.is_empty());
+            }
+            r => panic!("{:?}", r),
+        }
+
+        match load(&vfs, "rv_fake_vfs/broken") {
+            Err(WaitError::Vfs(e)) => {
+                assert_eq!(e.driver, Some("fake"));
+                assert!(matches!(e.cause, InternalError::DriverError(_)));
+            }
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn plugin_driver_priority() {
+        let dir = std::env::temp_dir().join("rv_core_vfs_plugin").join(FAKE_NAME);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hello.txt");
+        std::fs::write(&path, b"local").unwrap();
+        let url = path.to_string_lossy().into_owned();
+
+        // Without the plugin the local file is loaded
+        let vfs = Vfs::new();
+
+        match load(&vfs, &url) {
+            Ok(Response::Data(data)) => assert_eq!(data.get(), b"local"),
+            r => panic!("{:?}", r),
+        }
+
+        // Added drivers are tried before the built-in ones
+        let vfs = Vfs::new();
+        vfs.add_driver(Box::new(fake_driver(&vfs)));
+
+        match load(&vfs, &url) {
+            Ok(Response::Data(data)) => assert_eq!(data.get(), FAKE_DATA),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn plugin_driver_without_instance() {
+        let vfs = Vfs::new();
+        let driver = fake_driver(&vfs);
+
+        assert!(driver.instance().is_err());
+        assert!(driver.create_from_data(Bytes::from_static(b"data")).is_err());
+        assert!(driver.create_from_url("rv_fake_vfs").unwrap().supports_url("rv_fake_vfs/hello.txt"));
+    }
+}
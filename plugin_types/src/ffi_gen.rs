@@ -76,16 +76,19 @@ pub struct OutputPlugin {
     pub static_init: unsafe extern "C" fn(services: *const ServiceFFI),
 }
 
+unsafe impl Sync for OutputPlugin {}
+unsafe impl Send for OutputPlugin {}
+
 impl OutputPlugin {
-    pub fn get_name(&self) -> Cow<str> {
+    pub fn get_name(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.name) };
         t.to_string_lossy()
     }
-    pub fn get_version(&self) -> Cow<str> {
+    pub fn get_version(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.version) };
         t.to_string_lossy()
     }
-    pub fn get_library_version(&self) -> Cow<str> {
+    pub fn get_library_version(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.library_version) };
         t.to_string_lossy()
     }
@@ -182,15 +185,15 @@ unsafe impl Sync for PlaybackPlugin {}
 unsafe impl Send for PlaybackPlugin {}
 
 impl PlaybackPlugin {
-    pub fn get_name(&self) -> Cow<str> {
+    pub fn get_name(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.name) };
         t.to_string_lossy()
     }
-    pub fn get_version(&self) -> Cow<str> {
+    pub fn get_version(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.version) };
         t.to_string_lossy()
     }
-    pub fn get_library_version(&self) -> Cow<str> {
+    pub fn get_library_version(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.library_version) };
         t.to_string_lossy()
     }
@@ -237,15 +240,119 @@ unsafe impl Sync for ResamplePlugin {}
 unsafe impl Send for ResamplePlugin {}
 
 impl ResamplePlugin {
-    pub fn get_name(&self) -> Cow<str> {
+    pub fn get_name(&self) -> Cow<'_, str> {
+        let t = unsafe { CStr::from_ptr(self.name) };
+        t.to_string_lossy()
+    }
+    pub fn get_version(&self) -> Cow<'_, str> {
+        let t = unsafe { CStr::from_ptr(self.version) };
+        t.to_string_lossy()
+    }
+    pub fn get_library_version(&self) -> Cow<'_, str> {
+        let t = unsafe { CStr::from_ptr(self.library_version) };
+        t.to_string_lossy()
+    }
+}
+
+pub const RV_VFS_PLUGIN_API_VERSION: u64 = 1;
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VfsLoadStatus {
+    Data = 0,
+    Directory = 1,
+    NotFound = 2,
+    Error = 3,
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct VfsProgress {
+    pub private_data: *mut c_void,
+    pub set_step: unsafe extern "C" fn(self_c: *mut c_void, count: u64),
+    pub step: unsafe extern "C" fn(self_c: *mut c_void) -> i32,
+}
+
+impl VfsProgress {}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct VfsLoadResult {
+    pub status: VfsLoadStatus,
+    pub data: *const u8,
+    pub data_size: u64,
+}
+
+impl VfsLoadResult {
+    pub fn get_data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.data, self.data_size as _) }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct VfsDirectoryList {
+    pub status: VfsLoadStatus,
+    pub files: *const *const c_char,
+    pub files_size: u64,
+    pub dirs: *const *const c_char,
+    pub dirs_size: u64,
+}
+
+impl VfsDirectoryList {
+    pub fn get_files(&self) -> &[*const c_char] {
+        unsafe { slice::from_raw_parts(self.files, self.files_size as _) }
+    }
+    pub fn get_dirs(&self) -> &[*const c_char] {
+        unsafe { slice::from_raw_parts(self.dirs, self.dirs_size as _) }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct VfsPlugin {
+    pub api_version: u64,
+    pub name: *const c_char,
+    pub version: *const c_char,
+    pub library_version: *const c_char,
+    pub is_remote: unsafe extern "C" fn() -> bool,
+    pub supports_url: unsafe extern "C" fn(url: *const c_char) -> bool,
+    pub can_load_from_data: unsafe extern "C" fn(data: *const u8, data_size: u64) -> bool,
+    pub can_load_from_url: unsafe extern "C" fn(url: *const c_char) -> bool,
+    pub create_from_data: unsafe extern "C" fn(
+        data: *const u8,
+        data_size: u64,
+        services: *const ServiceFFI,
+    ) -> *mut c_void,
+    pub create_from_url:
+        unsafe extern "C" fn(url: *const c_char, services: *const ServiceFFI) -> *mut c_void,
+    pub destroy: unsafe extern "C" fn(user_data: *mut c_void) -> i32,
+    pub load_url: unsafe extern "C" fn(
+        user_data: *mut c_void,
+        path: *const c_char,
+        progress: *const VfsProgress,
+    ) -> VfsLoadResult,
+    pub free_load_result: unsafe extern "C" fn(user_data: *mut c_void, result: VfsLoadResult),
+    pub get_directory_list: unsafe extern "C" fn(
+        user_data: *mut c_void,
+        path: *const c_char,
+        progress: *const VfsProgress,
+    ) -> VfsDirectoryList,
+    pub free_directory_list: unsafe extern "C" fn(user_data: *mut c_void, list: VfsDirectoryList),
+    pub static_init: unsafe extern "C" fn(services: *const ServiceFFI),
+}
+
+unsafe impl Sync for VfsPlugin {}
+unsafe impl Send for VfsPlugin {}
+
+impl VfsPlugin {
+    pub fn get_name(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.name) };
         t.to_string_lossy()
     }
-    pub fn get_version(&self) -> Cow<str> {
+    pub fn get_version(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.version) };
         t.to_string_lossy()
     }
-    pub fn get_library_version(&self) -> Cow<str> {
+    pub fn get_library_version(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.library_version) };
         t.to_string_lossy()
     }
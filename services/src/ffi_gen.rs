@@ -93,6 +93,8 @@ pub struct LogFFI {
 pub enum MetaEncoding {
     Utf8 = 0,
     ShiftJS2 = 1,
+    Cp437 = 2,
+    Latin1 = 3,
 }
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -101,7 +103,7 @@ pub enum RVMetadataResult {
     UnableToMakeQuery = 1,
 }
 pub type MetadataId = u64;
-pub const RV_METADATA_API_VERSION: u64 = 1;
+pub const RV_METADATA_API_VERSION: u64 = 2;
 pub const RV_METADATA_TITLE_TAG: &str = "title";
 pub const RV_METADATA_SONGTYPE_TAG: &str = "song_type";
 pub const RV_METADATA_AUTHORINGTOOL_TAG: &str = "authoring_tool";
@@ -112,7 +114,7 @@ pub const RV_METADATA_GENRE_TAG: &str = "genre";
 pub const RV_METADATA_MESSAGE_TAG: &str = "message";
 pub const RV_METADATA_LENGTH_TAG: &str = "length";
 extern "C" fn metadata_create_url(self_c: *mut c_void, url: *const c_char) -> MetadataId {
-    let instance: &mut Metadata = unsafe { &mut *(self_c as *mut Metadata) };
+    let instance: &Metadata = unsafe { &*(self_c as *const Metadata) };
     let url_ = unsafe { CStr::from_ptr(url) };
     let ret_val = instance.create_url(&url_.to_string_lossy());
     ret_val
@@ -124,19 +126,32 @@ extern "C" fn metadata_set_tag(
     tag: *const c_char,
     data: *const c_char,
 ) {
-    let instance: &mut Metadata = unsafe { &mut *(self_c as *mut Metadata) };
+    let instance: &Metadata = unsafe { &*(self_c as *const Metadata) };
     let tag_ = unsafe { CStr::from_ptr(tag) };
     let data_ = unsafe { CStr::from_ptr(data) };
     instance.set_tag(id, &tag_.to_string_lossy(), &data_.to_string_lossy())
 }
 
+extern "C" fn metadata_set_tag_encoded(
+    self_c: *mut c_void,
+    id: MetadataId,
+    tag: *const c_char,
+    data: *const c_char,
+    encoding: MetaEncoding,
+) {
+    let instance: &Metadata = unsafe { &*(self_c as *const Metadata) };
+    let tag_ = unsafe { CStr::from_ptr(tag) };
+    let data_ = unsafe { CStr::from_ptr(data) };
+    instance.set_tag_encoded(id, &tag_.to_string_lossy(), data_.to_bytes(), encoding)
+}
+
 extern "C" fn metadata_set_tag_f64(
     self_c: *mut c_void,
     id: MetadataId,
     tag: *const c_char,
     data: f64,
 ) {
-    let instance: &mut Metadata = unsafe { &mut *(self_c as *mut Metadata) };
+    let instance: &Metadata = unsafe { &*(self_c as *const Metadata) };
     let tag_ = unsafe { CStr::from_ptr(tag) };
     instance.set_tag_f64(id, &tag_.to_string_lossy(), data)
 }
@@ -148,13 +163,13 @@ extern "C" fn metadata_add_subsong(
     name: *const c_char,
     length: f32,
 ) {
-    let instance: &mut Metadata = unsafe { &mut *(self_c as *mut Metadata) };
+    let instance: &Metadata = unsafe { &*(self_c as *const Metadata) };
     let name_ = unsafe { CStr::from_ptr(name) };
     instance.add_subsong(parent_id, index, &name_.to_string_lossy(), length)
 }
 
 extern "C" fn metadata_add_sample(self_c: *mut c_void, parent_id: MetadataId, text: *const c_char) {
-    let instance: &mut Metadata = unsafe { &mut *(self_c as *mut Metadata) };
+    let instance: &Metadata = unsafe { &*(self_c as *const Metadata) };
     let text_ = unsafe { CStr::from_ptr(text) };
     instance.add_sample(parent_id, &text_.to_string_lossy())
 }
@@ -164,7 +179,7 @@ extern "C" fn metadata_add_instrument(
     parent_id: MetadataId,
     text: *const c_char,
 ) {
-    let instance: &mut Metadata = unsafe { &mut *(self_c as *mut Metadata) };
+    let instance: &Metadata = unsafe { &*(self_c as *const Metadata) };
     let text_ = unsafe { CStr::from_ptr(text) };
     instance.add_instrument(parent_id, &text_.to_string_lossy())
 }
@@ -193,10 +208,17 @@ pub struct MetadataFFI {
         unsafe extern "C" fn(self_c: *mut c_void, parent_id: MetadataId, text: *const c_char),
     pub add_instrument:
         unsafe extern "C" fn(self_c: *mut c_void, parent_id: MetadataId, text: *const c_char),
+    pub set_tag_encoded: unsafe extern "C" fn(
+        self_c: *mut c_void,
+        id: MetadataId,
+        tag: *const c_char,
+        data: *const c_char,
+        encoding: MetaEncoding,
+    ),
 }
 
 impl MetadataFFI {
-    pub fn new(instance: *mut Metadata) -> MetadataFFI {
+    pub fn new(instance: *const Metadata) -> MetadataFFI {
         MetadataFFI {
             private_data: instance as *mut c_void,
             create_url: metadata_create_url,
@@ -205,6 +227,7 @@ impl MetadataFFI {
             add_subsong: metadata_add_subsong,
             add_sample: metadata_add_sample,
             add_instrument: metadata_add_instrument,
+            set_tag_encoded: metadata_set_tag_encoded,
         }
     }
 }
@@ -216,6 +239,7 @@ pub enum SettingsResult {
     UnknownId = 2,
     DuplicatedId = 3,
     WrongType = 4,
+    InvalidValue = 5,
 }
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -228,7 +252,9 @@ pub const RVS_INTEGER_TYPE: u64 = 0x1001;
 pub const RVS_BOOL_TYPE: u64 = 0x1002;
 pub const RVS_INTEGER_RANGE_TYPE: u64 = 0x1003;
 pub const RVS_STRING_RANGE_TYPE: u64 = 0x1004;
-pub const RV_SETTINGS_API_VERSION: u64 = 1;
+pub const RVS_STRING_TYPE: u64 = 0x1005;
+pub const RVS_PATH_TYPE: u64 = 0x1006;
+pub const RV_SETTINGS_API_VERSION: u64 = 3;
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub union Setting {
@@ -237,6 +263,7 @@ pub union Setting {
     pub int_fixed_value: SIntegerFixedRange,
     pub string_fixed_value: SStringFixedRange,
     pub bool_value: SBool,
+    pub string_value: SString,
 }
 
 #[repr(C)]
@@ -249,15 +276,15 @@ pub struct SBase {
 }
 
 impl SBase {
-    pub fn get_widget_id(&self) -> Cow<str> {
+    pub fn get_widget_id(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.widget_id) };
         t.to_string_lossy()
     }
-    pub fn get_name(&self) -> Cow<str> {
+    pub fn get_name(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.name) };
         t.to_string_lossy()
     }
-    pub fn get_desc(&self) -> Cow<str> {
+    pub fn get_desc(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.desc) };
         t.to_string_lossy()
     }
@@ -294,6 +321,20 @@ pub struct SBool {
 
 impl SBool {}
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct SString {
+    pub s_base: SBase,
+    pub value: *const c_char,
+}
+
+impl SString {
+    pub fn get_value(&self) -> Cow<'_, str> {
+        let t = unsafe { CStr::from_ptr(self.value) };
+        t.to_string_lossy()
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct SIntegerRangeValue {
@@ -302,7 +343,7 @@ pub struct SIntegerRangeValue {
 }
 
 impl SIntegerRangeValue {
-    pub fn get_name(&self) -> Cow<str> {
+    pub fn get_name(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.name) };
         t.to_string_lossy()
     }
@@ -316,11 +357,11 @@ pub struct SStringRangeValue {
 }
 
 impl SStringRangeValue {
-    pub fn get_name(&self) -> Cow<str> {
+    pub fn get_name(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.name) };
         t.to_string_lossy()
     }
-    pub fn get_value(&self) -> Cow<str> {
+    pub fn get_value(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.value) };
         t.to_string_lossy()
     }
@@ -351,7 +392,7 @@ pub struct SStringFixedRange {
 }
 
 impl SStringFixedRange {
-    pub fn get_value(&self) -> Cow<str> {
+    pub fn get_value(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.value) };
         t.to_string_lossy()
     }
@@ -386,7 +427,7 @@ pub struct SStringResult {
 }
 
 impl SStringResult {
-    pub fn get_value(&self) -> Cow<str> {
+    pub fn get_value(&self) -> Cow<'_, str> {
         let t = unsafe { CStr::from_ptr(self.value) };
         t.to_string_lossy()
     }
@@ -407,7 +448,7 @@ extern "C" fn settings_reg(
     settings: *const Setting,
     settings_size: u64,
 ) -> SettingsResult {
-    let instance: &mut Settings = unsafe { &mut *(self_c as *mut Settings) };
+    let instance: &Settings = unsafe { &*(self_c as *const Settings) };
     let id_ = unsafe { CStr::from_ptr(id) };
     let settings_ = unsafe { slice::from_raw_parts(settings, settings_size as _) };
     let ret_val = instance.reg(&id_.to_string_lossy(), settings_);
@@ -420,7 +461,7 @@ extern "C" fn settings_get_string(
     ext: *const c_char,
     id: *const c_char,
 ) -> SStringResult {
-    let instance: &mut Settings = unsafe { &mut *(self_c as *mut Settings) };
+    let instance: &Settings = unsafe { &*(self_c as *const Settings) };
     let reg_id_ = unsafe { CStr::from_ptr(reg_id) };
     let ext_ = unsafe { CStr::from_ptr(ext) };
     let id_ = unsafe { CStr::from_ptr(id) };
@@ -438,7 +479,7 @@ extern "C" fn settings_get_int(
     ext: *const c_char,
     id: *const c_char,
 ) -> SIntResult {
-    let instance: &mut Settings = unsafe { &mut *(self_c as *mut Settings) };
+    let instance: &Settings = unsafe { &*(self_c as *const Settings) };
     let reg_id_ = unsafe { CStr::from_ptr(reg_id) };
     let ext_ = unsafe { CStr::from_ptr(ext) };
     let id_ = unsafe { CStr::from_ptr(id) };
@@ -456,7 +497,7 @@ extern "C" fn settings_get_float(
     ext: *const c_char,
     id: *const c_char,
 ) -> SFloatResult {
-    let instance: &mut Settings = unsafe { &mut *(self_c as *mut Settings) };
+    let instance: &Settings = unsafe { &*(self_c as *const Settings) };
     let reg_id_ = unsafe { CStr::from_ptr(reg_id) };
     let ext_ = unsafe { CStr::from_ptr(ext) };
     let id_ = unsafe { CStr::from_ptr(id) };
@@ -474,7 +515,7 @@ extern "C" fn settings_get_bool(
     ext: *const c_char,
     id: *const c_char,
 ) -> SBoolResult {
-    let instance: &mut Settings = unsafe { &mut *(self_c as *mut Settings) };
+    let instance: &Settings = unsafe { &*(self_c as *const Settings) };
     let reg_id_ = unsafe { CStr::from_ptr(reg_id) };
     let ext_ = unsafe { CStr::from_ptr(ext) };
     let id_ = unsafe { CStr::from_ptr(id) };
@@ -486,6 +527,59 @@ extern "C" fn settings_get_bool(
     ret_val
 }
 
+extern "C" fn settings_set_int(
+    self_c: *mut c_void,
+    reg_id: *const c_char,
+    id: *const c_char,
+    value: i32,
+) -> SettingsResult {
+    let instance: &Settings = unsafe { &*(self_c as *const Settings) };
+    let reg_id_ = unsafe { CStr::from_ptr(reg_id) };
+    let id_ = unsafe { CStr::from_ptr(id) };
+    let ret_val = instance.set_int(&reg_id_.to_string_lossy(), &id_.to_string_lossy(), value);
+    ret_val
+}
+
+extern "C" fn settings_set_float(
+    self_c: *mut c_void,
+    reg_id: *const c_char,
+    id: *const c_char,
+    value: f32,
+) -> SettingsResult {
+    let instance: &Settings = unsafe { &*(self_c as *const Settings) };
+    let reg_id_ = unsafe { CStr::from_ptr(reg_id) };
+    let id_ = unsafe { CStr::from_ptr(id) };
+    let ret_val = instance.set_float(&reg_id_.to_string_lossy(), &id_.to_string_lossy(), value);
+    ret_val
+}
+
+extern "C" fn settings_set_bool(
+    self_c: *mut c_void,
+    reg_id: *const c_char,
+    id: *const c_char,
+    value: bool,
+) -> SettingsResult {
+    let instance: &Settings = unsafe { &*(self_c as *const Settings) };
+    let reg_id_ = unsafe { CStr::from_ptr(reg_id) };
+    let id_ = unsafe { CStr::from_ptr(id) };
+    let ret_val = instance.set_bool(&reg_id_.to_string_lossy(), &id_.to_string_lossy(), value);
+    ret_val
+}
+
+extern "C" fn settings_set_string(
+    self_c: *mut c_void,
+    reg_id: *const c_char,
+    id: *const c_char,
+    value: *const c_char,
+) -> SettingsResult {
+    let instance: &Settings = unsafe { &*(self_c as *const Settings) };
+    let reg_id_ = unsafe { CStr::from_ptr(reg_id) };
+    let id_ = unsafe { CStr::from_ptr(id) };
+    let value_ = unsafe { CStr::from_ptr(value) };
+    let ret_val = instance.set_string(&reg_id_.to_string_lossy(), &id_.to_string_lossy(), &value_.to_string_lossy());
+    ret_val
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct SettingsFFI {
@@ -520,10 +614,34 @@ pub struct SettingsFFI {
         ext: *const c_char,
         id: *const c_char,
     ) -> SBoolResult,
+    pub set_int: unsafe extern "C" fn(
+        self_c: *mut c_void,
+        reg_id: *const c_char,
+        id: *const c_char,
+        value: i32,
+    ) -> SettingsResult,
+    pub set_float: unsafe extern "C" fn(
+        self_c: *mut c_void,
+        reg_id: *const c_char,
+        id: *const c_char,
+        value: f32,
+    ) -> SettingsResult,
+    pub set_bool: unsafe extern "C" fn(
+        self_c: *mut c_void,
+        reg_id: *const c_char,
+        id: *const c_char,
+        value: bool,
+    ) -> SettingsResult,
+    pub set_string: unsafe extern "C" fn(
+        self_c: *mut c_void,
+        reg_id: *const c_char,
+        id: *const c_char,
+        value: *const c_char,
+    ) -> SettingsResult,
 }
 
 impl SettingsFFI {
-    pub fn new(instance: *mut Settings) -> SettingsFFI {
+    pub fn new(instance: *const Settings) -> SettingsFFI {
         SettingsFFI {
             private_data: instance as *mut c_void,
             reg: settings_reg,
@@ -531,6 +649,10 @@ impl SettingsFFI {
             get_int: settings_get_int,
             get_float: settings_get_float,
             get_bool: settings_get_bool,
+            set_int: settings_set_int,
+            set_float: settings_set_float,
+            set_bool: settings_set_bool,
+            set_string: settings_set_string,
         }
     }
 }
@@ -1,17 +1,26 @@
 use std::os::raw::c_void;
-use vfs::{Vfs, RecvMsg};
-use std::{thread, ptr, time::Duration};
+use vfs::{Bytes, Response, Vfs};
+use std::{sync::Mutex, ptr, time::Duration};
 use log::{error};
 
 use crate::ffi_gen::IoReadUrlResult;
+use crate::metadata::Metadata;
+
+// Max time to wait for a file to be loaded (this includes downloading)
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct Io {
     vfs: Vfs,
+    /// Data handed out to plugins. The data is shared with the vfs so it has to be kept alive here
+    /// until the plugin calls `free_url_to_memory`
+    loaded: Mutex<Vec<Bytes>>,
+    /// Content hashes of read files are recorded here so metadata can be keyed by content
+    metadata: &'static Metadata,
 }
 
 impl Io {
-    pub fn new(vfs: Vfs) -> Io {
-        Io { vfs }
+    pub fn new(vfs: Vfs, metadata: &'static Metadata) -> Io {
+        Io { vfs, loaded: Mutex::new(Vec::new()), metadata }
     }
 
     pub fn exists(&mut self, _url: &str) -> bool {
@@ -19,34 +28,24 @@ impl Io {
     }
 
     pub fn read_url_to_memory(&mut self, url: &str) -> IoReadUrlResult {
-        let handle = self.vfs.load_url(url);
-        
-        for _ in 0..100 {
-            let mut should_sleep = true;
-            match handle.recv.try_recv() {
-                Ok(RecvMsg::ReadDone(data)) => {
-                    return IoReadUrlResult {
-                        data: data.ptr, 
-                        data_size: data.size as _,
-                    }
-                },
-                Ok(RecvMsg::Error(e)) => {
-                    error!("{:?}", e);
-                    break;
-                },
-                Ok(RecvMsg::ReadProgress(_)) => should_sleep = false,
-                /*
-                Err(e) => {
-                    error!("{:?}", e);
-                    break;
+        match self.vfs.load_url(url).wait(READ_TIMEOUT) {
+            Ok(Response::Data(data)) => {
+                if let Some(md5) = data.hashes().md5_hex() {
+                    self.metadata.set_content_hash(url, &md5);
                 }
-                */
-                _ => (),
-            }
 
-            if should_sleep {
-                thread::sleep(Duration::from_millis(10));
+                let data = data.into_bytes();
+                let res = IoReadUrlResult {
+                    data: data.as_ptr(),
+                    data_size: data.len() as _,
+                };
+
+                self.loaded.lock().unwrap().push(data);
+                return res;
             }
+            Ok(Response::Directory(_)) => error!("{} is a directory", url),
+            Ok(Response::WriteDone) => (),
+            Err(e) => error!("Unable to read {}: {}", url, e),
         }
 
         IoReadUrlResult {
@@ -55,5 +54,15 @@ impl Io {
         }
     }
 
-    pub fn free_url_to_memory(&mut self, _data: *const c_void) {}
+    pub fn free_url_to_memory(&mut self, data: *const c_void) {
+        let mut loaded = self.loaded.lock().unwrap();
+
+        // The same data can be handed out several times (it's shared with the cache) so only one entry is removed
+        match loaded.iter().position(|d| d.as_ptr() as *const c_void == data) {
+            Some(index) => {
+                loaded.swap_remove(index);
+            }
+            None => error!("free_url_to_memory: {:?} wasn't loaded with read_url_to_memory", data),
+        }
+    }
 }
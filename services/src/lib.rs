@@ -2,8 +2,11 @@ pub mod ffi_gen;
 pub mod io;
 pub mod log;
 pub mod metadata;
+pub mod metadata_db;
 pub mod settings;
 pub use ffi_gen::*;
+use metadata::{Metadata, SongMetadata, TagValue};
+use settings::Settings;
 use vfs::Vfs;
 
 // It's not safe to pass pointers to other therads, so we use this to get around it
@@ -13,23 +16,29 @@ unsafe impl Send for PluginService {}
 #[derive(Clone)]
 pub struct PluginService {
     service_api: *const ServiceFFI,
+    /// Shared by all clones of the service (same as the C api)
+    metadata: &'static Metadata,
+    /// Shared by all clones of the service (same as the C api)
+    settings: &'static Settings,
 }
 
 impl PluginService {
     pub fn new(log_name: &str, vfs: Vfs) -> PluginService {
-        let io_api = Box::leak(Box::new(io::Io::new(vfs)));
-        let metadata_api = Box::leak(Box::new(metadata::Metadata::new()));
-        let settings_api = Box::leak(Box::new(settings::Settings::new()));
+        let metadata: &'static Metadata = Box::leak(Box::new(Metadata::new()));
+        let io_api = Box::leak(Box::new(io::Io::new(vfs, metadata)));
+        let settings: &'static Settings = Box::leak(Box::new(Settings::new()));
 
         let service_api = Box::new(ServiceApi {
             c_io_api: Box::leak(Box::new(IoFFI::new(io_api as _))) as _,
-            c_metadata_api: Box::leak(Box::new(MetadataFFI::new(metadata_api as _))) as _,
-            c_settings_api: Box::leak(Box::new(SettingsFFI::new(settings_api as _))) as _,
+            c_metadata_api: Box::leak(Box::new(MetadataFFI::new(metadata))) as _,
+            c_settings_api: Box::leak(Box::new(SettingsFFI::new(settings))) as _,
             c_log_api: log::Log::new_c_api(log_name),
         });
 
         PluginService {
             service_api: Box::leak(Box::new(ServiceFFI::new(Box::leak(service_api) as _))) as _,
+            metadata,
+            settings,
         }
     }
 
@@ -47,6 +56,8 @@ impl PluginService {
 
         PluginService {
             service_api: Box::leak(Box::new(ServiceFFI::new(Box::leak(service_api) as _))) as _,
+            metadata: base.metadata,
+            settings: base.settings,
         }
     }
 
@@ -55,8 +66,46 @@ impl PluginService {
         self.service_api
     }
 
-    pub fn get_settings_c_api(&self) {
+    pub fn get_settings_c_api(&self) -> *const SettingsFFI {
+        let api_ffi: &ServiceFFI = unsafe { &*self.service_api };
+        let api: &ServiceApi = unsafe { &*(api_ffi.private_data as *const ServiceApi) };
+        api.c_settings_api
+    }
+
+    /// Settings registered by plugins through the C api
+    #[inline]
+    pub fn settings(&self) -> &Settings {
+        self.settings
+    }
+
+    /// Metadata reported by plugins through the C api
+    #[inline]
+    pub fn metadata(&self) -> &Metadata {
+        self.metadata
+    }
+
+    /// Get the id metadata for a url is stored under (if any has been reported)
+    pub fn metadata_id(&self, url: &str) -> Option<MetadataId> {
+        self.metadata().id_for_url(url)
+    }
+
+    /// Get a copy of the metadata for an id
+    pub fn get_metadata(&self, id: MetadataId) -> Option<SongMetadata> {
+        self.metadata().get(id)
+    }
+
+    /// Get a copy of the metadata reported for a url
+    pub fn get_metadata_for_url(&self, url: &str) -> Option<SongMetadata> {
+        self.metadata().get_for_url(url)
+    }
+
+    /// Ids of the entries where the url or a string tag contains the text (ignoring case)
+    pub fn search_metadata(&self, text: &str) -> Vec<MetadataId> {
+        self.metadata().search(text)
+    }
 
+    pub fn get_metadata_tag(&self, id: MetadataId, tag: &str) -> Option<TagValue> {
+        self.metadata().get_tag(id, tag)
     }
 
 }
@@ -1,27 +1,395 @@
-use crate::ffi_gen::MetadataId;
+use crate::ffi_gen::{MetaEncoding, MetadataId};
+use crate::metadata_db::MetadataDb;
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use vfs::encoding::{self, LegacyEncoding};
+use vfs::FileInfo;
 
+/// Value of a tag reported by a plugin
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TagValue {
+    Str(String),
+    F64(f64),
+}
+
+impl TagValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            TagValue::Str(s) => Some(s),
+            TagValue::F64(_) => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            TagValue::Str(_) => None,
+            TagValue::F64(v) => Some(*v),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Subsong {
+    pub index: u32,
+    pub name: String,
+    /// Length in seconds (0 or negative if unknown)
+    pub length: f32,
+}
+
+/// Converts text reported by a plugin to UTF-8. Invalid sequences are replaced with U+FFFD
+pub fn decode_text(data: &[u8], text_encoding: MetaEncoding) -> String {
+    match text_encoding {
+        MetaEncoding::Utf8 => String::from_utf8_lossy(data).into_owned(),
+        MetaEncoding::ShiftJS2 => encoding::decode(data, LegacyEncoding::ShiftJis),
+        MetaEncoding::Cp437 => encoding::decode(data, LegacyEncoding::Cp437),
+        MetaEncoding::Latin1 => encoding::decode(data, LegacyEncoding::Latin1),
+    }
+}
+
+/// Everything plugins has reported for a url
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SongMetadata {
+    pub url: String,
+    /// MD5 (hex) of the file contents when the metadata was reported. Used to detect changed files
+    pub hash: Option<String>,
+    /// Size and modification time from the directory listing when the file was scanned. Used to skip unchanged
+    /// files without loading them
+    pub file_info: Option<FileInfo>,
+    /// Tags in the order they were first set. Setting a tag again replaces the value
+    pub tags: Vec<(String, TagValue)>,
+    pub subsongs: Vec<Subsong>,
+    pub samples: Vec<String>,
+    pub instruments: Vec<String>,
+}
+
+impl SongMetadata {
+    fn new(url: &str, hash: Option<String>) -> SongMetadata {
+        SongMetadata {
+            url: url.to_owned(),
+            hash,
+            ..Default::default()
+        }
+    }
+
+    pub fn tag(&self, tag: &str) -> Option<&TagValue> {
+        self.tags.iter().find(|(name, _)| name == tag).map(|(_, value)| value)
+    }
+
+    // Returns false if the tag already had the value
+    fn set_tag(&mut self, tag: &str, value: TagValue) -> bool {
+        match self.tags.iter_mut().find(|(name, _)| name == tag) {
+            Some(entry) if entry.1 == value => false,
+            Some(entry) => {
+                entry.1 = value;
+                true
+            }
+            None => {
+                self.tags.push((tag.to_owned(), value));
+                true
+            }
+        }
+    }
+
+    // Adds a subsong or replaces the one with the same index. Returns false if it was already there
+    fn set_subsong(&mut self, subsong: Subsong) -> bool {
+        match self.subsongs.iter_mut().find(|s| s.index == subsong.index) {
+            Some(entry) if *entry == subsong => false,
+            Some(entry) => {
+                *entry = subsong;
+                true
+            }
+            None => {
+                self.subsongs.push(subsong);
+                true
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<MetadataId, SongMetadata>,
+    ids: HashMap<String, MetadataId>,
+    next_id: MetadataId,
+    /// Content hashes of urls read by plugins (from Io)
+    hashes: HashMap<String, String>,
+    db: Option<MetadataDb>,
+    /// Entries changed since the last flush to the database
+    dirty: HashSet<MetadataId>,
+}
+
+impl Store {
+    fn insert(&mut self, entry: SongMetadata) -> MetadataId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(entry.url.clone(), id);
+        self.entries.insert(id, entry);
+        id
+    }
+}
+
+/// Metadata reported by plugins. Plugins may run on several threads at once so the store is behind a lock
 pub struct Metadata {
-    _dummy: u32,
+    store: Mutex<Store>,
 }
 
 impl Metadata {
     pub fn new() -> Metadata {
-        Metadata { _dummy: 0 }
+        Metadata {
+            store: Mutex::new(Store {
+                // 0 is never handed out so plugins can use it as "no id"
+                next_id: 1,
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Store> {
+        self.store.lock().unwrap()
+    }
+
+    // Runs the function on the entry for an id and marks it as dirty if the function returns true (the entry
+    // changed). Unknown ids are logged and ignored
+    fn update<F: FnOnce(&mut SongMetadata) -> bool>(&self, id: MetadataId, func: F) {
+        let mut store = self.lock();
+
+        let changed = match store.entries.get_mut(&id) {
+            Some(entry) => func(entry),
+            None => {
+                warn!("Metadata id {} hasn't been created with create_url", id);
+                return;
+            }
+        };
+
+        if changed {
+            store.dirty.insert(id);
+        }
     }
 
-    pub fn create_url(&mut self, _url: &str) -> MetadataId {
-        0
+    /// Get the id for a url. A url keeps the same id, but the data reported for it earlier is cleared
+    /// as the plugin is about to report it again.
+    pub fn create_url(&self, url: &str) -> MetadataId {
+        let mut store = self.lock();
+        let entry = SongMetadata::new(url, store.hashes.get(url).cloned());
+
+        let id = match store.ids.get(url).copied() {
+            Some(id) => {
+                store.entries.insert(id, entry);
+                id
+            }
+            None => store.insert(entry),
+        };
+
+        store.dirty.insert(id);
+        id
+    }
+
+    /// Records the content hash of a url. Called when a url is read so the metadata reported for it can be
+    /// keyed by content
+    pub fn set_content_hash(&self, url: &str, hash: &str) {
+        let mut store = self.lock();
+
+        if let Some(id) = store.ids.get(url).copied() {
+            let entry = store.entries.get_mut(&id).unwrap();
+
+            if entry.hash.as_deref() != Some(hash) {
+                entry.hash = Some(hash.to_owned());
+                store.dirty.insert(id);
+            }
+        }
+
+        store.hashes.insert(url.to_owned(), hash.to_owned());
+    }
+
+    /// Records the size and modification time of a url that metadata has been reported for
+    pub fn set_file_info(&self, url: &str, info: &FileInfo) {
+        let mut store = self.lock();
+
+        if let Some(id) = store.ids.get(url).copied() {
+            let entry = store.entries.get_mut(&id).unwrap();
+
+            if entry.file_info.as_ref() != Some(info) {
+                entry.file_info = Some(info.clone());
+                store.dirty.insert(id);
+            }
+        }
+    }
+
+    pub fn set_tag(&self, id: MetadataId, tag: &str, data: &str) {
+        self.update(id, |e| e.set_tag(tag, TagValue::Str(data.to_owned())));
+    }
+
+    /// Sets a string tag from text in the encoding the song file uses (such as Shift-JIS for PMD, MDX and S98)
+    pub fn set_tag_encoded(&self, id: MetadataId, tag: &str, data: &[u8], text_encoding: MetaEncoding) {
+        let text = decode_text(data, text_encoding);
+        self.update(id, |e| e.set_tag(tag, TagValue::Str(text)));
+    }
+
+    pub fn set_tag_f64(&self, id: MetadataId, tag: &str, data: f64) {
+        self.update(id, |e| e.set_tag(tag, TagValue::F64(data)));
     }
 
-    pub fn set_tag(&mut self, _id: MetadataId, _tag: &str, _data: &str) {}
+    pub fn add_subsong(&self, parent_id: MetadataId, index: u32, name: &str, length: f32) {
+        self.update(parent_id, |e| {
+            e.set_subsong(Subsong {
+                index,
+                name: name.to_owned(),
+                length,
+            })
+        });
+    }
 
-    pub fn set_tag_f64(&mut self, _id: MetadataId, _tag: &str, _data: f64) {}
+    pub fn add_sample(&self, parent_id: MetadataId, text: &str) {
+        self.update(parent_id, |e| {
+            e.samples.push(text.to_owned());
+            true
+        });
+    }
 
-    pub fn add_subsong(&mut self, _parent_id: MetadataId, _index: u32, _name: &str, _length: f32) {}
+    pub fn add_instrument(&self, parent_id: MetadataId, text: &str) {
+        self.update(parent_id, |e| {
+            e.instruments.push(text.to_owned());
+            true
+        });
+    }
 
-    pub fn add_sample(&mut self, _parent_id: MetadataId, _text: &str) {}
+    /// Get the id of a url that metadata has been reported for
+    pub fn id_for_url(&self, url: &str) -> Option<MetadataId> {
+        self.lock().ids.get(url).copied()
+    }
 
-    pub fn add_instrument(&mut self, _parent_id: MetadataId, _text: &str) {}
+    /// Get a copy of the metadata for an id
+    pub fn get(&self, id: MetadataId) -> Option<SongMetadata> {
+        self.lock().entries.get(&id).cloned()
+    }
+
+    /// Get a copy of the metadata for a url
+    pub fn get_for_url(&self, url: &str) -> Option<SongMetadata> {
+        let store = self.lock();
+        store.ids.get(url).and_then(|id| store.entries.get(id)).cloned()
+    }
+
+    pub fn get_tag(&self, id: MetadataId, tag: &str) -> Option<TagValue> {
+        self.lock().entries.get(&id).and_then(|e| e.tag(tag)).cloned()
+    }
+
+    /// Calls the function for each entry (in no particular order). The store is locked during the call so
+    /// plugins reporting metadata will wait until it's done
+    pub fn for_each<F: FnMut(MetadataId, &SongMetadata)>(&self, mut func: F) {
+        for (id, entry) in &self.lock().entries {
+            func(*id, entry);
+        }
+    }
+
+    /// Ids of all entries (in no particular order)
+    pub fn ids(&self) -> Vec<MetadataId> {
+        self.lock().entries.keys().copied().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Ids of the entries where the url or a string tag contains the text (ignoring case), sorted by id
+    pub fn search(&self, text: &str) -> Vec<MetadataId> {
+        let text = text.to_lowercase();
+        let store = self.lock();
+
+        let mut ids: Vec<MetadataId> = store
+            .entries
+            .iter()
+            .filter(|(_, e)| {
+                e.url.to_lowercase().contains(&text)
+                    || e.tags.iter().any(|(_, v)| v.as_str().is_some_and(|s| s.to_lowercase().contains(&text)))
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Returns true if metadata has been stored for the url with the given content hash. Files that are
+    /// known can be skipped when scanning
+    pub fn is_known(&self, url: &str, hash: &str) -> bool {
+        self.get_for_url(url).is_some_and(|e| e.hash.as_deref() == Some(hash))
+    }
+
+    /// Returns true if metadata has been stored for the url when it had the given size and modification time
+    pub fn has_file_info(&self, url: &str, info: &FileInfo) -> bool {
+        self.get_for_url(url).is_some_and(|e| e.file_info.as_ref() == Some(info))
+    }
+
+    /// Opens (or creates) the database at path and loads the entries in it. Changes are appended to the
+    /// database on [`Metadata::flush`]
+    pub fn open_db(&self, path: &Path) -> Result<()> {
+        let (db, records) = MetadataDb::open(path)?;
+        let mut store = self.lock();
+
+        for record in records {
+            match store.ids.get(&record.url).copied() {
+                Some(id) => {
+                    store.entries.insert(id, record);
+                }
+                None => {
+                    store.insert(record);
+                }
+            }
+        }
+
+        store.db = Some(db);
+        Ok(())
+    }
+
+    /// Writes entries changed since the last flush to the database (if one has been opened)
+    pub fn flush(&self) -> Result<()> {
+        let mut store = self.lock();
+        let store = &mut *store;
+
+        let Some(db) = store.db.as_mut() else {
+            return Ok(());
+        };
+
+        if store.dirty.is_empty() {
+            return Ok(());
+        }
+
+        for id in store.dirty.drain() {
+            if let Some(entry) = store.entries.get(&id) {
+                db.append(entry)?;
+            }
+        }
+
+        db.flush()
+    }
+
+    /// Rewrites the database with only the current entries (dropping replaced records)
+    pub fn compact(&self) -> Result<()> {
+        self.flush()?;
+
+        let mut store = self.lock();
+        let store = &mut *store;
+
+        if let Some(db) = store.db.as_mut() {
+            let mut ids: Vec<MetadataId> = store.entries.keys().copied().collect();
+            ids.sort_unstable();
+
+            let records: Vec<SongMetadata> = ids.iter().map(|id| store.entries[id].clone()).collect();
+            db.compact(&records)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Default for Metadata {
@@ -29,3 +397,168 @@ impl Default for Metadata {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi_gen::MetadataFFI;
+    use std::ffi::CString;
+
+    // Encodes the text, sends it through the C api and checks that the same text comes back
+    fn round_trip(text: &str, legacy: LegacyEncoding, text_encoding: MetaEncoding) {
+        let metadata = Metadata::new();
+        let ffi = MetadataFFI::new(&metadata);
+        let id = metadata.create_url("test.mod");
+
+        let tag = CString::new("title").unwrap();
+        let data = CString::new(encoding::encode(text, legacy).unwrap()).unwrap();
+
+        unsafe { (ffi.set_tag_encoded)(ffi.private_data, id, tag.as_ptr(), data.as_ptr(), text_encoding) };
+
+        assert_eq!(metadata.get_tag(id, "title"), Some(TagValue::Str(text.to_owned())));
+    }
+
+    #[test]
+    fn set_tag_shift_jis() {
+        round_trip("東方封魔録　～ Story of Eastern Wonderland", LegacyEncoding::ShiftJis, MetaEncoding::ShiftJS2);
+        round_trip("ｿｰｻﾘｱﾝ (half width kana)", LegacyEncoding::ShiftJis, MetaEncoding::ShiftJS2);
+    }
+
+    #[test]
+    fn set_tag_cp437() {
+        round_trip("Ñandú ░▒▓ ½ ß", LegacyEncoding::Cp437, MetaEncoding::Cp437);
+    }
+
+    #[test]
+    fn set_tag_latin1() {
+        round_trip("Jörg Kärcher © 1991", LegacyEncoding::Latin1, MetaEncoding::Latin1);
+    }
+
+    #[test]
+    fn set_tag_utf8() {
+        let metadata = Metadata::new();
+        let id = metadata.create_url("test.mod");
+
+        metadata.set_tag_encoded(id, "title", "ÅÄÖ 東方".as_bytes(), MetaEncoding::Utf8);
+        metadata.set_tag_encoded(id, "artist", b"bad \xff utf-8", MetaEncoding::Utf8);
+
+        assert_eq!(metadata.get_tag(id, "title"), Some(TagValue::Str("ÅÄÖ 東方".into())));
+        assert_eq!(metadata.get_tag(id, "artist"), Some(TagValue::Str("bad \u{fffd} utf-8".into())));
+    }
+
+    #[test]
+    fn unchanged_values_are_not_dirty() {
+        let metadata = Metadata::new();
+        let id = metadata.create_url("a.mod");
+        metadata.set_tag(id, "title", "intro");
+        metadata.set_tag_f64(id, "length", 10.0);
+        metadata.add_subsong(id, 0, "intro", 10.0);
+        metadata.lock().dirty.clear();
+
+        metadata.set_tag(id, "title", "intro");
+        metadata.set_tag_f64(id, "length", 10.0);
+        metadata.add_subsong(id, 0, "intro", 10.0);
+        assert!(metadata.lock().dirty.is_empty());
+        assert_eq!(metadata.get(id).unwrap().subsongs.len(), 1);
+
+        metadata.set_tag_f64(id, "length", 11.0);
+        assert!(metadata.lock().dirty.contains(&id));
+        metadata.lock().dirty.clear();
+
+        // A subsong with the same index replaces the old one
+        metadata.add_subsong(id, 0, "theme", 10.0);
+        assert!(metadata.lock().dirty.contains(&id));
+        assert_eq!(metadata.get(id).unwrap().subsongs, [Subsong { index: 0, name: "theme".into(), length: 10.0 }]);
+    }
+
+    #[test]
+    fn create_url_reuses_id() {
+        let metadata = Metadata::new();
+        let id = metadata.create_url("a.mod");
+
+        // 0 is reserved for "no id"
+        assert_ne!(id, 0);
+
+        metadata.set_tag(id, "title", "first");
+        metadata.add_subsong(id, 0, "intro", 10.0);
+
+        // Reporting again keeps the id but clears the old data
+        assert_eq!(metadata.create_url("a.mod"), id);
+        assert_eq!(metadata.get(id).unwrap(), SongMetadata::new("a.mod", None));
+
+        let other = metadata.create_url("b.mod");
+        assert_ne!(other, id);
+        assert_eq!(metadata.id_for_url("b.mod"), Some(other));
+        assert_eq!(metadata.id_for_url("c.mod"), None);
+        assert_eq!(metadata.len(), 2);
+    }
+
+    #[test]
+    fn set_tag_replaces_value() {
+        let metadata = Metadata::new();
+        let id = metadata.create_url("a.mod");
+
+        metadata.set_tag(id, "title", "first");
+        metadata.set_tag(id, "artist", "someone");
+        metadata.set_tag(id, "title", "second");
+        metadata.set_tag_f64(id, "length", 60.0);
+        metadata.set_tag_f64(id, "length", 90.5);
+
+        let entry = metadata.get(id).unwrap();
+
+        // Replaced tags keep the position they were first set at
+        assert_eq!(
+            entry.tags,
+            vec![
+                ("title".to_owned(), TagValue::Str("second".into())),
+                ("artist".to_owned(), TagValue::Str("someone".into())),
+                ("length".to_owned(), TagValue::F64(90.5)),
+            ]
+        );
+
+        // Unknown ids are ignored
+        metadata.set_tag(1234, "title", "lost");
+        assert_eq!(metadata.get(1234), None);
+    }
+
+    #[test]
+    fn search() {
+        let metadata = Metadata::new();
+        let a = metadata.create_url("mods/Space Debris.mod");
+        let b = metadata.create_url("sid/Commando.sid");
+        let c = metadata.create_url("sid/Delta.sid");
+
+        metadata.set_tag(b, "artist", "Rob Hubbard");
+        metadata.set_tag(c, "artist", "Rob Hubbard");
+        metadata.set_tag_f64(a, "length", 235.0);
+
+        assert_eq!(metadata.search("space"), vec![a]);
+        assert_eq!(metadata.search("HUBBARD"), vec![b, c]);
+        assert_eq!(metadata.search(".sid"), vec![b, c]);
+        // Only string tags are searched
+        assert!(metadata.search("235").is_empty());
+    }
+
+    #[test]
+    fn plugin_service_queries() {
+        let service = crate::PluginService::new("metadata_test", vfs::Vfs::new());
+        let metadata = service.metadata();
+
+        let id = metadata.create_url("mods/Space Debris.mod");
+        metadata.set_tag(id, "artist", "Captain");
+        metadata.set_tag_f64(id, "length", 235.0);
+
+        assert_eq!(service.metadata_id("mods/Space Debris.mod"), Some(id));
+        assert_eq!(service.metadata_id("missing.mod"), None);
+        assert_eq!(service.get_metadata(id).unwrap().url, "mods/Space Debris.mod");
+        assert_eq!(service.get_metadata_for_url("mods/Space Debris.mod"), service.get_metadata(id));
+        assert_eq!(service.get_metadata_for_url("missing.mod"), None);
+        assert_eq!(service.search_metadata("captain"), vec![id]);
+        assert_eq!(service.get_metadata_tag(id, "length"), Some(TagValue::F64(235.0)));
+        assert_eq!(service.get_metadata_tag(id, "title"), None);
+
+        // Clones share the store
+        let clone = crate::PluginService::clone_with_log_name(&service, "clone");
+        assert_eq!(clone.metadata_id("mods/Space Debris.mod"), Some(id));
+    }
+}
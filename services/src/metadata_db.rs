@@ -0,0 +1,385 @@
+//! On disk storage of the metadata. The file is JSON lines: a header with the schema version followed by one
+//! record per line. Updates are appended so saving after each scanned file is cheap and a crash only loses the
+//! last (partial) line. Records are keyed by url; a later record for a url replaces the earlier one, and compaction
+//! rewrites the file with only the latest records. The content hash stored in each record is only used to detect
+//! files that have changed since they were scanned.
+
+use crate::metadata::SongMetadata;
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Current version of the record format
+pub const DB_VERSION: u32 = 1;
+
+/// Upgrades a record one version. Entry 0 upgrades version 1 to 2 and so on, so a new schema version is added
+/// by bumping `DB_VERSION` and appending the upgrade here.
+const MIGRATIONS: &[Migration] = &[];
+
+type Migration = fn(&mut serde_json::Value);
+
+// Compact when opening if more than this fraction of the records has been replaced
+const COMPACT_RATIO: f32 = 0.5;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+}
+
+pub struct MetadataDb {
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// Number of records in the file (including replaced ones)
+    record_count: usize,
+}
+
+// Upgrades a record from an older version to the current one
+fn migrate(version: u32, value: serde_json::Value) -> serde_json::Value {
+    migrate_with(MIGRATIONS, version, value)
+}
+
+fn migrate_with(migrations: &[Migration], version: u32, mut value: serde_json::Value) -> serde_json::Value {
+    for upgrade in &migrations[(version as usize - 1).min(migrations.len())..] {
+        upgrade(&mut value);
+    }
+
+    value
+}
+
+fn write_header(file: &mut impl Write) -> Result<()> {
+    serde_json::to_writer(&mut *file, &Header { version: DB_VERSION })?;
+    writeln!(file)?;
+    Ok(())
+}
+
+// Opens the file for appending. If the last line was cut short (by a crash while writing) a line break is added
+// first so the next record doesn't end up on the same line as the broken one and gets skipped with it
+fn open_append(path: &Path) -> Result<BufWriter<File>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Unable to open metadata database {:?} for writing", path))?;
+
+    if file.metadata()?.len() > 0 {
+        let mut last = [0u8];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+
+        if last[0] != b'\n' {
+            warn!("{:?} ends with a partial record", path);
+            writeln!(file)?;
+        }
+    }
+
+    Ok(BufWriter::new(file))
+}
+
+// Reads all records from the file. Returns the version of the file and the latest record for each url
+// in the order they were first added
+fn read_records(path: &Path) -> Result<(u32, Vec<SongMetadata>, usize)> {
+    let file = File::open(path).with_context(|| format!("Unable to open metadata database {:?}", path))?;
+    let mut lines = BufReader::new(file).lines();
+
+    let header: Header = match lines.next() {
+        Some(line) => serde_json::from_str(&line?).with_context(|| format!("Invalid header in {:?}", path))?,
+        None => return Ok((DB_VERSION, Vec::new(), 0)),
+    };
+
+    if header.version == 0 || header.version > DB_VERSION {
+        bail!(
+            "{:?} has version {} and this version only supports up to {}",
+            path,
+            header.version,
+            DB_VERSION
+        );
+    }
+
+    let mut records: Vec<SongMetadata> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut record_count = 0;
+
+    for (line_number, line) in lines.enumerate() {
+        let line = line?;
+
+        let record = serde_json::from_str(&line)
+            .map(|value| migrate(header.version, value))
+            .and_then(serde_json::from_value::<SongMetadata>);
+
+        // A broken line is most likely the last write before a crash. Skip it and keep the rest
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("{:?}:{} skipping broken record: {}", path, line_number + 2, e);
+                continue;
+            }
+        };
+
+        record_count += 1;
+
+        match index.get(&record.url) {
+            Some(i) => records[*i] = record,
+            None => {
+                index.insert(record.url.clone(), records.len());
+                records.push(record);
+            }
+        }
+    }
+
+    Ok((header.version, records, record_count))
+}
+
+impl MetadataDb {
+    /// Opens the database at path (it's created if it doesn't exist) and returns it with all records in it.
+    /// Files from older versions are migrated and rewritten in the current version.
+    pub fn open(path: &Path) -> Result<(MetadataDb, Vec<SongMetadata>)> {
+        if !path.exists() {
+            let mut file = File::create(path).with_context(|| format!("Unable to create {:?}", path))?;
+            write_header(&mut file)?;
+        }
+
+        let (version, records, record_count) = read_records(path)?;
+
+        let mut db = MetadataDb {
+            path: path.to_owned(),
+            file: open_append(path)?,
+            record_count,
+        };
+
+        if version != DB_VERSION {
+            info!("Migrating {:?} from version {} to {}", path, version, DB_VERSION);
+            db.compact(&records)?;
+        } else if record_count > 0 && (record_count - records.len()) as f32 / record_count as f32 > COMPACT_RATIO {
+            db.compact(&records)?;
+        }
+
+        Ok((db, records))
+    }
+
+    /// Appends a record. It replaces any earlier record for the same url
+    pub fn append(&mut self, record: &SongMetadata) -> Result<()> {
+        serde_json::to_writer(&mut self.file, record)?;
+        writeln!(self.file)?;
+        self.record_count += 1;
+        Ok(())
+    }
+
+    /// Writes appended records to disk
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Rewrites the file with only the given records. The new file is written next to the old one and
+    /// then renamed over it so the database is intact if this fails half way.
+    pub fn compact(&mut self, records: &[SongMetadata]) -> Result<()> {
+        self.flush()?;
+
+        let temp_path = self.path.with_extension("compact");
+
+        {
+            let mut file = BufWriter::new(File::create(&temp_path)?);
+            write_header(&mut file)?;
+
+            for record in records {
+                serde_json::to_writer(&mut file, record)?;
+                writeln!(file)?;
+            }
+
+            file.flush()?;
+        }
+
+        std::fs::rename(&temp_path, &self.path)
+            .with_context(|| format!("Unable to replace {:?} with compacted database", self.path))?;
+
+        info!("Compacted {:?} from {} to {} records", self.path, self.record_count, records.len());
+
+        self.file = open_append(&self.path)?;
+        self.record_count = records.len();
+
+        Ok(())
+    }
+}
+
+impl Drop for MetadataDb {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Unable to write metadata to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::TagValue;
+    use serde_json::json;
+
+    fn temp_db(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("metadata_db_{}_{}.jsonl", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn record(url: &str, title: &str) -> SongMetadata {
+        SongMetadata {
+            url: url.to_owned(),
+            hash: Some("8b04d5e3775d298e78455efc5ca404d5".to_owned()),
+            tags: vec![("title".to_owned(), TagValue::Str(title.to_owned()))],
+            ..Default::default()
+        }
+    }
+
+    fn read_lines(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path).unwrap().lines().map(|l| l.to_owned()).collect()
+    }
+
+    // Writes a file with the header for the current version followed by the records
+    fn write_db(path: &Path, records: &[SongMetadata]) {
+        let mut data = format!("{{\"version\":{}}}\n", DB_VERSION);
+
+        for r in records {
+            data += &serde_json::to_string(r).unwrap();
+            data.push('\n');
+        }
+
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn create_and_reopen() {
+        let path = temp_db("create_and_reopen");
+
+        {
+            let (mut db, records) = MetadataDb::open(&path).unwrap();
+            assert!(records.is_empty());
+            assert_eq!(read_lines(&path), vec![format!("{{\"version\":{}}}", DB_VERSION)]);
+
+            db.append(&record("a.mod", "first")).unwrap();
+            db.append(&record("b.mod", "other")).unwrap();
+            db.append(&record("a.mod", "second")).unwrap();
+        }
+
+        // The latest record for a url wins but keeps the position of the first one
+        let (_db, records) = MetadataDb::open(&path).unwrap();
+        assert_eq!(records, vec![record("a.mod", "second"), record("b.mod", "other")]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let path = temp_db("unsupported_version");
+
+        for version in [0, DB_VERSION + 1] {
+            std::fs::write(&path, format!("{{\"version\":{}}}\n", version)).unwrap();
+            assert!(MetadataDb::open(&path).is_err());
+        }
+
+        std::fs::write(&path, "not a header\n").unwrap();
+        assert!(MetadataDb::open(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn migrations() {
+        fn add_hash(value: &mut serde_json::Value) {
+            value["hash"] = json!("v2");
+        }
+
+        fn rename_title(value: &mut serde_json::Value) {
+            value["tags"] = json!([["title", value["name"].take()]]);
+        }
+
+        let migrations: &[Migration] = &[add_hash, rename_title];
+        let old = json!({ "url": "a.mod", "name": "song" });
+
+        // Only the upgrades from the version of the file and up are run
+        assert_eq!(
+            migrate_with(migrations, 1, old.clone()),
+            json!({ "url": "a.mod", "name": null, "hash": "v2", "tags": [["title", "song"]] })
+        );
+        assert_eq!(
+            migrate_with(migrations, 2, old.clone()),
+            json!({ "url": "a.mod", "name": null, "tags": [["title", "song"]] })
+        );
+        assert_eq!(migrate_with(migrations, 3, old.clone()), old);
+    }
+
+    #[test]
+    fn broken_tail() {
+        let path = temp_db("broken_tail");
+        write_db(&path, &[record("a.mod", "first")]);
+
+        // A crash while writing leaves a partial record without a line break
+        let mut data = std::fs::read_to_string(&path).unwrap();
+        data += "{\"url\":\"b.mod\",\"tags\":[[\"ti";
+        std::fs::write(&path, data).unwrap();
+
+        {
+            let (mut db, records) = MetadataDb::open(&path).unwrap();
+            assert_eq!(records, vec![record("a.mod", "first")]);
+            db.append(&record("c.mod", "third")).unwrap();
+        }
+
+        let (_db, records) = MetadataDb::open(&path).unwrap();
+        assert_eq!(records, vec![record("a.mod", "first"), record("c.mod", "third")]);
+        assert_eq!(read_lines(&path).len(), 4);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_on_open() {
+        let path = temp_db("compact_on_open");
+
+        // 1 of 3 records replaced is below the threshold so the file is kept as is
+        write_db(&path, &[record("a.mod", "1"), record("b.mod", "1"), record("a.mod", "2")]);
+        let (db, records) = MetadataDb::open(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(read_lines(&path).len(), 4);
+        drop(db);
+
+        // 2 of 3 is above it
+        write_db(&path, &[record("a.mod", "1"), record("a.mod", "2"), record("a.mod", "3")]);
+        let (db, records) = MetadataDb::open(&path).unwrap();
+        assert_eq!(records, vec![record("a.mod", "3")]);
+        assert_eq!(read_lines(&path).len(), 2);
+        assert_eq!(db.record_count, 1);
+        drop(db);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact() {
+        let path = temp_db("compact");
+
+        {
+            let (mut db, _) = MetadataDb::open(&path).unwrap();
+            db.append(&record("a.mod", "1")).unwrap();
+            db.append(&record("a.mod", "2")).unwrap();
+
+            db.compact(&[record("a.mod", "2")]).unwrap();
+            assert_eq!(db.record_count, 1);
+            assert!(!path.with_extension("compact").exists());
+
+            // Appending still works after the file has been replaced
+            db.append(&record("b.mod", "1")).unwrap();
+        }
+
+        let lines = read_lines(&path);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], serde_json::to_string(&record("a.mod", "2")).unwrap());
+
+        let (_db, records) = MetadataDb::open(&path).unwrap();
+        assert_eq!(records, vec![record("a.mod", "2"), record("b.mod", "1")]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,16 +1,18 @@
 use crate::ffi_gen::*;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    ffi::CStr,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    ffi::{CStr, CString},
     fs::File,
     io::{Read, Write},
     mem,
     os::raw::c_char,
-    path::Path,
-    ptr, slice,
+    path::{Path, PathBuf},
+    ptr,
+    sync::{Mutex, MutexGuard},
 };
 use toml;
 
@@ -33,6 +35,12 @@ struct SerSetting {
 struct SerPluginTypeSettings {
     plugin_name: String,
     settings: Vec<SerSetting>,
+    /// Overrides per file extension
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    extensions: BTreeMap<String, Vec<SerSetting>>,
+    /// Overrides per song (url or content hash)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    songs: BTreeMap<String, Vec<SerSetting>>,
 }
 
 impl SerSetting {
@@ -44,22 +52,358 @@ impl SerSetting {
     }
 }
 
-pub struct Settings {
+/// Where a value applies. Per song values take precedence over per extension values which take precedence
+/// over the global value
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SettingsLayer {
+    Global,
+    /// File extension (the dot and case are ignored)
+    Extension(String),
+    /// Url or content hash (MD5 as hex, case is ignored) of a song
+    Song(String),
+}
+
+impl SettingsLayer {
+    fn normalized(&self) -> SettingsLayer {
+        match self {
+            SettingsLayer::Extension(ext) => SettingsLayer::Extension(normalize_ext(ext)),
+            SettingsLayer::Song(song) => SettingsLayer::Song(normalize_song(song)),
+            layer => layer.clone(),
+        }
+    }
+}
+
+/// Value of a setting
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum SettingValue {
+    Int(i32),
+    Float(f32),
+    Bool(bool),
+    /// Value of one of the choices of a string setting
+    Str(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SettingType {
+    Int,
+    Float,
+    Bool,
+    /// One of a fixed set of integers
+    IntChoice,
+    /// One of a fixed set of strings
+    StringChoice,
+    /// Free text
+    String,
+    /// Path to a file or directory
+    Path,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SettingChoice {
+    pub name: String,
+    pub value: SettingValue,
+}
+
+/// Description of a setting that a frontend can build its settings ui from
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SettingSchema {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    #[serde(rename = "type")]
+    pub setting_type: SettingType,
+    /// Range for int and float settings that has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<SettingValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<SettingValue>,
+    /// Values to select from for choice settings
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub choices: Vec<SettingChoice>,
+    /// Value the plugin registered
+    pub default: SettingValue,
+    /// Global value
+    pub value: SettingValue,
+}
+
+/// A value in a settings file that couldn't be used as is. Values out of range are clamped, other invalid
+/// values are skipped
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidSetting {
+    pub reg_id: String,
+    pub layer: SettingsLayer,
+    pub id: String,
+    /// What was wrong and what was done with the value
+    pub reason: String,
+}
+
+/// Settings registered under an id (usually by a plugin)
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SettingsSchema {
+    pub reg_id: String,
+    pub settings: Vec<SettingSchema>,
+}
+
+thread_local! {
+    // Url and content hash of the song getters on this thread apply the per song overrides of
+    static CURRENT_SONG: RefCell<Option<(String, Option<String>)>> = const { RefCell::new(None) };
+}
+
+/// Runs func with the overrides for the song (and its extension) applied to the settings getters called on this
+/// thread. Used around calls such as `open` where plugins read their settings
+pub fn with_song<R, F: FnOnce() -> R>(url: &str, hash: Option<&str>, func: F) -> R {
+    let song = Some((url.to_owned(), hash.map(|h| h.to_lowercase())));
+    let prev = CURRENT_SONG.with(|s| s.replace(song));
+    let res = func();
+    CURRENT_SONG.with(|s| *s.borrow_mut() = prev);
+    res
+}
+
+fn normalize_ext(ext: &str) -> String {
+    ext.trim_start_matches('.').to_lowercase()
+}
+
+// Content hashes are looked up in lower case (see with_song). Urls are kept as is
+fn normalize_song(song: &str) -> String {
+    if song.len() == 32 && song.chars().all(|c| c.is_ascii_hexdigit()) {
+        song.to_lowercase()
+    } else {
+        song.to_owned()
+    }
+}
+
+#[derive(Default)]
+struct Store {
     settings: HashMap<String, NativeSettings>,
+    /// Registrations with values changed since the last take_changed
+    changed: Vec<String>,
+    /// Directory settings are loaded from when registered and saved to
+    dir: Option<PathBuf>,
+    /// Values in the loaded settings files that couldn't be used as is
+    invalid: Vec<InvalidSetting>,
+}
+
+/// Settings registered by plugins. Plugins may run on several threads at once so the store is behind a lock
+pub struct Settings {
+    store: Mutex<Store>,
 }
 
 pub struct NativeSettings {
-    native_settings: *const Setting,
-    native_settings_count: usize,
+    /// Current global values. These start as copies of the settings the plugin registered (the array owned by
+    /// the plugin is never written to)
+    current: Vec<Setting>,
+    /// Settings as the plugin registered them
     stored_settings: Vec<Setting>,
+    /// Per extension and per song values. These are copies of the registered settings with the value changed
+    overrides: HashMap<SettingsLayer, Vec<Setting>>,
+    /// Values of free text and path settings. Plugins may hold on to the pointers so these are kept for as long
+    /// as the settings are registered
+    strings: Vec<CString>,
+}
+
+// The names, ranges and choices the settings point to are owned by the plugins and stay valid as long as the
+// plugins are loaded
+unsafe impl Send for NativeSettings {}
+
+// A range where start isn't below end means the setting has no range
+fn in_range<T: PartialOrd>(value: T, start: T, end: T) -> bool {
+    start >= end || (start <= value && value <= end)
+}
+
+// Validates the value against the type and range (or choices) of the setting and sets it. Returns true if the
+// value changed
+fn apply_value(
+    s: &mut Setting,
+    value: &SettingValue,
+    strings: &mut Vec<CString>,
+) -> std::result::Result<bool, SettingsResult> {
+    unsafe {
+        match (s.int_value.s_base.widget_type, value) {
+            (RVS_INTEGER_TYPE, SettingValue::Int(value)) => {
+                let v = &mut s.int_value;
+
+                if !in_range(*value, v.start_range, v.end_range) {
+                    return Err(SettingsResult::InvalidValue);
+                }
+
+                Ok(mem::replace(&mut v.value, *value) != *value)
+            }
+            (RVS_INTEGER_RANGE_TYPE, SettingValue::Int(value)) => {
+                let v = &mut s.int_fixed_value;
+
+                if !v.get_values().iter().any(|c| c.value == *value) {
+                    return Err(SettingsResult::InvalidValue);
+                }
+
+                Ok(mem::replace(&mut v.value, *value) != *value)
+            }
+            (RVS_FLOAT_TYPE, SettingValue::Float(value)) => {
+                let v = &mut s.float_value;
+
+                if value.is_nan() || !in_range(*value, v.start_range, v.end_range) {
+                    return Err(SettingsResult::InvalidValue);
+                }
+
+                Ok(mem::replace(&mut v.value, *value) != *value)
+            }
+            (RVS_BOOL_TYPE, SettingValue::Bool(value)) => {
+                Ok(mem::replace(&mut s.bool_value.value, *value) != *value)
+            }
+            (RVS_STRING_RANGE_TYPE, SettingValue::Str(value)) => {
+                // Points to the choice so the value stays valid for as long as the plugin is loaded
+                let choice = NativeSettings::get_string_range_value(s, value);
+
+                if choice.is_null() {
+                    return Err(SettingsResult::InvalidValue);
+                }
+
+                Ok(mem::replace(&mut s.string_fixed_value.value, choice) != choice)
+            }
+            (RVS_STRING_TYPE | RVS_PATH_TYPE, SettingValue::Str(value)) => {
+                let v = &mut s.string_value;
+
+                if !v.value.is_null() && v.get_value() == value.as_str() {
+                    return Ok(false);
+                }
+
+                v.value = keep_string(strings, value).ok_or(SettingsResult::InvalidValue)?;
+                Ok(true)
+            }
+            _ => Err(SettingsResult::WrongType),
+        }
+    }
+}
+
+// Returns a pointer to a copy of the string that stays valid as long as strings is kept. None if the string
+// has nul bytes
+fn keep_string(strings: &mut Vec<CString>, value: &str) -> Option<*const c_char> {
+    if let Some(s) = strings.iter().find(|s| s.as_bytes() == value.as_bytes()) {
+        return Some(s.as_ptr());
+    }
+
+    let s = CString::new(value).ok()?;
+    // The heap buffer of the CString doesn't move when the Vec grows
+    let ptr = s.as_ptr();
+    strings.push(s);
+    Some(ptr)
+}
+
+// Value of a setting or None if the type is unknown
+fn setting_value(s: &Setting) -> Option<SettingValue> {
+    unsafe {
+        match s.int_value.s_base.widget_type {
+            RVS_INTEGER_TYPE => Some(SettingValue::Int(s.int_value.value)),
+            RVS_INTEGER_RANGE_TYPE => Some(SettingValue::Int(s.int_fixed_value.value)),
+            RVS_FLOAT_TYPE => Some(SettingValue::Float(s.float_value.value)),
+            RVS_BOOL_TYPE => Some(SettingValue::Bool(s.bool_value.value)),
+            RVS_STRING_RANGE_TYPE => Some(SettingValue::Str(s.string_fixed_value.get_value().into_owned())),
+            // Plugins may register free text settings without a default
+            RVS_STRING_TYPE | RVS_PATH_TYPE if s.string_value.value.is_null() => Some(SettingValue::Str(String::new())),
+            RVS_STRING_TYPE | RVS_PATH_TYPE => Some(SettingValue::Str(s.string_value.get_value().into_owned())),
+            _ => None,
+        }
+    }
+}
+
+// Value within the range of the setting for values outside of it. None if the value can't be clamped
+fn clamp_value(s: &Setting, value: &SettingValue) -> Option<SettingValue> {
+    unsafe {
+        match (s.int_value.s_base.widget_type, value) {
+            (RVS_INTEGER_TYPE, SettingValue::Int(v)) if s.int_value.start_range < s.int_value.end_range => {
+                Some(SettingValue::Int((*v).clamp(s.int_value.start_range, s.int_value.end_range)))
+            }
+            (RVS_FLOAT_TYPE, SettingValue::Float(v)) if !v.is_nan() && s.float_value.start_range < s.float_value.end_range => {
+                Some(SettingValue::Float(v.clamp(s.float_value.start_range, s.float_value.end_range)))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Min and max for settings with a range (see in_range)
+fn range<T: PartialOrd, F: Fn(T) -> SettingValue>(start: T, end: T, value: F) -> (Option<SettingValue>, Option<SettingValue>) {
+    if start < end {
+        (Some(value(start)), Some(value(end)))
+    } else {
+        (None, None)
+    }
+}
+
+fn setting_schema(current: &Setting, default: &Setting) -> Option<SettingSchema> {
+    let base = unsafe { &current.int_value.s_base };
+    let mut choices = Vec::new();
+
+    let (setting_type, (min, max)) = unsafe {
+        match base.widget_type {
+            RVS_INTEGER_TYPE => {
+                let v = &current.int_value;
+                (SettingType::Int, range(v.start_range, v.end_range, SettingValue::Int))
+            }
+            RVS_FLOAT_TYPE => {
+                let v = &current.float_value;
+                (SettingType::Float, range(v.start_range, v.end_range, SettingValue::Float))
+            }
+            RVS_BOOL_TYPE => (SettingType::Bool, (None, None)),
+            RVS_INTEGER_RANGE_TYPE => {
+                for c in current.int_fixed_value.get_values() {
+                    choices.push(SettingChoice { name: c.get_name().into_owned(), value: SettingValue::Int(c.value) });
+                }
+
+                (SettingType::IntChoice, (None, None))
+            }
+            RVS_STRING_RANGE_TYPE => {
+                for c in current.string_fixed_value.get_values() {
+                    let value = SettingValue::Str(c.get_value().into_owned());
+                    choices.push(SettingChoice { name: c.get_name().into_owned(), value });
+                }
+
+                (SettingType::StringChoice, (None, None))
+            }
+            RVS_STRING_TYPE => (SettingType::String, (None, None)),
+            RVS_PATH_TYPE => (SettingType::Path, (None, None)),
+            t => {
+                warn!("Setting {} has unknown type {}", base.get_widget_id(), t);
+                return None;
+            }
+        }
+    };
+
+    Some(SettingSchema {
+        id: base.get_widget_id().into_owned(),
+        name: base.get_name().into_owned(),
+        description: base.get_desc().into_owned(),
+        setting_type,
+        min,
+        max,
+        choices,
+        default: setting_value(default)?,
+        value: setting_value(current)?,
+    })
 }
 
 impl NativeSettings {
     pub fn new(settings: &[Setting]) -> NativeSettings {
         NativeSettings {
-            native_settings: settings.as_ptr(),
-            native_settings_count: settings.len(),
+            current: settings.to_vec(),
             stored_settings: settings.to_vec(),
+            overrides: HashMap::new(),
+            strings: Vec::new(),
+        }
+    }
+
+    fn ser_value(s: &Setting) -> Option<SerValue> {
+        match setting_value(s) {
+            Some(SettingValue::Int(v)) => Some(SerValue::IntValue(v)),
+            Some(SettingValue::Float(v)) => Some(SerValue::FloatValue(v)),
+            Some(SettingValue::Bool(v)) => Some(SerValue::BoolValue(v)),
+            Some(SettingValue::Str(v)) => Some(SerValue::StrValue(v)),
+            None => {
+                let base = unsafe { &s.int_fixed_value.s_base };
+                warn!("Setting id {} unknown {}", base.get_widget_id(), base.widget_type);
+                None
+            }
         }
     }
 
@@ -67,59 +411,61 @@ impl NativeSettings {
         debug!("Serializing settings");
 
         let mut ser_settings = Vec::new();
-        let native_settings =
-            unsafe { slice::from_raw_parts(self.native_settings, self.native_settings_count) };
 
-        let bytes_size = std::mem::size_of::<Setting>();
-
-        for (s, t) in self.stored_settings.iter().zip(native_settings.iter()) {
-            let t0 =
-                unsafe { slice::from_raw_parts(mem::transmute::<_, *const u8>(t), bytes_size) };
-            let t1 =
-                unsafe { slice::from_raw_parts(mem::transmute::<_, *const u8>(s), bytes_size) };
-
-            if t0 != t1 {
+        // s is the current value and t the one the plugin registered. Values are compared rather than the bytes
+        // as free text values point to copies of the text
+        for (s, t) in self.current.iter().zip(self.stored_settings.iter()) {
+            if setting_value(s) != setting_value(t) {
                 let id = unsafe { s.int_value.s_base.get_widget_id() };
                 debug!("field that differs is {}", &id);
 
-                let t = unsafe {
-                    match s.int_value.s_base.widget_type as _ {
-                        RVS_FLOAT_TYPE => {
-                            SerSetting::new(&id, SerValue::FloatValue(s.float_value.value))
-                        }
-                        RVS_INTEGER_TYPE => {
-                            SerSetting::new(&id, SerValue::IntValue(s.int_value.value))
-                        }
-                        RVS_INTEGER_RANGE_TYPE => {
-                            SerSetting::new(&id, SerValue::IntValue(s.int_value.value))
-                        }
-                        RVS_BOOL_TYPE => {
-                            SerSetting::new(&id, SerValue::BoolValue(s.bool_value.value))
-                        }
-                        RVS_STRING_RANGE_TYPE => {
-                            let value = s.string_fixed_value.get_value();
-                            SerSetting::new(&id, SerValue::StrValue(value.to_string()))
-                        }
-                        t => {
-                            warn!(
-                                "Setting id {} unknown {}",
-                                t, s.int_fixed_value.s_base.widget_type
-                            );
-                            SerSetting::new("", SerValue::NoSetting)
-                        }
-                    }
-                };
-
-                ser_settings.push(t);
+                if let Some(value) = Self::ser_value(s) {
+                    ser_settings.push(SerSetting::new(&id, value));
+                }
             }
         }
 
         ser_settings
     }
 
-    fn write_internal(&self, path: &str) -> Result<()> {
-        let ser_data = self.build_ser_settings();
-        let mut file = File::create(&path)?;
+    // Overrides for the extension or song layers keyed by extension or song
+    fn build_ser_overrides(&self, extensions: bool) -> BTreeMap<String, Vec<SerSetting>> {
+        let mut ser_overrides = BTreeMap::new();
+
+        for (layer, settings) in &self.overrides {
+            let key = match layer {
+                SettingsLayer::Extension(ext) if extensions => ext,
+                SettingsLayer::Song(song) if !extensions => song,
+                _ => continue,
+            };
+
+            let values = settings
+                .iter()
+                .filter_map(|s| {
+                    let id = unsafe { s.int_value.s_base.get_widget_id() };
+                    Self::ser_value(s).map(|value| SerSetting::new(&id, value))
+                })
+                .collect();
+
+            ser_overrides.insert(key.to_owned(), values);
+        }
+
+        ser_overrides
+    }
+
+    /// True if any value differs from the ones the plugin registered or there are any overrides
+    fn has_changes(&self) -> bool {
+        !self.overrides.is_empty() || !self.build_ser_settings().is_empty()
+    }
+
+    fn write_internal(&self, plugin_name: &str, path: &str) -> Result<()> {
+        let ser_data = SerPluginTypeSettings {
+            plugin_name: plugin_name.to_owned(),
+            settings: self.build_ser_settings(),
+            extensions: self.build_ser_overrides(true),
+            songs: self.build_ser_overrides(false),
+        };
+        let mut file = File::create(path)?;
 
         let toml = toml::to_string(&ser_data)?;
         file.write_all(toml.as_bytes())?;
@@ -129,7 +475,7 @@ impl NativeSettings {
 
     fn read_to_file(path: &str) -> Result<String> {
         let mut data = String::new();
-        let mut file = File::open(&path)?;
+        let mut file = File::open(path)?;
         file.read_to_string(&mut data)?;
         Ok(data)
     }
@@ -146,6 +492,12 @@ impl NativeSettings {
         None
     }
 
+    fn find_setting<'a>(id: &str, settings: &'a [Setting]) -> Option<&'a Setting> {
+        settings
+            .iter()
+            .find(|s| unsafe { s.int_fixed_value.s_base.get_widget_id() } == id)
+    }
+
     fn get_string_range_value(s: &Setting, name: &str) -> *const c_char {
         let values = unsafe { s.string_fixed_value.get_values() };
 
@@ -161,84 +513,318 @@ impl NativeSettings {
         ptr::null()
     }
 
-    fn patch_data(&mut self, input_data: &[SerSetting]) {
-        let data = unsafe {
-            slice::from_raw_parts_mut(self.native_settings as *mut _, self.native_settings_count)
+    // Validates a loaded value and sets it. Values out of range are clamped. Returns Ok with what was wrong
+    // if the value was clamped and Err if the value couldn't be used
+    fn patch_setting(
+        wd: &mut Setting,
+        value: &SerValue,
+        strings: &mut Vec<CString>,
+    ) -> std::result::Result<Option<String>, String> {
+        let value = match value {
+            SerValue::FloatValue(v) => SettingValue::Float(*v),
+            SerValue::IntValue(v) => SettingValue::Int(*v),
+            SerValue::BoolValue(v) => SettingValue::Bool(*v),
+            SerValue::StrValue(v) => SettingValue::Str(v.to_owned()),
+            SerValue::NoSetting => return Ok(None),
         };
 
+        match apply_value(wd, &value, strings) {
+            Ok(_) => Ok(None),
+            Err(SettingsResult::WrongType) => Err(format!("{:?} doesn't match the type of the setting", value)),
+            Err(_) => match clamp_value(wd, &value) {
+                Some(clamped) => {
+                    let _ = apply_value(wd, &clamped, strings);
+                    Ok(Some(format!("{:?} is out of range, clamped to {:?}", value, clamped)))
+                }
+                None => Err(format!("{:?} isn't a valid value", value)),
+            },
+        }
+    }
+
+    // Applies the values for a layer. Values that couldn't be used as is are added to invalid
+    fn patch_data(&mut self, plugin_name: &str, layer: SettingsLayer, input_data: &[SerSetting], invalid: &mut Vec<InvalidSetting>) {
+        let data = &mut self.current;
+        let mut overrides = Vec::new();
+
         for input in input_data {
-            if let Some(wd) = Self::find_id(data, &input.id) {
-                match input.value {
-                    SerValue::FloatValue(v) => wd.float_value.value = v,
-                    SerValue::IntValue(v) => wd.int_value.value = v,
-                    SerValue::BoolValue(v) => wd.bool_value.value = v,
-                    SerValue::StrValue(ref v) => {
-                        wd.string_fixed_value.value = Self::get_string_range_value(wd, v)
+            let mut report = |reason: String| {
+                warn!("Settings for {}: {} ({:?}): {}", plugin_name, input.id, layer, reason);
+                invalid.push(InvalidSetting {
+                    reg_id: plugin_name.to_owned(),
+                    layer: layer.clone(),
+                    id: input.id.to_owned(),
+                    reason,
+                });
+            };
+
+            let wd = match Self::find_id(data, &input.id) {
+                Some(wd) => wd,
+                None => {
+                    report("id wasn't found in settings, skipping".to_owned());
+                    continue;
+                }
+            };
+
+            // Overrides start from the registered setting so they get its type and range
+            let mut setting = *wd;
+            let target = if layer == SettingsLayer::Global { wd } else { &mut setting };
+
+            match Self::patch_setting(target, &input.value, &mut self.strings) {
+                Ok(reason) => {
+                    if let Some(reason) = reason {
+                        report(reason);
+                    }
+
+                    if layer != SettingsLayer::Global {
+                        overrides.push(setting);
                     }
-                    SerValue::NoSetting => (),
                 }
-            } else {
-                warn!("Id: {} wasn't found in settings", input.id);
+                Err(reason) => report(format!("{}, skipping", reason)),
             }
         }
+
+        if !overrides.is_empty() {
+            self.overrides.insert(layer, overrides);
+        }
     }
 
-    fn load_internal(&mut self, path: &str) -> Result<()> {
+    fn load_internal(&mut self, plugin_name: &str, path: &str) -> Result<Vec<InvalidSetting>> {
+        let mut invalid = Vec::new();
+
         if std::fs::metadata(path).is_err() {
-            return Ok(());
+            return Ok(invalid);
         }
 
-        let data = Self::read_to_file(path).unwrap();
+        let data = Self::read_to_file(path)?;
 
         let s: SerPluginTypeSettings = toml::from_str(&data)?;
-        self.patch_data(&s.settings);
+        self.patch_data(plugin_name, SettingsLayer::Global, &s.settings, &mut invalid);
 
-        Ok(())
+        for (ext, values) in &s.extensions {
+            self.patch_data(plugin_name, SettingsLayer::Extension(normalize_ext(ext)), values, &mut invalid);
+        }
+
+        for (song, values) in &s.songs {
+            self.patch_data(plugin_name, SettingsLayer::Song(normalize_song(song)), values, &mut invalid);
+        }
+
+        Ok(invalid)
     }
 
-    pub fn load(&mut self, path: &Path, filename: &str) -> Result<()> {
+    /// Loads values written by [`NativeSettings::write`]. Values that are out of range are clamped and other
+    /// invalid values are skipped. Both are returned
+    pub fn load(&mut self, plugin_name: &str, path: &Path, filename: &str) -> Result<Vec<InvalidSetting>> {
         let dir = path.join(filename);
-        self.load_internal(&dir.to_string_lossy())?;
-        Ok(())
+        self.load_internal(plugin_name, &dir.to_string_lossy())
     }
 
-    pub fn write(&self, path: &Path, filename: &str) -> Result<()> {
+    /// Writes the values that differs from the ones the plugin registered and the overrides
+    pub fn write(&self, plugin_name: &str, path: &Path, filename: &str) -> Result<()> {
         let dir = path.join(filename);
-        self.write_internal(&dir.to_string_lossy())?;
+        self.write_internal(plugin_name, &dir.to_string_lossy())?;
         Ok(())
     }
+
+    // Sets the value at a layer. Returns true if the value changed
+    fn set(&mut self, layer: &SettingsLayer, id: &str, value: &SettingValue) -> std::result::Result<bool, SettingsResult> {
+        let native = Self::find_id(&mut self.current, id).ok_or(SettingsResult::NotFound)?;
+
+        if *layer == SettingsLayer::Global {
+            return apply_value(native, value, &mut self.strings);
+        }
+
+        // New overrides start from the registered setting so they get its type and range
+        let mut setting = *native;
+        let overrides = self.overrides.entry(layer.clone()).or_default();
+
+        if let Some(s) = Self::find_id(overrides, id) {
+            return apply_value(s, value, &mut self.strings);
+        }
+
+        let res = apply_value(&mut setting, value, &mut self.strings);
+
+        if res.is_ok() {
+            overrides.push(setting);
+        } else if overrides.is_empty() {
+            self.overrides.remove(layer);
+        }
+
+        res.map(|_| true)
+    }
+
+    // Removes the value at a layer. The global value goes back to the one the plugin registered. Returns true if
+    // anything changed
+    fn clear(&mut self, layer: &SettingsLayer, id: &str) -> std::result::Result<bool, SettingsResult> {
+        let index = self
+            .stored_settings
+            .iter()
+            .position(|s| unsafe { s.int_value.s_base.get_widget_id() } == id)
+            .ok_or(SettingsResult::NotFound)?;
+
+        if *layer == SettingsLayer::Global {
+            let default = self.stored_settings[index];
+            let native = &mut self.current[index];
+            let changed = setting_value(native) != setting_value(&default);
+
+            *native = default;
+            return Ok(changed);
+        }
+
+        let Some(overrides) = self.overrides.get_mut(layer) else {
+            return Ok(false);
+        };
+
+        let len = overrides.len();
+        overrides.retain(|s| unsafe { s.int_value.s_base.get_widget_id() } != id);
+        let changed = overrides.len() != len;
+
+        if overrides.is_empty() {
+            self.overrides.remove(layer);
+        }
+
+        Ok(changed)
+    }
+
+    // The setting with the overrides for the extension and the song set by with_song applied
+    fn effective(&self, ext: &str, id: &str) -> Option<&Setting> {
+        let native = Self::find_setting(id, &self.current)?;
+
+        if self.overrides.is_empty() {
+            return Some(native);
+        }
+
+        // Layers in the order they take precedence
+        let layers = CURRENT_SONG.with(|song| {
+            let song = song.borrow();
+            let mut layers = Vec::new();
+            let mut ext = normalize_ext(ext);
+
+            if let Some((url, hash)) = &*song {
+                layers.push(SettingsLayer::Song(url.to_owned()));
+
+                if let Some(hash) = hash {
+                    layers.push(SettingsLayer::Song(hash.to_owned()));
+                }
+
+                if ext.is_empty() {
+                    ext = Path::new(url).extension().map(|e| normalize_ext(&e.to_string_lossy())).unwrap_or_default();
+                }
+            }
+
+            if !ext.is_empty() {
+                layers.push(SettingsLayer::Extension(ext));
+            }
+
+            layers
+        });
+
+        let value = layers
+            .iter()
+            .find_map(|layer| self.overrides.get(layer).and_then(|o| Self::find_setting(id, o)));
+
+        Some(value.unwrap_or(native))
+    }
+}
+
+// Name of the file the settings of a registration are stored in
+fn settings_filename(reg_id: &str) -> String {
+    let name: String = reg_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+        .collect();
+
+    format!("{}.toml", name)
 }
 
 impl Settings {
     pub fn new() -> Settings {
         Settings {
-            settings: HashMap::new(),
+            store: Mutex::new(Store::default()),
         }
     }
 
-    pub fn reg(&mut self, name: &str, settings: &[Setting]) -> SettingsResult {
-        if let Some(_ps) = self.settings.get(name) {
+    fn lock(&self) -> MutexGuard<'_, Store> {
+        self.store.lock().unwrap()
+    }
+
+    /// Sets the directory settings are stored in. Settings registered after this get their stored values
+    pub fn set_dir(&self, dir: &Path) {
+        self.lock().dir = Some(dir.to_owned());
+    }
+
+    pub fn reg(&self, name: &str, settings: &[Setting]) -> SettingsResult {
+        let mut store = self.lock();
+
+        if let Some(_ps) = store.settings.get(name) {
             info!("Trying to register settings for {} twice, skipping", name);
             SettingsResult::DuplicatedId
         } else {
-            self.settings
-                .insert(name.to_owned(), NativeSettings::new(settings));
+            let mut native = NativeSettings::new(settings);
+
+            if let Some(dir) = store.dir.clone() {
+                match native.load(name, &dir, &settings_filename(name)) {
+                    Ok(invalid) => store.invalid.extend(invalid),
+                    Err(e) => warn!("Unable to load settings for {}: {:?}", name, e),
+                }
+            }
+
+            store.settings.insert(name.to_owned(), native);
             SettingsResult::Ok
         }
     }
 
-    fn find_reg_settings(&self, reg_id: &str) -> Option<&[Setting]> {
-        if let Some(s) = self.settings.get(reg_id) {
-            let settings =
-                unsafe { slice::from_raw_parts(s.native_settings, s.native_settings_count) };
-            Some(settings)
-        } else {
-            None
+    // Writes the values that differs from the defaults. The file is removed if nothing differs
+    fn save_native(dir: &Path, reg_id: &str, settings: &NativeSettings) -> Result<()> {
+        let filename = settings_filename(reg_id);
+
+        if !settings.has_changes() {
+            let path = dir.join(filename);
+
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+
+            return Ok(());
         }
+
+        std::fs::create_dir_all(dir)?;
+        settings.write(reg_id, dir, &filename)
     }
 
-    pub fn get_string(&mut self, reg_id: &str, _ext: &str, id: &str) -> SStringResult {
-        let s = match self.find_reg_settings(reg_id) {
+    /// Writes the values of a registration that differs from the defaults to the settings directory (if set)
+    pub fn save(&self, reg_id: &str) -> Result<()> {
+        let store = self.lock();
+
+        let dir = match &store.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        match store.settings.get(reg_id) {
+            Some(settings) => Self::save_native(dir, reg_id, settings),
+            None => bail!("Settings for {} hasn't been registered", reg_id),
+        }
+    }
+
+    /// Writes the values of all registrations that differs from the defaults to the settings directory (if set)
+    pub fn save_all(&self) -> Result<()> {
+        let store = self.lock();
+
+        let dir = match &store.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        for (reg_id, settings) in &store.settings {
+            Self::save_native(dir, reg_id, settings)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_string(&self, reg_id: &str, ext: &str, id: &str) -> SStringResult {
+        let store = self.lock();
+        let s = match store.settings.get(reg_id) {
             Some(s) => s,
             None => {
                 return SStringResult {
@@ -248,10 +834,23 @@ impl Settings {
             }
         };
 
-        if let Some(setting) = Self::find_setting(id, s) {
+        if let Some(setting) = s.effective(ext, id) {
+            let value = unsafe {
+                match setting.string_value.s_base.widget_type {
+                    RVS_STRING_RANGE_TYPE => setting.string_fixed_value.value,
+                    RVS_STRING_TYPE | RVS_PATH_TYPE => setting.string_value.value,
+                    _ => {
+                        return SStringResult {
+                            result: SettingsResult::WrongType,
+                            value: ptr::null(),
+                        }
+                    }
+                }
+            };
+
             SStringResult {
                 result: SettingsResult::Ok,
-                value: unsafe { setting.string_fixed_value.value },
+                value,
             }
         } else {
             SStringResult {
@@ -261,8 +860,9 @@ impl Settings {
         }
     }
 
-    pub fn get_int(&mut self, reg_id: &str, _ext: &str, id: &str) -> SIntResult {
-        let s = match self.find_reg_settings(reg_id) {
+    pub fn get_int(&self, reg_id: &str, ext: &str, id: &str) -> SIntResult {
+        let store = self.lock();
+        let s = match store.settings.get(reg_id) {
             Some(s) => s,
             None => {
                 return SIntResult {
@@ -272,21 +872,21 @@ impl Settings {
             }
         };
 
-        if let Some(setting) = Self::find_setting(id, s) {
-            let int_value = unsafe { &setting.int_value };
-
-            if int_value.s_base.widget_type != RVS_INTEGER_TYPE && 
-               int_value.s_base.widget_type != RVS_INTEGER_RANGE_TYPE {
-                SIntResult {
+        if let Some(setting) = s.effective(ext, id) {
+            match unsafe { setting.int_value.s_base.widget_type } {
+                RVS_INTEGER_TYPE => SIntResult {
+                    result: SettingsResult::Ok,
+                    value: unsafe { setting.int_value.value },
+                },
+                RVS_INTEGER_RANGE_TYPE => SIntResult {
+                    result: SettingsResult::Ok,
+                    value: unsafe { setting.int_fixed_value.value },
+                },
+                _ => SIntResult {
                     result: SettingsResult::WrongType,
                     value: 0
-                }
-            } else {
-                SIntResult {
-                    result: SettingsResult::Ok,
-                    value: int_value.value,
-                }
-            } 
+                },
+            }
         } else {
             SIntResult {
                 result: SettingsResult::NotFound,
@@ -295,8 +895,9 @@ impl Settings {
         }
     }
 
-    pub fn get_float(&mut self, reg_id: &str, _ext: &str, id: &str) -> SFloatResult {
-        let s = match self.find_reg_settings(reg_id) {
+    pub fn get_float(&self, reg_id: &str, ext: &str, id: &str) -> SFloatResult {
+        let store = self.lock();
+        let s = match store.settings.get(reg_id) {
             Some(s) => s,
             None => {
                 return SFloatResult {
@@ -306,7 +907,7 @@ impl Settings {
             }
         };
 
-        if let Some(setting) = Self::find_setting(id, s) {
+        if let Some(setting) = s.effective(ext, id) {
             let float_value = unsafe { &setting.float_value };
 
             if float_value.s_base.widget_type != RVS_FLOAT_TYPE {
@@ -328,8 +929,9 @@ impl Settings {
         }
     }
 
-    pub fn get_bool(&mut self, reg_id: &str, _ext: &str, id: &str) -> SBoolResult {
-        let s = match self.find_reg_settings(reg_id) {
+    pub fn get_bool(&self, reg_id: &str, ext: &str, id: &str) -> SBoolResult {
+        let store = self.lock();
+        let s = match store.settings.get(reg_id) {
             Some(s) => s,
             None => {
                 return SBoolResult {
@@ -339,7 +941,7 @@ impl Settings {
             }
         };
 
-        if let Some(setting) = Self::find_setting(id, s) {
+        if let Some(setting) = s.effective(ext, id) {
             let bool_value = unsafe { &setting.bool_value };
 
             if bool_value.s_base.widget_type != RVS_BOOL_TYPE {
@@ -361,14 +963,107 @@ impl Settings {
         }
     }
 
-    fn find_setting<'a>(id: &str, settings: &'a [Setting]) -> Option<&'a Setting> {
-        for s in settings {
-            if unsafe { s.int_fixed_value.s_base.get_widget_id() } == id {
-                return Some(s);
+    // Runs func on the settings of a registration. If it reports a change the registration is returned by
+    // take_changed
+    fn update<F>(&self, reg_id: &str, func: F) -> SettingsResult
+    where
+        F: FnOnce(&mut NativeSettings) -> std::result::Result<bool, SettingsResult>,
+    {
+        let mut store = self.lock();
+
+        let settings = match store.settings.get_mut(reg_id) {
+            Some(s) => s,
+            None => return SettingsResult::UnknownId,
+        };
+
+        match func(settings) {
+            Ok(changed) => {
+                if changed && !store.changed.iter().any(|r| r == reg_id) {
+                    store.changed.push(reg_id.to_owned());
+                }
+
+                SettingsResult::Ok
             }
+            Err(e) => e,
         }
+    }
 
-        None
+    /// Sets the value of a setting at a layer. The value has to match the type of the setting and be within its
+    /// range (or be one of its choices)
+    pub fn set(&self, reg_id: &str, layer: &SettingsLayer, id: &str, value: &SettingValue) -> SettingsResult {
+        let layer = layer.normalized();
+        self.update(reg_id, |s| s.set(&layer, id, value))
+    }
+
+    /// Removes the value of a setting at a layer so the one below is used. Clearing the global value restores the
+    /// one the plugin registered
+    pub fn clear(&self, reg_id: &str, layer: &SettingsLayer, id: &str) -> SettingsResult {
+        let layer = layer.normalized();
+        self.update(reg_id, |s| s.clear(&layer, id))
+    }
+
+    /// Sets an integer setting. The value has to be within the range of the setting (or one of the choices
+    /// for fixed range settings)
+    pub fn set_int(&self, reg_id: &str, id: &str, value: i32) -> SettingsResult {
+        self.set(reg_id, &SettingsLayer::Global, id, &SettingValue::Int(value))
+    }
+
+    /// Sets a float setting. The value has to be within the range of the setting
+    pub fn set_float(&self, reg_id: &str, id: &str, value: f32) -> SettingsResult {
+        self.set(reg_id, &SettingsLayer::Global, id, &SettingValue::Float(value))
+    }
+
+    pub fn set_bool(&self, reg_id: &str, id: &str, value: bool) -> SettingsResult {
+        self.set(reg_id, &SettingsLayer::Global, id, &SettingValue::Bool(value))
+    }
+
+    /// Sets a free text or path setting or selects one of the choices of a string setting by its value
+    pub fn set_string(&self, reg_id: &str, id: &str, value: &str) -> SettingsResult {
+        self.set(reg_id, &SettingsLayer::Global, id, &SettingValue::Str(value.to_owned()))
+    }
+
+    /// Schema for all registered settings (ordered by registration id) with the global values
+    pub fn schema(&self) -> Vec<SettingsSchema> {
+        let store = self.lock();
+
+        let mut schema: Vec<SettingsSchema> = store
+            .settings
+            .iter()
+            .map(|(reg_id, s)| {
+                SettingsSchema {
+                    reg_id: reg_id.to_owned(),
+                    settings: s
+                        .current
+                        .iter()
+                        .zip(s.stored_settings.iter())
+                        .filter_map(|(current, default)| setting_schema(current, default))
+                        .collect(),
+                }
+            })
+            .collect();
+
+        schema.sort_by(|a, b| a.reg_id.cmp(&b.reg_id));
+        schema
+    }
+
+    /// [`Settings::schema`] as JSON
+    pub fn schema_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(&self.schema())?)
+    }
+
+    /// Values in the settings files loaded so far that couldn't be used as is
+    pub fn invalid_entries(&self) -> Vec<InvalidSetting> {
+        self.lock().invalid.clone()
+    }
+
+    /// Ids of all registrations
+    pub fn reg_ids(&self) -> Vec<String> {
+        self.lock().settings.keys().cloned().collect()
+    }
+
+    /// Ids of the registrations that have had values changed by the setters since the last call
+    pub fn take_changed(&self) -> Vec<String> {
+        mem::take(&mut self.lock().changed)
     }
 }
 
@@ -377,3 +1072,457 @@ impl Default for Settings {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c(s: &'static [u8]) -> *const c_char {
+        s.as_ptr() as _
+    }
+
+    fn base(id: &'static [u8], widget_type: u64) -> SBase {
+        SBase {
+            widget_id: c(id),
+            name: c(b"name\0"),
+            desc: c(b"desc\0"),
+            widget_type,
+        }
+    }
+
+    // Settings array the way a plugin registers it. A new array is made for each call (same as a plugin
+    // being loaded again) and it's leaked as plugins keep theirs for as long as they are loaded
+    fn fake_settings() -> &'static [Setting] {
+        let int_values: &'static [SIntegerRangeValue] = Box::leak(Box::new([
+            SIntegerRangeValue { name: c(b"Mono\0"), value: 1 },
+            SIntegerRangeValue { name: c(b"Stereo\0"), value: 2 },
+        ]));
+
+        let string_values: &'static [SStringRangeValue] = Box::leak(Box::new([
+            SStringRangeValue { name: c(b"Fast\0"), value: c(b"fast\0") },
+            SStringRangeValue { name: c(b"Best\0"), value: c(b"best\0") },
+        ]));
+
+        Box::leak(Box::new([
+            Setting {
+                int_value: SInteger {
+                    s_base: base(b"volume\0", RVS_INTEGER_TYPE),
+                    value: 50,
+                    start_range: 0,
+                    end_range: 100,
+                },
+            },
+            Setting {
+                float_value: SFloat {
+                    s_base: base(b"stereo_separation\0", RVS_FLOAT_TYPE),
+                    value: 0.5,
+                    start_range: 0.0,
+                    end_range: 1.0,
+                },
+            },
+            Setting {
+                bool_value: SBool {
+                    s_base: base(b"filter\0", RVS_BOOL_TYPE),
+                    value: false,
+                },
+            },
+            Setting {
+                int_fixed_value: SIntegerFixedRange {
+                    s_base: base(b"channels\0", RVS_INTEGER_RANGE_TYPE),
+                    value: 2,
+                    values: int_values.as_ptr(),
+                    values_size: int_values.len() as _,
+                },
+            },
+            Setting {
+                string_fixed_value: SStringFixedRange {
+                    s_base: base(b"interpolation\0", RVS_STRING_RANGE_TYPE),
+                    value: string_values[0].value,
+                    values: string_values.as_ptr(),
+                    values_size: string_values.len() as _,
+                },
+            },
+            Setting {
+                string_value: SString {
+                    s_base: base(b"title_format\0", RVS_STRING_TYPE),
+                    value: c(b"%artist% - %title%\0"),
+                },
+            },
+            Setting {
+                string_value: SString {
+                    s_base: base(b"rom_path\0", RVS_PATH_TYPE),
+                    value: ptr::null(),
+                },
+            },
+        ]))
+    }
+
+    fn settings_in(dir: &Path) -> Settings {
+        let settings = Settings::new();
+        settings.set_dir(dir);
+        assert_eq!(settings.reg("test", fake_settings()), SettingsResult::Ok);
+        settings
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("settings_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = temp_dir("save_and_load");
+        let settings = settings_in(&dir);
+
+        assert_eq!(settings.set_int("test", "volume", 75), SettingsResult::Ok);
+        assert_eq!(settings.set_float("test", "stereo_separation", 0.25), SettingsResult::Ok);
+        assert_eq!(settings.set_bool("test", "filter", true), SettingsResult::Ok);
+        assert_eq!(settings.set_int("test", "channels", 1), SettingsResult::Ok);
+        assert_eq!(settings.set_string("test", "interpolation", "best"), SettingsResult::Ok);
+        assert_eq!(settings.set_string("test", "title_format", "%title%"), SettingsResult::Ok);
+        assert_eq!(settings.set_string("test", "rom_path", "/roms/kernal.bin"), SettingsResult::Ok);
+        settings.save("test").unwrap();
+
+        let settings = settings_in(&dir);
+
+        assert_eq!(settings.get_int("test", "", "volume").value, 75);
+        assert_eq!(settings.get_float("test", "", "stereo_separation").value, 0.25);
+        assert!(settings.get_bool("test", "", "filter").value);
+        assert_eq!(settings.get_int("test", "", "channels").value, 1);
+        assert_eq!(settings.get_string("test", "", "interpolation").get_value(), "best");
+        assert_eq!(settings.get_string("test", "", "title_format").get_value(), "%title%");
+        assert_eq!(settings.get_string("test", "", "rom_path").get_value(), "/roms/kernal.bin");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn free_text() {
+        let settings = Settings::new();
+        assert_eq!(settings.reg("test", fake_settings()), SettingsResult::Ok);
+
+        // registered without a default
+        assert!(settings.get_string("test", "", "rom_path").value.is_null());
+        assert_eq!(settings.get_string("test", "", "volume").result, SettingsResult::WrongType);
+
+        assert_eq!(settings.set_string("test", "rom_path", "/roms/basic.bin"), SettingsResult::Ok);
+        let first = settings.get_string("test", "", "rom_path").value;
+        assert_eq!(settings.set_string("test", "rom_path", "/roms/kernal.bin"), SettingsResult::Ok);
+
+        // values handed out earlier stay valid
+        assert_eq!(unsafe { CStr::from_ptr(first) }.to_str().unwrap(), "/roms/basic.bin");
+        assert_eq!(settings.get_string("test", "", "rom_path").get_value(), "/roms/kernal.bin");
+        assert_eq!(settings.set_string("test", "rom_path", "nul\0byte"), SettingsResult::InvalidValue);
+        assert_eq!(settings.set_int("test", "title_format", 1), SettingsResult::WrongType);
+
+        let ext = SettingsLayer::Extension("sid".to_owned());
+        let value = SettingValue::Str("%title% (%subsong%)".to_owned());
+        assert_eq!(settings.set("test", &ext, "title_format", &value), SettingsResult::Ok);
+        assert_eq!(settings.get_string("test", "sid", "title_format").get_value(), "%title% (%subsong%)");
+        assert_eq!(settings.get_string("test", "", "title_format").get_value(), "%artist% - %title%");
+    }
+
+    #[test]
+    fn load_invalid_values() {
+        let dir = temp_dir("load_invalid_values");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let text = r#"
+plugin_name = "test"
+
+[[settings]]
+id = "volume"
+value = { IntValue = 200 }
+
+[[settings]]
+id = "stereo_separation"
+value = { FloatValue = -1.0 }
+
+[[settings]]
+id = "filter"
+value = { IntValue = 1 }
+
+[[settings]]
+id = "channels"
+value = { IntValue = 6 }
+
+[[settings]]
+id = "interpolation"
+value = { StrValue = "worst" }
+
+[[settings]]
+id = "removed"
+value = { BoolValue = true }
+
+[[settings]]
+id = "rom_path"
+value = { StrValue = "/roms" }
+
+[[extensions.mod]]
+id = "volume"
+value = { IntValue = -5 }
+
+[[extensions.mod]]
+id = "channels"
+value = { IntValue = 3 }
+"#;
+
+        std::fs::write(dir.join("test.toml"), text).unwrap();
+        let settings = settings_in(&dir);
+
+        // clamped
+        assert_eq!(settings.get_int("test", "", "volume").value, 100);
+        assert_eq!(settings.get_float("test", "", "stereo_separation").value, 0.0);
+        assert_eq!(settings.get_int("test", "mod", "volume").value, 0);
+        // skipped so the defaults are kept
+        assert!(!settings.get_bool("test", "", "filter").value);
+        assert_eq!(settings.get_int("test", "", "channels").value, 2);
+        assert_eq!(settings.get_int("test", "mod", "channels").value, 2);
+        assert_eq!(settings.get_string("test", "", "interpolation").get_value(), "fast");
+        // valid
+        assert_eq!(settings.get_string("test", "", "rom_path").get_value(), "/roms");
+
+        let invalid = settings.invalid_entries();
+        let ids: Vec<(&str, &SettingsLayer)> = invalid.iter().map(|i| (i.id.as_str(), &i.layer)).collect();
+        let ext = SettingsLayer::Extension("mod".to_owned());
+
+        assert_eq!(
+            ids,
+            [
+                ("volume", &SettingsLayer::Global),
+                ("stereo_separation", &SettingsLayer::Global),
+                ("filter", &SettingsLayer::Global),
+                ("channels", &SettingsLayer::Global),
+                ("interpolation", &SettingsLayer::Global),
+                ("removed", &SettingsLayer::Global),
+                ("volume", &ext),
+                ("channels", &ext),
+            ]
+        );
+
+        assert!(invalid.iter().all(|i| i.reg_id == "test"));
+        assert!(invalid[0].reason.contains("clamped"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn setters_keep_registered_values() {
+        let registered = fake_settings();
+        let settings = Settings::new();
+        assert_eq!(settings.reg("test", registered), SettingsResult::Ok);
+        assert_eq!(settings.reg("other", fake_settings()), SettingsResult::Ok);
+
+        assert_eq!(settings.set_int("test", "volume", 75), SettingsResult::Ok);
+        assert_eq!(settings.set_string("test", "title_format", "%title%"), SettingsResult::Ok);
+
+        // The array owned by the plugin isn't changed
+        assert_eq!(unsafe { registered[0].int_value.value }, 50);
+        assert_eq!(unsafe { registered[5].string_value.get_value() }, "%artist% - %title%");
+        assert_eq!(settings.get_int("test", "", "volume").value, 75);
+        assert_eq!(settings.get_string("test", "", "title_format").get_value(), "%title%");
+
+        // Only registrations with changed values are reported
+        assert_eq!(settings.take_changed(), ["test"]);
+        assert!(settings.take_changed().is_empty());
+        assert_eq!(settings.set_int("test", "volume", 75), SettingsResult::Ok);
+        assert!(settings.take_changed().is_empty());
+
+        assert_eq!(settings.clear("test", &SettingsLayer::Global, "volume"), SettingsResult::Ok);
+        assert_eq!(settings.get_int("test", "", "volume").value, 50);
+        assert_eq!(settings.take_changed(), ["test"]);
+
+        let mut reg_ids = settings.reg_ids();
+        reg_ids.sort();
+        assert_eq!(reg_ids, ["other", "test"]);
+    }
+
+    #[test]
+    fn save_only_changed() {
+        let dir = temp_dir("save_only_changed");
+        let path = dir.join("test.toml");
+        let settings = settings_in(&dir);
+
+        // nothing differs from the defaults so nothing is written
+        settings.save_all().unwrap();
+        assert!(!path.exists());
+
+        assert_eq!(settings.set_int("test", "volume", 10), SettingsResult::Ok);
+        settings.save_all().unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("volume"));
+        assert!(!text.contains("filter"));
+
+        // back to the default so the file is removed
+        assert_eq!(settings.set_int("test", "volume", 50), SettingsResult::Ok);
+        settings.save("test").unwrap();
+        assert!(!path.exists());
+
+        // free text values are compared by the text and not the pointer to it
+        assert_eq!(settings.set_string("test", "title_format", "%title%"), SettingsResult::Ok);
+        settings.save("test").unwrap();
+        assert!(path.exists());
+
+        assert_eq!(settings.set_string("test", "title_format", "%artist% - %title%"), SettingsResult::Ok);
+        settings.save("test").unwrap();
+        assert!(!path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn layered_overrides() {
+        let settings = Settings::new();
+        assert_eq!(settings.reg("test", fake_settings()), SettingsResult::Ok);
+
+        let ext = SettingsLayer::Extension(".MOD".to_owned());
+        let song = SettingsLayer::Song("songs/intro.mod".to_owned());
+
+        assert_eq!(settings.set("test", &ext, "volume", &SettingValue::Int(60)), SettingsResult::Ok);
+        assert_eq!(settings.set("test", &song, "volume", &SettingValue::Int(70)), SettingsResult::Ok);
+        assert_eq!(settings.set("test", &ext, "volume", &SettingValue::Int(200)), SettingsResult::InvalidValue);
+        assert_eq!(settings.set("test", &ext, "volume", &SettingValue::Bool(true)), SettingsResult::WrongType);
+
+        assert_eq!(settings.get_int("test", "", "volume").value, 50);
+        assert_eq!(settings.get_int("test", "mod", "volume").value, 60);
+        assert_eq!(settings.get_int("test", "xm", "volume").value, 50);
+
+        // the extension comes from the url of the song if the plugin doesn't pass one
+        with_song("songs/other.mod", None, || {
+            assert_eq!(settings.get_int("test", "", "volume").value, 60);
+        });
+
+        with_song("songs/intro.mod", None, || {
+            assert_eq!(settings.get_int("test", "mod", "volume").value, 70);
+            // other settings fall through to the global value
+            assert!(!settings.get_bool("test", "", "filter").value);
+        });
+
+        // songs can also be keyed by content hash
+        let hash = SettingsLayer::Song("1b0d06d04ec173257bd1f8c72d213606".to_owned());
+        assert_eq!(settings.set("test", &hash, "volume", &SettingValue::Int(80)), SettingsResult::Ok);
+
+        with_song("elsewhere/intro.mod", Some("1B0D06D04EC173257BD1F8C72D213606"), || {
+            assert_eq!(settings.get_int("test", "", "volume").value, 80);
+        });
+
+        // set with an upper case hash (and cleared with a lower case one)
+        let upper = SettingsLayer::Song("9E107D9D372BB6826BD81D3542A419D6".to_owned());
+        assert_eq!(settings.set("test", &upper, "volume", &SettingValue::Int(90)), SettingsResult::Ok);
+
+        with_song("elsewhere/other.mod", Some("9e107d9d372bb6826bd81d3542a419d6"), || {
+            assert_eq!(settings.get_int("test", "", "volume").value, 90);
+        });
+
+        let lower = SettingsLayer::Song("9e107d9d372bb6826bd81d3542a419d6".to_owned());
+        assert_eq!(settings.clear("test", &lower, "volume"), SettingsResult::Ok);
+
+        with_song("elsewhere/other.mod", Some("9E107D9D372BB6826BD81D3542A419D6"), || {
+            assert_eq!(settings.get_int("test", "", "volume").value, 60);
+        });
+
+        // urls keep their case
+        let url = SettingsLayer::Song("Songs/Intro.MOD".to_owned());
+        assert_eq!(settings.set("test", &url, "volume", &SettingValue::Int(40)), SettingsResult::Ok);
+
+        with_song("songs/intro.mod", None, || {
+            assert_eq!(settings.get_int("test", "", "volume").value, 70);
+        });
+
+        assert_eq!(settings.clear("test", &song, "volume"), SettingsResult::Ok);
+
+        with_song("songs/intro.mod", None, || {
+            assert_eq!(settings.get_int("test", "", "volume").value, 60);
+        });
+
+        assert_eq!(settings.set_int("test", "volume", 10), SettingsResult::Ok);
+        assert_eq!(settings.clear("test", &SettingsLayer::Global, "volume"), SettingsResult::Ok);
+        assert_eq!(settings.get_int("test", "", "volume").value, 50);
+        assert_eq!(settings.clear("test", &ext, "unknown"), SettingsResult::NotFound);
+    }
+
+    #[test]
+    fn save_and_load_overrides() {
+        let dir = temp_dir("save_and_load_overrides");
+        let settings = settings_in(&dir);
+
+        let ext = SettingsLayer::Extension("mod".to_owned());
+        let song = SettingsLayer::Song("songs/intro.mod".to_owned());
+
+        let value = SettingValue::Str("best".to_owned());
+        assert_eq!(settings.set("test", &ext, "interpolation", &value), SettingsResult::Ok);
+        assert_eq!(settings.set("test", &song, "filter", &SettingValue::Bool(true)), SettingsResult::Ok);
+        settings.save_all().unwrap();
+
+        let settings = settings_in(&dir);
+
+        assert_eq!(settings.get_string("test", "", "interpolation").get_value(), "fast");
+        assert_eq!(settings.get_string("test", "mod", "interpolation").get_value(), "best");
+
+        with_song("songs/intro.mod", None, || {
+            assert!(settings.get_bool("test", "", "filter").value);
+            assert_eq!(settings.get_string("test", "", "interpolation").get_value(), "best");
+        });
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn schema() {
+        let settings = Settings::new();
+        assert_eq!(settings.reg("test", fake_settings()), SettingsResult::Ok);
+        assert_eq!(settings.set_int("test", "volume", 75), SettingsResult::Ok);
+
+        let schema = settings.schema();
+        assert_eq!(schema.len(), 1);
+        assert_eq!(schema[0].reg_id, "test");
+
+        let volume = &schema[0].settings[0];
+        assert_eq!(volume.id, "volume");
+        assert_eq!(volume.name, "name");
+        assert_eq!(volume.description, "desc");
+        assert_eq!(volume.setting_type, SettingType::Int);
+        assert_eq!(volume.min, Some(SettingValue::Int(0)));
+        assert_eq!(volume.max, Some(SettingValue::Int(100)));
+        assert_eq!(volume.default, SettingValue::Int(50));
+        assert_eq!(volume.value, SettingValue::Int(75));
+
+        let types: Vec<SettingType> = schema[0].settings.iter().map(|s| s.setting_type).collect();
+        assert_eq!(
+            types,
+            [
+                SettingType::Int,
+                SettingType::Float,
+                SettingType::Bool,
+                SettingType::IntChoice,
+                SettingType::StringChoice,
+                SettingType::String,
+                SettingType::Path,
+            ]
+        );
+
+        assert_eq!(schema[0].settings[6].value, SettingValue::Str(String::new()));
+
+        let interpolation = &schema[0].settings[4];
+        assert_eq!(
+            interpolation.choices,
+            [
+                SettingChoice { name: "Fast".to_owned(), value: SettingValue::Str("fast".to_owned()) },
+                SettingChoice { name: "Best".to_owned(), value: SettingValue::Str("best".to_owned()) },
+            ]
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&settings.schema_json().unwrap()).unwrap();
+        let volume = &json[0]["settings"][0];
+        assert_eq!(volume["type"], "int");
+        assert_eq!(volume["min"], 0);
+        assert_eq!(volume["value"], 75);
+        assert_eq!(json[0]["settings"][2].get("min"), None);
+        assert_eq!(json[0]["settings"][4]["choices"][1]["value"], "best");
+        assert_eq!(json[0]["settings"][4]["default"], "fast");
+        assert_eq!(json[0]["settings"][5]["type"], "string");
+        assert_eq!(json[0]["settings"][5]["value"], "%artist% - %title%");
+    }
+}
@@ -0,0 +1,119 @@
+//! Conversion between UTF-8 and the legacy 8-bit/multi-byte encodings found in old collections.
+//! Zip archives from DOS use CP437, Amiga software uses Latin-1 and Japanese archives and tunes
+//! usually use Shift-JIS.
+
+use encoding_rs::SHIFT_JIS;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LegacyEncoding {
+    /// IBM PC code page 437 (DOS)
+    Cp437,
+    /// ISO-8859-1 (Amiga)
+    Latin1,
+    /// Shift-JIS (PC-98, X68000 and Japanese Windows)
+    ShiftJis,
+}
+
+#[rustfmt::skip]
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Decode bytes in the given encoding to UTF-8. Invalid sequences are replaced with U+FFFD
+pub fn decode(data: &[u8], encoding: LegacyEncoding) -> String {
+    match encoding {
+        LegacyEncoding::Cp437 => data
+            .iter()
+            .map(|&b| if b < 0x80 { b as char } else { CP437_HIGH[b as usize - 0x80] })
+            .collect(),
+        LegacyEncoding::Latin1 => data.iter().map(|&b| b as char).collect(),
+        LegacyEncoding::ShiftJis => SHIFT_JIS.decode_without_bom_handling(data).0.into_owned(),
+    }
+}
+
+/// Encode UTF-8 text to the given encoding. Returns None if the text contains characters that
+/// can't be represented in the encoding
+pub fn encode(text: &str, encoding: LegacyEncoding) -> Option<Vec<u8>> {
+    match encoding {
+        LegacyEncoding::Cp437 => text
+            .chars()
+            .map(|c| {
+                if c.is_ascii() {
+                    Some(c as u8)
+                } else {
+                    CP437_HIGH.iter().position(|&t| t == c).map(|p| (p + 0x80) as u8)
+                }
+            })
+            .collect(),
+        LegacyEncoding::Latin1 => text
+            .chars()
+            .map(|c| u8::try_from(c as u32).ok())
+            .collect(),
+        LegacyEncoding::ShiftJis => {
+            let (data, _, unmappable) = SHIFT_JIS.encode(text);
+            if unmappable {
+                None
+            } else {
+                Some(data.into_owned())
+            }
+        }
+    }
+}
+
+fn is_japanese(c: char) -> bool {
+    matches!(c,
+        '\u{3000}'..='\u{30ff}' | // punctuation, hiragana and katakana
+        '\u{4e00}'..='\u{9fff}' | // kanji
+        '\u{ff00}'..='\u{ffef}')  // full/half width forms
+}
+
+fn is_kana(c: char) -> bool {
+    matches!(c, '\u{3040}'..='\u{30ff}' | '\u{ff66}'..='\u{ff9f}')
+}
+
+// Counts the double byte Shift-JIS sequences where the second byte isn't an ascii letter. CP437/Latin-1 text
+// is usually an accented letter followed by a regular letter which also happens to be valid Shift-JIS.
+fn shift_jis_pairs(data: &[u8]) -> usize {
+    let mut count = 0;
+    let mut i = 0;
+
+    while i < data.len() {
+        if matches!(data[i], 0x81..=0x9f | 0xe0..=0xfc) && i + 1 < data.len() {
+            if !data[i + 1].is_ascii_alphabetic() {
+                count += 1;
+            }
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+
+    count
+}
+
+/// Guess the encoding of data that isn't valid UTF-8. Data that is valid Shift-JIS where all non-ascii characters
+/// are Japanese is assumed to be Shift-JIS if it has kana or a few double byte characters that doesn't look like
+/// accented letters. Otherwise bytes in the 0x80 - 0x9f range (control codes in Latin-1, but accented letters
+/// in CP437) are used to select between CP437 and Latin-1.
+pub fn detect(data: &[u8]) -> LegacyEncoding {
+    if let Some(text) = SHIFT_JIS.decode_without_bom_handling_and_without_replacement(data) {
+        let all_japanese = text.chars().filter(|c| !c.is_ascii()).all(is_japanese);
+
+        if all_japanese && (text.chars().any(is_kana) || shift_jis_pairs(data) >= 2) {
+            return LegacyEncoding::ShiftJis;
+        }
+    }
+
+    if data.iter().any(|b| (0x80..0xa0).contains(b)) {
+        LegacyEncoding::Cp437
+    } else {
+        LegacyEncoding::Latin1
+    }
+}
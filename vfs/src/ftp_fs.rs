@@ -1,7 +1,7 @@
-use crate::{InternalError, LoadStatus, Progress, VfsDriver, VfsDriverType, FilesDirs};
+use crate::{Bytes, FileInfo, InternalError, LoadStatus, Progress, VfsDriver, VfsDriverType, FilesDirs};
 use ftp::{FtpError, FtpStream};
+use crate::path_match::normalize_separators;
 use log::error;
-use std::path::MAIN_SEPARATOR;
 //use std::collections::HashSet;
 //use std::fs::File;
 //use std::io::{Cursor, Read, Write};
@@ -13,6 +13,26 @@ pub const FTP_URL:&str = "ftp:\\";
 #[cfg(not(target_os = "windows"))]
 pub const FTP_URL:&str = "ftp:/";
 
+// Reports progress while the data is being uploaded
+struct ProgressReader<'a, 'b> {
+    data: &'a [u8],
+    progress: &'a mut Progress<'b>,
+}
+
+impl std::io::Read for ProgressReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = usize::min(buf.len(), self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+
+        if len > 0 {
+            self.progress.step().map_err(|e| std::io::Error::other(e.to_string()))?;
+        }
+
+        Ok(len)
+    }
+}
+
 #[derive(Debug)]
 pub struct FtpFs {
     data: Option<FtpStream>,
@@ -26,27 +46,13 @@ impl FtpFs {
 
 impl FtpFs {
     fn find_server_name(url: &str) -> Option<&str> {
-        if let Some(url) = url.strip_prefix(FTP_URL) {
-            // handle if we have name and no path
-            if !url.contains(MAIN_SEPARATOR) {
-                return Some(url);
-            }
+        let url = url.strip_prefix(FTP_URL).unwrap_or(url);
 
-            if let Some(offset) = url.find(MAIN_SEPARATOR) {
-                return Some(&url[..offset]);
-            }
-
-        } else {
-            if !url.contains(MAIN_SEPARATOR) {
-                return Some(url);
-            }
-
-            if let Some(offset) = url.find(MAIN_SEPARATOR) {
-                return Some(&url[..offset]);
-            }
-        } 
-
-        None
+        // handle if we have name and no path
+        match url.find(['/', '\\']) {
+            Some(offset) => Some(&url[..offset]),
+            None => Some(url),
+        }
     }
 }
 
@@ -77,8 +83,8 @@ impl VfsDriver for FtpFs {
     }
 
     // Create a new instance given data
-    fn create_from_data(&self, _data: Box<[u8]>) -> Option<VfsDriverType> {
-        None
+    fn create_from_data(&self, _data: Bytes) -> Result<VfsDriverType, InternalError> {
+        Err(InternalError::DriverError("ftp_fs can't be created from data".into()))
     }
 
     // Get some data in and returns true if driver can be mounted from it
@@ -89,37 +95,26 @@ impl VfsDriver for FtpFs {
         }
 
         // Make sure we don't have any slashes in the path except ftp start
-        if let Some(url) = url.strip_prefix(FTP_URL) {
-            !url.contains(MAIN_SEPARATOR)
-        } else {
-            !url.contains(MAIN_SEPARATOR)
-        }
+        let url = url.strip_prefix(FTP_URL).unwrap_or(url);
+        !url.contains(['/', '\\'])
     }
 
     /// Used when creating an instance of the driver with a path to load from
-    fn create_from_url(&self, url: &str) -> Option<VfsDriverType> {
-        if let Some(url) = Self::find_server_name(url) {
-            let url_with_port = if url.contains(':') {
-                url.to_owned()
-            } else {
-                format!("{}:21", url)
-            };
-
-            let mut stream = match FtpStream::connect(url_with_port) {
-                Ok(stream) => stream,
-                Err(e) => {
-                    error!("Unable to connect to {:?}", e);
-                    return None;
-                }
-            };
+    fn create_from_url(&self, url: &str) -> Result<VfsDriverType, InternalError> {
+        let url = Self::find_server_name(url).ok_or(InternalError::UnsupportedPath)?;
 
-            stream.login("anonymous", "anonymous").unwrap();
-            stream.transfer_type(ftp::types::FileType::Binary).unwrap();
+        let url_with_port = if url.contains(':') {
+            url.to_owned()
+        } else {
+            format!("{}:21", url)
+        };
 
-            return Some(Box::new(FtpFs { data: Some(stream) }))
-        }
+        let mut stream = FtpStream::connect(url_with_port).inspect_err(|e| error!("Unable to connect to {:?}", e))?;
 
-        None
+        stream.login("anonymous", "anonymous")?;
+        stream.transfer_type(ftp::types::FileType::Binary)?;
+
+        Ok(Box::new(FtpFs { data: Some(stream) }))
     }
 
     /// Returns a handle which updates the progress and returns the loaded data. This will try to
@@ -129,6 +124,9 @@ impl VfsDriver for FtpFs {
         progress: &mut Progress,
     ) -> Result<LoadStatus, InternalError> {
         let conn = self.data.as_mut().unwrap();
+        // FTP servers always use / as separator
+        let path = normalize_separators(path);
+        let path = path.as_ref();
 
         // We get a listing of the files first here because if we try to do 'SIZE' on a directory 
         // this command will hang, if this is a fault of the FTP server or ftp-rs I don't know, but this is a workaround at least 
@@ -160,7 +158,7 @@ impl VfsDriver for FtpFs {
 
                 })?;
 
-                Ok(LoadStatus::Data(output_data.into_boxed_slice()))
+                Ok(LoadStatus::Data(output_data.into()))
 
         } else {
             // if we didn't get any size here we assume it's a directory.
@@ -174,13 +172,13 @@ impl VfsDriver for FtpFs {
         progress: &mut Progress,
     ) -> Result<FilesDirs, InternalError> {
         let conn = self.data.as_mut().unwrap();
+        let path = normalize_separators(path);
 
         progress.set_step(2);
 
-        let dirs_and_files = conn.list(Some(path))?;
+        let dirs_and_files = conn.list(Some(&path))?;
 
-        let mut dirs = Vec::with_capacity(dirs_and_files.len());
-        let mut files = Vec::with_capacity(dirs_and_files.len());
+        let mut files_dirs = FilesDirs::new(Vec::with_capacity(dirs_and_files.len()), Vec::new());
 
         progress.step()?;
 
@@ -192,17 +190,43 @@ impl VfsDriver for FtpFs {
 
             // if flags starts with 'd' we assume it's a directory 
             if t[0].starts_with('d') {
-                dirs.push(t[8].to_owned());
+                files_dirs.dirs.push(t[8].to_owned());
             } else {
-                files.push(t[8].to_owned());
+                // The date is kept as listed (the year is left out for recent files) as it's only compared
+                // with earlier listings
+                if let Ok(size) = t[4].parse() {
+                    let info = FileInfo { size, modified: t[5..8].join(" ") };
+                    files_dirs.file_infos.insert(t[8].to_owned(), info);
+                }
+
+                files_dirs.files.push(t[8].to_owned());
             }
         } 
 
-        files.sort();
-        dirs.sort();
+        files_dirs.files.sort();
+        files_dirs.dirs.sort();
 
         progress.step()?;
 
-        Ok(FilesDirs::new(files, dirs))
+        Ok(files_dirs)
+    }
+
+    /// Uploads the data with STOR. Directories are not created
+    fn write_url(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        progress: &mut Progress,
+    ) -> Result<(), InternalError> {
+        let conn = self.data.as_mut().unwrap();
+        let path = normalize_separators(path);
+
+        // The data is read in blocks of at most 8k by the ftp stream
+        progress.set_step(data.len() / (8 * 1024) + 1);
+
+        let mut reader = ProgressReader { data, progress };
+        conn.put(&path, &mut reader)?;
+
+        Ok(())
     }
 }
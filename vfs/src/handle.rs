@@ -0,0 +1,147 @@
+//! Handles returned for requests to the vfs. Replies can be polled from `recv`, waited for with
+//! [`Handle::wait`] or, with the `async` feature, awaited as the handle implements `Future`.
+
+use crate::{Data, FilesDirs, RecvMsg, VfsError};
+use crossbeam_channel::{RecvTimeoutError, SendError};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Final reply to a request
+#[derive(Debug)]
+pub enum Response {
+    Data(Data),
+    Directory(FilesDirs),
+    /// Data was written by [`crate::Vfs::write_url`] or [`crate::Vfs::copy_url`]
+    WriteDone,
+}
+
+#[derive(Error, Debug)]
+pub enum WaitError {
+    #[error("Timed out waiting for the vfs")]
+    Timeout,
+    #[error("Not found")]
+    NotFound,
+    /// The vfs stopped without replying
+    #[error("The vfs has been stopped")]
+    Disconnected,
+    #[error(transparent)]
+    Vfs(#[from] VfsError),
+}
+
+// Task waiting for the handle (when used as a future)
+type SharedWaker = Arc<Mutex<Option<Waker>>>;
+
+/// Sending side of a [`Handle`]. Sending wakes up the task awaiting the handle (if any)
+#[derive(Clone, Debug)]
+pub struct HandleSender {
+    send: crossbeam_channel::Sender<RecvMsg>,
+    waker: SharedWaker,
+}
+
+impl HandleSender {
+    pub fn send(&self, msg: RecvMsg) -> Result<(), SendError<RecvMsg>> {
+        self.send.send(msg)?;
+
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    pub recv: crossbeam_channel::Receiver<RecvMsg>,
+    // Only used by the Future implementation
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    waker: SharedWaker,
+}
+
+/// Creates a handle and the sender used by the vfs to reply to it
+pub(crate) fn channel() -> (HandleSender, Handle) {
+    let (send, recv) = crossbeam_channel::unbounded::<RecvMsg>();
+    let waker = SharedWaker::default();
+
+    (HandleSender { send, waker: waker.clone() }, Handle { recv, waker })
+}
+
+// Converts a message to the final result. Returns None for progress updates
+fn to_response(msg: RecvMsg) -> Option<Result<Response, WaitError>> {
+    match msg {
+        RecvMsg::ReadProgress(_) => None,
+        RecvMsg::ReadDone(data) => Some(Ok(Response::Data(data))),
+        RecvMsg::Directory(files_dirs) => Some(Ok(Response::Directory(files_dirs))),
+        RecvMsg::WriteDone => Some(Ok(Response::WriteDone)),
+        RecvMsg::NotFound => Some(Err(WaitError::NotFound)),
+        RecvMsg::Error(e) => Some(Err(e.into())),
+    }
+}
+
+impl Handle {
+    /// Blocks until the request is done or the timeout has passed
+    pub fn wait(&self, timeout: Duration) -> Result<Response, WaitError> {
+        self.wait_with_progress(timeout, |_| ())
+    }
+
+    /// Blocks until the request is done or the timeout has passed. Progress (0.0 - 1.0) is reported to the callback
+    pub fn wait_with_progress<F: FnMut(f32)>(&self, timeout: Duration, mut progress: F) -> Result<Response, WaitError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let msg = match self.recv.recv_deadline(deadline) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => return Err(WaitError::Timeout),
+                Err(RecvTimeoutError::Disconnected) => return Err(WaitError::Disconnected),
+            };
+
+            if let RecvMsg::ReadProgress(p) = msg {
+                progress(p);
+            }
+
+            if let Some(res) = to_response(msg) {
+                return res;
+            }
+        }
+    }
+
+    // Returns the result if the request is done. Progress updates are skipped
+    #[cfg(feature = "async")]
+    fn try_response(&self) -> Option<Result<Response, WaitError>> {
+        use crossbeam_channel::TryRecvError;
+
+        loop {
+            match self.recv.try_recv() {
+                Ok(msg) => {
+                    if let Some(res) = to_response(msg) {
+                        return Some(res);
+                    }
+                }
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => return Some(Err(WaitError::Disconnected)),
+            }
+        }
+    }
+}
+
+/// Allows `vfs.load_url(url).await`. Works with any executor as the vfs worker wakes the task when it replies
+#[cfg(feature = "async")]
+impl std::future::Future for Handle {
+    type Output = Result<Response, WaitError>;
+
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
+        if let Some(res) = self.try_response() {
+            return std::task::Poll::Ready(res);
+        }
+
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+
+        // The reply may have arrived before the waker was registered
+        match self.try_response() {
+            Some(res) => std::task::Poll::Ready(res),
+            None => std::task::Poll::Pending,
+        }
+    }
+}
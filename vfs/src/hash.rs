@@ -0,0 +1,46 @@
+//! Content hashes of loaded data. Song databases (modland, HVSC, UADE) identify tunes by the MD5 or SHA-1
+//! of the file contents, so these allow looking up a file independent of where it was loaded from.
+
+use md5::Md5;
+use sha1::{Digest, Sha1};
+
+/// Hashes of the contents of a loaded file. Only the hashes enabled in [`crate::VfsConfig`] are computed.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct ContentHashes {
+    pub md5: Option<[u8; 16]>,
+    pub sha1: Option<[u8; 20]>,
+}
+
+impl ContentHashes {
+    pub(crate) fn compute(data: &[u8], md5: bool, sha1: bool) -> ContentHashes {
+        ContentHashes {
+            md5: md5.then(|| Md5::digest(data).into()),
+            sha1: sha1.then(|| Sha1::digest(data).into()),
+        }
+    }
+
+    /// Returns true if all the requested hashes are present
+    pub(crate) fn has(&self, md5: bool, sha1: bool) -> bool {
+        (!md5 || self.md5.is_some()) && (!sha1 || self.sha1.is_some())
+    }
+
+    /// MD5 as a lower case hex string (the format used by Songlengths.md5 and modland)
+    pub fn md5_hex(&self) -> Option<String> {
+        self.md5.as_ref().map(|h| to_hex(h))
+    }
+
+    /// SHA-1 as a lower case hex string
+    pub fn sha1_hex(&self) -> Option<String> {
+        self.sha1.as_ref().map(|h| to_hex(h))
+    }
+}
+
+/// Formats bytes as a lower case hex string
+pub fn to_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
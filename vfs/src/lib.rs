@@ -1,14 +1,28 @@
-use crossbeam_channel::unbounded;
 use log::*;
+
+pub use bytes::Bytes;
 use thiserror::Error;
 
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::path::{Component, Path, PathBuf};
 use std::thread::{self};
+use std::time::Duration;
 
 mod local_fs;
 mod zip_fs;
 mod ftp_fs;
+mod path_match;
+mod handle;
+mod tree;
+mod hash;
+pub mod encoding;
+
+pub use handle::{Handle, HandleSender, Response, WaitError};
+pub use tree::NodeSnapshot;
+pub use hash::{to_hex, ContentHashes};
+use tree::{add_new_node, mount_driver, use_driver, NodeDriver};
 
 #[cfg(test)]
 use std::println as trace;
@@ -17,42 +31,109 @@ use std::println as trace;
 // common that another system wants to read the same data. We keep it alive for this amount of entries at the same time
 const MAX_CACHE_COUNT:usize = 5;
 
+/// Settings for how the vfs resolves urls. Given when creating the vfs with [`Vfs::new_with_config`]
+#[derive(Clone, Default, Debug)]
+pub struct VfsConfig {
+    /// Resolve paths without regard to case. Names are compared after Unicode (NFC) normalization and `\`
+    /// is accepted as a separator on all platforms. This is useful for Amiga and DOS collections where
+    /// playlists and archives often don't agree on the case of names.
+    pub case_insensitive: bool,
+    /// Encoding used for archive file names that aren't UTF-8 (old zips use CP437 or Shift-JIS). If None
+    /// the encoding is detected per archive. Names are always reported as UTF-8 and can be passed back
+    /// as is to [`Vfs::load_url`].
+    pub archive_name_encoding: Option<encoding::LegacyEncoding>,
+    /// Driver instances (mounted archives, ftp connections) that hasn't been used for this long are dropped
+    /// together with their part of the node tree. If None drivers are only dropped by [`Vfs::unmount`] and
+    /// [`Vfs::evict_idle`].
+    pub driver_idle_timeout: Option<Duration>,
+    /// Include symlinked directories in local directory listings. Links that lead back to the directory being
+    /// listed (or one of its parents) are always skipped so walking the tree (such as in random mode) will not
+    /// loop. If false symlinked directories are left out of the listings so they aren't traversed, but paths
+    /// containing them can still be loaded. Symlinks to files are always listed (broken links never are).
+    pub follow_symlinks: bool,
+    /// Leave dotfiles and OS junk (`.DS_Store`, `Thumbs.db`, `__MACOSX`) out of directory listings
+    pub hide_junk: bool,
+    /// Compute the MD5 of loaded files (see [`Data::hashes`]). Hashes are cached per node so a file is only
+    /// hashed once while it stays in the tree. They are computed again if the file is written, its size or
+    /// modification time in a listing changes or the loaded data has another size.
+    pub hash_md5: bool,
+    /// Compute the SHA-1 of loaded files
+    pub hash_sha1: bool,
+}
+
+/// Size and modification time of a file as reported by the driver when listing its directory. Can be used to
+/// detect changed files without loading them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub size: u64,
+    /// Modification time in the format the driver reports it (seconds since the epoch for local files, the date
+    /// of the listing for ftp). Only comparable with values reported by the same driver
+    pub modified: String,
+}
+
 #[derive(Default, Debug)]
 pub struct FilesDirs {
     pub files: Vec<String>,
     pub dirs: Vec<String>,
+    /// Info for the entries in `files` by name. Files are missing if the driver doesn't report it
+    pub file_infos: HashMap<String, FileInfo>,
 }
 
 impl FilesDirs {
-    pub(crate) fn new(files: Vec<String>, dirs: Vec<String>) -> FilesDirs {
-        FilesDirs { files, dirs }
+    pub fn new(files: Vec<String>, dirs: Vec<String>) -> FilesDirs {
+        FilesDirs { files, dirs, file_infos: HashMap::new() }
     }
 }
 
-unsafe impl Sync for Data {}
-unsafe impl Send for Data {}
-
+/// Loaded data. This shares the buffer with the vfs (including the cache and any archive the data came from)
+/// so no copy is made. The buffer stays valid for as long as the Data (or a clone of the bytes) is alive.
 pub struct Data {
-    pub ptr: *const u8,
-    pub size: usize,
+    bytes: Bytes,
+    hashes: ContentHashes,
+}
+
+impl std::fmt::Debug for Data {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Data").field("size", &self.bytes.len()).finish()
+    }
 }
 
 impl Data {
-    pub fn new(data: &[u8]) -> Data {
-        Data {
-            ptr: data.as_ptr(),
-            size: data.len(),
-        }
+    pub fn new(bytes: Bytes) -> Data {
+        Data { bytes, hashes: ContentHashes::default() }
+    }
+
+    pub fn with_hashes(bytes: Bytes, hashes: ContentHashes) -> Data {
+        Data { bytes, hashes }
+    }
+
+    /// Hashes of the contents. Only set for the hashes enabled in [`VfsConfig`]
+    pub fn hashes(&self) -> &ContentHashes {
+        &self.hashes
     }
 
     pub fn get(&self) -> &[u8] {
-        unsafe { std::slice::from_raw_parts(self.ptr, self.size)}
+        &self.bytes
+    }
+
+    /// Get the shared buffer
+    pub fn into_bytes(self) -> Bytes {
+        self.bytes
     }
 }
 
+// Maps a file into memory. The file is expected to not change while mapped as it would change the data
+// seen by users of it (this is the same as for any other program mapping files)
+pub(crate) fn map_file(file: &std::fs::File) -> std::io::Result<Bytes> {
+    let map = unsafe { memmap2::Mmap::map(file)? };
+    Ok(Bytes::from_owner(map))
+}
+
 pub enum RecvMsg {
     ReadProgress(f32),
     ReadDone(Data),
+    /// Data was written by [`Vfs::write_url`] or [`Vfs::copy_url`]
+    WriteDone,
     Error(VfsError),
     Directory(FilesDirs),
     NotFound,
@@ -60,8 +141,8 @@ pub enum RecvMsg {
 
 #[derive(Debug)]
 pub enum LoadStatus {
-    // Data was loaded from the current node
-    Data(Box<[u8]>),
+    // Data was loaded from the current node. The buffer is shared (not copied) when an archive is mounted from it
+    Data(Bytes),
     // directory.
     Directory,
     /// Requested node wasn't found
@@ -70,37 +151,58 @@ pub enum LoadStatus {
 
 #[derive(Error, Debug)]
 pub enum InternalError {
-    #[error("File Error)")]
+    #[error("File Error: not found")]
     FileDirNotFound,
-    #[error("File Error)")]
+    #[error("File Error: {0}")]
     FileError(#[from] std::io::Error),
-    #[error("Parse Error)")]
+    #[error("Parse Error: {0}")]
     ParseError(#[from] std::num::ParseIntError),
+    /// The receiver of the request has been dropped
     #[error("Send Error")]
-    SendError(#[from] crossbeam_channel::SendError<RecvMsg>),
-    #[error("Walkdir Error")]
+    SendError,
+    #[error("Walkdir Error: {0}")]
     WalkdirError(#[from] walkdir::Error),
-    #[error("Ftp Error")]
+    #[error("Ftp Error: {0}")]
     FtpError(#[from] ftp::FtpError),
+    #[error("Zip Error: {0}")]
+    ZipError(#[from] zip::result::ZipError),
+    #[error("Driver Error: {0}")]
+    DriverError(String),
+    /// No driver is able to handle the url
+    #[error("Unsupported path")]
+    UnsupportedPath,
 }
 
+// The message isn't kept as it can't be delivered anyway (and would make the error contain itself)
+impl From<crossbeam_channel::SendError<RecvMsg>> for InternalError {
+    fn from(_: crossbeam_channel::SendError<RecvMsg>) -> Self {
+        InternalError::SendError
+    }
+}
+
+/// Error sent back to the [`Handle`] when a request fails
 #[derive(Error, Debug)]
-pub enum VfsError {
-    /// Errors from std::io::Error
-    #[error("File Error)")]
-    FileError(#[from] std::io::Error),
+#[error("{url} ({}): {cause}", .driver.unwrap_or("vfs"))]
+pub struct VfsError {
+    /// Url of the request. For [`Vfs::copy_url`] this is the source or the destination depending on which failed
+    pub url: String,
+    /// Name of the driver that failed. None if the error happened before a driver was involved
+    pub driver: Option<&'static str>,
+    #[source]
+    pub cause: InternalError,
 }
 
 #[derive(Clone, Debug)]
-pub(crate) struct Progress<'a> {
+pub struct Progress<'a> {
     range: (f32, f32),
     step: f32,
     current: f32,
-    msg: &'a crossbeam_channel::Sender<RecvMsg>,
+    msg: &'a HandleSender,
 }
 
-/// File system implementations must implement this trait
-pub(crate) trait VfsDriver: std::fmt::Debug {
+/// File system implementations must implement this trait. Besides the built-in drivers new ones can be
+/// registered with [`Vfs::add_driver`] (core uses this for drivers coming from `rv_vfs_plugin` plugins)
+pub trait VfsDriver: std::fmt::Debug + Send {
     /// This indicates that the file system is remote (such as ftp, https) and has no local path
     fn is_remote(&self) -> bool;
     /// If a driver id should be included for the node (should be true for anything but local) 
@@ -111,12 +213,12 @@ pub(crate) trait VfsDriver: std::fmt::Debug {
     fn create_instance(&self) -> Box<dyn VfsDriver>;
     // Get some data in and returns true if driver can be mounted from it
     fn can_load_from_data(&self, data: &[u8]) -> bool;
-    // Create a new instance given data. The VfsDriver will take ownership of the data
-    fn create_from_data(&self, data: Box<[u8]>) -> Option<VfsDriverType>;
+    // Create a new instance given data. The data is shared with the vfs so the driver should keep it instead of copying it
+    fn create_from_data(&self, data: Bytes) -> Result<VfsDriverType, InternalError>;
     // Get some data in and returns true if driver can be mounted from it
     fn can_load_from_url(&self, url: &str) -> bool;
     /// Used when creating an instance of the driver with a path to load from
-    fn create_from_url(&self, url: &str) -> Option<VfsDriverType>;
+    fn create_from_url(&self, url: &str) -> Result<VfsDriverType, InternalError>;
     /// Returns a handle which updates the progress and returns the loaded data. This will try to
     fn load_url(
         &mut self,
@@ -129,11 +231,17 @@ pub(crate) trait VfsDriver: std::fmt::Debug {
         path: &str,
         progress: &mut Progress,
     ) -> Result<FilesDirs, InternalError>;
-}
-
-#[derive(Clone)]
-pub struct Handle {
-    pub recv: crossbeam_channel::Receiver<RecvMsg>,
+    /// Write data to a path relative to where the driver was created. Missing directories should be created if
+    /// possible. Drivers are read-only unless they implement this.
+    fn write_url(
+        &mut self,
+        _path: &str,
+        _data: &[u8],
+        _progress: &mut Progress,
+    ) -> Result<(), InternalError> {
+        let e = std::io::Error::new(std::io::ErrorKind::Unsupported, format!("{} doesn't support writing", self.name()));
+        Err(e.into())
+    }
 }
 
 pub enum VfsType {
@@ -156,7 +264,7 @@ impl<'a> Progress<'a> {
         self.step = 1.0 / usize::max(1, count) as f32;
     }
 
-    fn new(start: f32, end: f32, msg: &crossbeam_channel::Sender<RecvMsg>) -> Progress {
+    fn new(start: f32, end: f32, msg: &HandleSender) -> Progress<'_> {
         Progress {
             range: (start, end),
             step: 0.1,
@@ -166,8 +274,9 @@ impl<'a> Progress<'a> {
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Default, Clone, Serialize, Deserialize)]
 pub enum NodeType {
+    #[default]
     Unknown,
     File,
     Directory,
@@ -175,12 +284,6 @@ pub enum NodeType {
     Other(usize),
 }
 
-impl Default for NodeType {
-    fn default() -> Self {
-        NodeType::Unknown
-    }
-}
-
 // TODO: Move bunch of data out to arrays to reduce reallocs
 #[derive(Default, Debug)]
 pub struct Node {
@@ -189,6 +292,10 @@ pub struct Node {
     driver_index: i32,
     parent: u32,
     nodes: Vec<u32>,
+    /// Size and modification time from the directory listing (files only)
+    info: Option<FileInfo>,
+    /// Hashes of the file contents once it has been loaded and the size of the data they were computed from
+    hashes: Option<(ContentHashes, usize)>,
 }
 
 impl Node {
@@ -221,32 +328,47 @@ impl Node {
             ..Default::default()
         }
     }
+
+    fn new_listed_file_node(name: String, parent: u32, info: Option<FileInfo>) -> Node {
+        Node {
+            info,
+            ..Self::new_file_node(name, parent)
+        }
+    }
 }
 
-type VfsDriverType = Box<dyn VfsDriver>;
+pub type VfsDriverType = Box<dyn VfsDriver>;
 
 struct CachedDataEntry {
     path: String,
-    data: Box<[u8]>,
+    data: Bytes,
+    hashes: ContentHashes,
 }
 
 #[derive(Default)]
 struct VfsState {
+    config: VfsConfig,
     nodes: Vec<Node>,
-    node_drivers: Vec<VfsDriverType>,
+    /// Free slots in nodes (removed by unmount/eviction) that will be reused
+    free_nodes: Vec<u32>,
+    node_drivers: Vec<Option<NodeDriver>>,
+    free_drivers: Vec<usize>,
     drivers: Vec<VfsDriverType>,
     cached_data: Vec<CachedDataEntry>, 
+    /// Name of the driver used last by the current request. Reported in errors
+    active_driver: Option<&'static str>,
 }
 
 impl VfsState {
-    fn new() -> VfsState {
+    fn new(config: VfsConfig) -> VfsState {
         let drivers: Vec<VfsDriverType> = vec![
             Box::new(ftp_fs::FtpFs::new()),
-            Box::new(zip_fs::ZipFs::new()),
-            Box::new(local_fs::LocalFs::new()),
+            Box::new(zip_fs::ZipFs::new(config.archive_name_encoding)),
+            Box::new(local_fs::LocalFs::new(config.follow_symlinks)),
         ];
 
         VfsState {
+            config,
             drivers,
             nodes: vec![Node::new_directory_node("root".into(), 0)],
             cached_data: Vec::with_capacity(MAX_CACHE_COUNT),
@@ -266,34 +388,97 @@ impl Vfs {
     /// (given if it has a file listing) and that will be returned until a file is encountered. If there are
     /// no files an error will/archive will be returned instead and the user code has to handle it
     pub fn load_url(&self, path: &str) -> Handle {
-        let (thread_send, main_recv) = unbounded::<RecvMsg>();
+        let (thread_send, handle) = handle::channel();
 
         self.main_send
             .send(SendMsg::LoadUrl(path.into(), 0, thread_send))
             .unwrap();
 
-        Handle { recv: main_recv }
+        handle
+    }
+
+    /// Registers a new driver with the vfs. Added drivers are tried before the built-in ones so they can
+    /// be used to handle archive formats or protocols the vfs doesn't support by itself
+    pub fn add_driver(&self, driver: VfsDriverType) {
+        self.main_send.send(SendMsg::AddDriver(driver)).unwrap();
+    }
+
+    /// Removes the node for a path and everything below it from the vfs. Drivers mounted within it (such as
+    /// archives and ftp connections) are dropped and cached data loaded from it is released.
+    pub fn unmount(&self, path: &str) {
+        self.main_send.send(SendMsg::Unmount(path.into())).unwrap();
+    }
+
+    /// Drops all driver instances that hasn't been used for the given duration. Use `Duration::ZERO` to drop all.
+    pub fn evict_idle(&self, max_idle: Duration) {
+        self.main_send.send(SendMsg::EvictIdle(max_idle)).unwrap();
+    }
+
+    /// Writes data to a url. The file (and any missing directories) is created or replaced. The handle gets
+    /// progress and [`RecvMsg::WriteDone`] when done.
+    pub fn write_url(&self, url: &str, data: impl Into<Bytes>) -> Handle {
+        let (thread_send, handle) = handle::channel();
+
+        self.main_send
+            .send(SendMsg::WriteUrl(url.into(), data.into(), thread_send))
+            .unwrap();
+
+        handle
+    }
+
+    /// Copies the data at `src` (which may be inside archives or remote) to `dst`. The progress covers both
+    /// loading and writing and [`RecvMsg::WriteDone`] is sent when done. If `src` is a directory the listing
+    /// is sent back instead and nothing is written.
+    pub fn copy_url(&self, src: &str, dst: &str) -> Handle {
+        let (thread_send, handle) = handle::channel();
+
+        self.main_send
+            .send(SendMsg::CopyUrl(src.into(), dst.into(), thread_send))
+            .unwrap();
+
+        handle
+    }
+
+    /// Returns a snapshot of the current node tree. This waits for the vfs to finish pending requests
+    pub fn snapshot(&self) -> NodeSnapshot {
+        let (thread_send, main_recv) = crossbeam_channel::bounded(1);
+        self.main_send.send(SendMsg::Snapshot(thread_send)).unwrap();
+        main_recv.recv().unwrap()
     }
 }
 
 pub enum SendMsg {
-    LoadUrl(String, u32, crossbeam_channel::Sender<RecvMsg>),
+    LoadUrl(String, u32, HandleSender),
+    AddDriver(VfsDriverType),
+    WriteUrl(String, Bytes, HandleSender),
+    CopyUrl(String, String, HandleSender),
+    Unmount(String),
+    EvictIdle(Duration),
+    Snapshot(crossbeam_channel::Sender<NodeSnapshot>),
 }
 
-fn handle_error(e: InternalError, msg: &crossbeam_channel::Sender<RecvMsg>) {
-    if let InternalError::FileError(e) = e {
-        let file_error = format!("{:#?}", e);
-        if let Err(send_err) = msg.send(RecvMsg::Error(e.into())) {
-            error!(
-                "evfs: Unable to send file error {:#?} to main thread due to {:#?}",
-                file_error, send_err
-            );
-        }
+fn handle_error(vfs: &VfsState, e: InternalError, url: &str, msg: &HandleSender) {
+    let error = VfsError {
+        url: url.to_owned(),
+        driver: vfs.active_driver,
+        cause: e,
+    };
+
+    // A send error means that the receiver is gone so there is nobody to report to
+    if let InternalError::SendError = error.cause {
+        trace!("evfs: Receiver dropped for {}", url);
+        return;
+    }
+
+    let text = error.to_string();
+
+    if msg.send(RecvMsg::Error(error)).is_err() {
+        error!("evfs: Unable to send error {} to main thread", text);
     }
 }
 
 // TODO: uses a hashmap instead?
-fn find_entry_in_node(node: &Node, nodes: &[Node], name: &str) -> Option<usize> {
+fn find_entry_in_node(node: &Node, nodes: &[Node], name: &str, case_insensitive: bool) -> Option<usize> {
     for n in &node.nodes {
         let index = *n as usize;
         let t = &nodes[index];
@@ -302,7 +487,14 @@ fn find_entry_in_node(node: &Node, nodes: &[Node], name: &str) -> Option<usize>
         }
     }
 
-    None
+    if !case_insensitive {
+        return None;
+    }
+
+    node.nodes
+        .iter()
+        .map(|n| *n as usize)
+        .find(|index| path_match::names_match(&nodes[*index].name, name, true))
 }
 
 fn get_component_name<'a>(component: &'a Component, had_prefix: &mut bool) -> Cow<'a, str> {
@@ -324,15 +516,8 @@ fn get_component_name<'a>(component: &'a Component, had_prefix: &mut bool) -> Co
     }
 }
 
-// Add a new node to the vfs at a specific index and get the new node index back
-fn add_new_node(state: &mut VfsState, index: usize, new_node: Node) -> usize {
-    //let mut node = &state.nodes[index];
-    let new_index = state.nodes.len();
-    state.nodes.push(new_node);
-    state.nodes[index].nodes.push(new_index as u32);
-    new_index
-}
-
+// Adds the nodes for a path below a node. Nodes that are there already (such as the entries of listed directories
+// or paths loaded earlier) are reused so the tree has a single node per path
 fn add_path_to_vfs(
     vfs: &mut VfsState,
     index: usize,
@@ -343,22 +528,20 @@ fn add_path_to_vfs(
     let mut current_index = index;
 
     for c in path.components() {
-        let new_node = Node {
-            node_type: NodeType::Unknown,
-            parent: current_index as _,
-            driver_index: -1,
-            name: get_component_name(&c, &mut prefix).to_string(),
-            ..Default::default()
+        let name = get_component_name(&c, &mut prefix);
+
+        current_index = match find_entry_in_node(&vfs.nodes[current_index], &vfs.nodes, &name, vfs.config.case_insensitive) {
+            Some(entry) => entry,
+            None => add_new_node(vfs, current_index, Node::new_unknown_node(name.into_owned(), current_index as _)),
         };
 
-        current_index = add_new_node(vfs, current_index, new_node);
         count += 1;
     }
 
     (current_index, count)
 }
 
-fn add_files_dirs_to_vfs(vfs: &mut VfsState, components: &[Component], in_index: usize, files_dirs: FilesDirs) -> usize {
+fn add_files_dirs_to_vfs(vfs: &mut VfsState, components: &[Component], in_index: usize, mut files_dirs: FilesDirs) -> usize {
     let mut index = in_index;
     let mut had_prefix = false;
     let mut search_nodes = true;
@@ -368,7 +551,7 @@ fn add_files_dirs_to_vfs(vfs: &mut VfsState, components: &[Component], in_index:
         let node = &vfs.nodes[index];
         let component_name = get_component_name(c, &mut had_prefix);
         if search_nodes {
-            if let Some(entry) = find_entry_in_node(node, &vfs.nodes, &component_name) {
+            if let Some(entry) = find_entry_in_node(node, &vfs.nodes, &component_name, vfs.config.case_insensitive) {
                 index = entry;
                 continue;
             } else {
@@ -381,24 +564,96 @@ fn add_files_dirs_to_vfs(vfs: &mut VfsState, components: &[Component], in_index:
         index = add_new_node(vfs, index, new_node);
     }
 
-    // if the last node isn't empty we assume that it has been filed already
-    if !vfs.nodes[index].nodes.is_empty() {
-        return index;
-    }
+    // Paths below the directory may have been loaded before it was listed. Those nodes are kept (they may have
+    // drivers mounted) and the listing is merged with them
+    let existing = vfs.nodes[index].nodes.clone();
+    let case_insensitive = vfs.config.case_insensitive;
+    let find_existing = |nodes: &[Node], name: &str| {
+        existing.iter().map(|n| *n as usize).find(|n| path_match::names_match(&nodes[*n].name, name, case_insensitive))
+    };
 
+    // Existing nodes get the name from the listing as it may have been loaded with another case
     for name in files_dirs.dirs {
-        let new_node = Node::new_unknown_node(name, index as _);
-        add_new_node(vfs, index, new_node);
+        match find_existing(&vfs.nodes, &name) {
+            Some(entry) => vfs.nodes[entry].name = name,
+            None => {
+                add_new_node(vfs, index, Node::new_unknown_node(name, index as _));
+            }
+        }
     }
 
     for name in files_dirs.files {
-        let new_node = Node::new_file_node(name, index as _);
-        add_new_node(vfs, index, new_node);
+        let info = files_dirs.file_infos.remove(&name);
+
+        match find_existing(&vfs.nodes, &name) {
+            Some(entry) => {
+                let node = &mut vfs.nodes[entry];
+
+                // The file has changed since it was hashed
+                if node.info != info {
+                    node.hashes = None;
+                }
+
+                node.node_type = NodeType::File;
+                node.info = info;
+                node.name = name;
+            }
+            None => {
+                add_new_node(vfs, index, Node::new_listed_file_node(name, index as _, info));
+            }
+        }
     }
 
     index
 }
 
+// Resolve a driver relative path without regard to case by walking the directory listings of the driver.
+// Returns None if any of the components can't be found.
+fn resolve_driver_path(driver: &mut dyn VfsDriver, path: &str, progress: &mut Progress) -> Option<String> {
+    let mut resolved = String::new();
+
+    for name in path_match::split_components(path) {
+        let files_dirs = driver.get_directory_list(&resolved, progress).ok()?;
+        let found = files_dirs
+            .dirs
+            .iter()
+            .chain(files_dirs.files.iter())
+            .find(|n| path_match::names_match(n, name, true))?;
+
+        if !resolved.is_empty() {
+            resolved.push('/');
+        }
+
+        resolved.push_str(found);
+    }
+
+    Some(resolved)
+}
+
+// Load a path from a driver. In case insensitive mode we retry with the path resolved against the listings of
+// the driver if the exact path can't be loaded. The path that was used for loading is returned with the status.
+fn load_driver_url(
+    vfs: &mut VfsState,
+    driver: usize,
+    path: &str,
+    progress: &mut Progress,
+) -> Result<(LoadStatus, String), InternalError> {
+    let res = use_driver(vfs, driver).load_url(path, progress);
+
+    if !vfs.config.case_insensitive || matches!(res, Ok(LoadStatus::Data(_)) | Ok(LoadStatus::Directory)) {
+        return res.map(|status| (status, path.to_owned()));
+    }
+
+    match resolve_driver_path(use_driver(vfs, driver).as_mut(), path, progress) {
+        Some(resolved) if resolved != path => {
+            trace!("Resolved {} to {}", path, resolved);
+            let status = use_driver(vfs, driver).load_url(&resolved, progress)?;
+            Ok((status, resolved))
+        }
+        _ => res.map(|status| (status, path.to_owned())),
+    }
+}
+
 #[derive(Debug, PartialEq)]
 enum LoadState {
     FindNode,
@@ -414,12 +669,22 @@ struct Loader<'a> {
     state: LoadState,
     path_components: Vec<Component<'a>>,
     path_str: String,
+    /// The url starts with a scheme such as ftp://. Checked on the url as given as paths rebuilt from the
+    /// components have the `//` collapsed
+    has_url_scheme: bool,
     component_index: usize,
     node_index: usize,
     driver_index: isize,
     had_prefix: bool,
-    data: Option<Box<[u8]>>,
-    msg: &'a crossbeam_channel::Sender<RecvMsg>,
+    data: Option<Bytes>,
+    /// Range of the progress reported while loading
+    progress_range: (f32, f32),
+    /// If set the loaded data is kept in `captured` instead of being sent back (used for copying)
+    capture: bool,
+    captured: Option<Bytes>,
+    /// Error from the last driver that failed to be created. Reported if no other driver can handle the url
+    driver_error: Option<(&'static str, InternalError)>,
+    msg: &'a HandleSender,
 }
 
 /// Loading of urls works in the following way:
@@ -430,16 +695,21 @@ struct Loader<'a> {
 ///    trying to resolve "foo.zip/test/bar.mod" which should succede in this case.
 ///    We repeat this process until everthing we are done.
 impl<'a> Loader<'a> {
-    fn new(path: &'a str, msg: &'a crossbeam_channel::Sender<RecvMsg>) -> Loader<'a> {
+    fn new(path: &'a str, msg: &'a HandleSender) -> Loader<'a> {
         Loader {
             state: LoadState::FindNode,
             path_components: Path::new(path).components().collect(),
             path_str: path.to_owned(),
+            has_url_scheme: path_match::has_url_scheme(path),
             component_index: 0,
             node_index: 0,
             driver_index: -1,
             had_prefix: false,
             data: None,
+            progress_range: (0.0, 1.0),
+            capture: false,
+            captured: None,
+            driver_error: None,
             msg,
         }
     }
@@ -456,14 +726,11 @@ impl<'a> Loader<'a> {
         for c in components.iter() {
             let node = &vfs.nodes[self.node_index];
             let component_name = get_component_name(c, &mut self.had_prefix);
-            if let Some(entry) = find_entry_in_node(node, &vfs.nodes, &component_name) {
+            if let Some(entry) = find_entry_in_node(node, &vfs.nodes, &component_name, vfs.config.case_insensitive) {
                 let driver_index = vfs.nodes[entry].driver_index;
                 if driver_index != -1 {
-                    if vfs.node_drivers[driver_index as usize].is_remote() {
-                        has_local_parent_driver = false;
-                    } else {
-                        has_local_parent_driver = true;
-                    }
+                    // Mark drivers along the path as used so parents of nested archives aren't evicted
+                    has_local_parent_driver = !use_driver(vfs, driver_index as usize).is_remote();
                     found_driver = true;
                 }
 
@@ -512,7 +779,7 @@ impl<'a> Loader<'a> {
 
         while !current_path.is_empty() {
             for d in &vfs.drivers {
-                if !d.supports_url(&current_path) {
+                if !driver_supports_url(d, &current_path, self.has_url_scheme) {
                     continue;
                 }
 
@@ -522,24 +789,27 @@ impl<'a> Loader<'a> {
                     continue;
                 }
 
-                if let Some(new_driver) = d.create_from_url(&current_path) {
-                    let driver_name = new_driver.name();
-                    // If we found a driver we mount it inside the vfs
-                    self.driver_index = vfs.node_drivers.len() as _;
-                    vfs.node_drivers.push(new_driver);
+                let new_driver = match d.create_from_url(&current_path) {
+                    Ok(new_driver) => new_driver,
+                    Err(e) => {
+                        error!("Unable to create {} at {}: {}", d.name(), current_path, e);
+                        self.driver_error = Some((d.name(), e));
+                        continue;
+                    }
+                };
 
-                    trace!("Creating new driver: {} at {} - comp index {}", driver_name, current_path, self.component_index);
+                trace!("Creating new driver: {} at {} - comp index {}", new_driver.name(), current_path, self.component_index);
 
-                    let res = add_path_to_vfs(vfs, self.node_index, &p);
-                    self.node_index = res.0;
-                    self.component_index += res.1;
+                let res = add_path_to_vfs(vfs, self.node_index, &p);
+                self.node_index = res.0;
+                self.component_index += res.1;
 
-                    vfs.nodes[self.node_index].driver_index = self.driver_index as _;
+                // If we found a driver we mount it inside the vfs
+                self.driver_index = mount_driver(vfs, self.node_index, new_driver) as _;
 
-                    self.state = LoadState::LoadFromDriver;
+                self.state = LoadState::LoadFromDriver;
 
-                    return;
-                }
+                return;
             }
 
             p.pop();
@@ -553,26 +823,36 @@ impl<'a> Loader<'a> {
     // Find a driver given input data at a node. If a driver is found we switch to state LoadFromDriver
     fn find_driver_data(&mut self, vfs: &mut VfsState) -> Result<(), InternalError> {
         let node_data = self.data.as_ref().unwrap();
+        let mut mount_error = None;
 
         for d in &vfs.drivers {
             if !d.can_load_from_data(node_data) {
                 continue;
             }
 
-            // TODO: Fix this clone
             // Found a driver for this data. Updated the node index with the new driver
-            // and switch state to load that from the new driver
-            if let Some(new_driver) = d.create_from_data(node_data.clone()) {
-                self.driver_index = vfs.node_drivers.len() as _;
-
-                vfs.node_drivers.push(new_driver);
-                vfs.nodes[self.node_index].driver_index = self.driver_index as _;
+            // and switch state to load that from the new driver. Cloning only bumps the ref count of the buffer
+            match d.create_from_data(node_data.clone()) {
+                Ok(new_driver) => {
+                    self.driver_index = mount_driver(vfs, self.node_index, new_driver) as _;
 
-                self.state = LoadState::LoadFromDriver;
-                return Ok(());
+                    self.state = LoadState::LoadFromDriver;
+                    return Ok(());
+                }
+                Err(e) => {
+                    error!("Unable to create {} from data: {}", d.name(), e);
+                    mount_error = Some((d.name(), e));
+                }
             }
         }
 
+        // A driver recognized the data but failed to mount it. The rest of the path can't be resolved so
+        // report why instead of sending back the data for the archive
+        if let Some((driver, e)) = mount_error {
+            vfs.active_driver = Some(driver);
+            return Err(e);
+        }
+
         trace!("No driver found, sending data as is {}", node_data.len());
 
         // No driver found data. So we just send it back here
@@ -589,18 +869,18 @@ impl<'a> Loader<'a> {
         let mut p: PathBuf = components.iter().collect();
         let mut current_path: String = p.to_string_lossy().into();
 
-        trace!("Loading from driver {} : {} - type {}", &current_path, self.driver_index, vfs.node_drivers[self.driver_index as usize].name());
+        trace!("Loading from driver {} : {} - type {}", &current_path, self.driver_index, use_driver(vfs, self.driver_index as usize).name());
 
         // walk backwards from the current path and try to load the data
         loop {
             // TODO: Fix range
             let driver = self.driver_index as usize;
-            let mut progress = Progress::new(0.0, 1.0, self.msg);
-            let load_msg = vfs.node_drivers[driver].load_url(&current_path, &mut progress)?;
+            let mut progress = Progress::new(self.progress_range.0, self.progress_range.1, self.msg);
+            let (load_msg, driver_path) = load_driver_url(vfs, driver, &current_path, &mut progress)?;
 
             match load_msg {
                 LoadStatus::Directory => {
-                    return self.add_dir_to_vfs(vfs, self.component_index, &current_path, &mut progress, driver, self.node_index);
+                    return self.add_dir_to_vfs(vfs, self.component_index, &driver_path, &mut progress, driver, self.node_index);
                 }
 
                 LoadStatus::Data(in_data) => {
@@ -631,8 +911,10 @@ impl<'a> Loader<'a> {
             }
         }
 
+        // The driver was found but doesn't have the path
         if current_path.is_empty() && self.state == LoadState::LoadFromDriver {
-            self.state = LoadState::UnsupportedPath;
+            self.msg.send(RecvMsg::NotFound)?;
+            self.state = LoadState::Done;
         }
 
         Ok(())
@@ -670,13 +952,13 @@ impl<'a> Loader<'a> {
                 trace!("loading from driver {} path {}", driver_index, &current_path);
 
                 // construct the path to load from the driver
-                let mut progress = Progress::new(0.0, 1.0, self.msg);
-                let load_msg = vfs.node_drivers[driver_index as usize]
-                    .load_url(&current_path, &mut progress)?;
+                let mut progress = Progress::new(self.progress_range.0, self.progress_range.1, self.msg);
+                let (load_msg, driver_path) =
+                    load_driver_url(vfs, driver_index as usize, &current_path, &mut progress)?;
 
                 match load_msg {
                     LoadStatus::Directory => {
-                        return self.add_dir_to_vfs(vfs, i, &current_path, &mut progress, driver_index as usize, self.node_index);
+                        return self.add_dir_to_vfs(vfs, i, &driver_path, &mut progress, driver_index as usize, self.node_index);
                     }
                     LoadStatus::Data(in_data) => self.send_data(vfs, in_data)?,
                     LoadStatus::NotFound => self.msg.send(RecvMsg::NotFound)?,
@@ -706,7 +988,13 @@ impl<'a> Loader<'a> {
             // If the node type is unknown it means that we haven't fetched the dirs for
             // this node yet, so do that and update the node type
             //if vfs.nodes[node_index].node_type == NodeType::Unknown {
-            let files_dirs = vfs.node_drivers[driver].get_directory_list(current_path, progress)?;
+            let mut files_dirs = use_driver(vfs, driver).get_directory_list(current_path, progress)?;
+
+            if vfs.config.hide_junk {
+                files_dirs.files.retain(|n| !path_match::is_hidden_name(n));
+                files_dirs.dirs.retain(|n| !path_match::is_hidden_name(n));
+            }
+
             node_index = add_files_dirs_to_vfs(vfs, components, node_index, files_dirs);
             vfs.nodes[node_index].node_type = NodeType::Directory;
         }
@@ -724,87 +1012,126 @@ impl<'a> Loader<'a> {
         node_index: usize,
     ) -> Result<(), InternalError> {
         let source_node = &vfs.nodes[node_index];
-        let mut files = Vec::with_capacity(source_node.nodes.len());
-        let mut dirs = Vec::with_capacity(source_node.nodes.len());
+        let mut files_dirs = FilesDirs::new(Vec::with_capacity(source_node.nodes.len()), Vec::new());
 
         for i in &source_node.nodes {
             let node = &vfs.nodes[*i as usize];
             if node.node_type == NodeType::File {
-                files.push(node.name.to_owned())
+                files_dirs.files.push(node.name.to_owned());
+
+                if let Some(info) = &node.info {
+                    files_dirs.file_infos.insert(node.name.to_owned(), info.clone());
+                }
             } else {
-                dirs.push(node.name.to_owned())
+                files_dirs.dirs.push(node.name.to_owned())
             }
         }
 
-        self.msg.send(RecvMsg::Directory(FilesDirs::new(files, dirs)))?;
+        self.msg.send(RecvMsg::Directory(files_dirs))?;
         Ok(())
     }
 
-    fn send_data(&mut self, vfs: &mut VfsState, data: Box<[u8]>) -> Result<(), InternalError> {
+    fn send_data(&mut self, vfs: &mut VfsState, data: Bytes) -> Result<(), InternalError> {
         // check if the cache is full, in that case remove the last entry
         if vfs.cached_data.len() >= MAX_CACHE_COUNT {
             vfs.cached_data.remove(0);
         } 
 
-        let ret_data = Data::new(&data);
+        let hashes = content_hashes(vfs, &self.path_str, &data);
+        let ret_data = Data::with_hashes(data.clone(), hashes);
 
         let cache_entry = CachedDataEntry {
             path: self.path_str.to_owned(),
             data,
+            hashes,
         };
 
         vfs.cached_data.push(cache_entry);
 
-        self.msg.send(RecvMsg::ReadDone(ret_data))?;
+        if self.capture {
+            self.captured = Some(ret_data.into_bytes());
+        } else {
+            self.msg.send(RecvMsg::ReadDone(ret_data))?;
+        }
+
         self.state = LoadState::Done;
 
         Ok(())
     }
 }
 
+// Get the hashes enabled in the config for loaded data. Hashes stored at the node are reused if they were
+// computed from data of the same size
+fn content_hashes(vfs: &mut VfsState, path: &str, data: &[u8]) -> ContentHashes {
+    let (md5, sha1) = (vfs.config.hash_md5, vfs.config.hash_sha1);
+
+    if !md5 && !sha1 {
+        return ContentHashes::default();
+    }
 
+    let node = tree::find_path(vfs, path);
 
-/*
-fn print_tree(state: &VfsState, index: u32, _parent: u32, indent: usize) {
-    let node = &state.nodes[index as usize];
+    if let Some((hashes, size)) = node.and_then(|n| vfs.nodes[n].hashes) {
+        if size == data.len() && hashes.has(md5, sha1) {
+            return hashes;
+        }
+    }
 
-    println!(
-        "{:indent$} {} driver {}",
-        "",
-        node.name,
-        node.driver_index,
-        indent = indent
-    );
+    let hashes = ContentHashes::compute(data, md5, sha1);
 
-    for n in &node.nodes {
-        print_tree(state, *n, node.parent, indent + 1);
+    if let Some(n) = node {
+        vfs.nodes[n].hashes = Some((hashes, data.len()));
     }
-}
-*/
 
+    hashes
+}
 
 pub(crate) fn load(
     vfs: &mut VfsState,
     path: &str,
-    msg: &crossbeam_channel::Sender<RecvMsg>,
+    msg: &HandleSender,
 ) -> Result<(), InternalError> {
-    let mut loader = Loader::new(path, msg);
+    load_with(vfs, path, msg, (0.0, 1.0), false).map(|_| ())
+}
+
+// Loads a url reporting progress in the given range. If capture is set the data is returned instead of being
+// sent back. Directory listings and not found are always sent back.
+fn load_with(
+    vfs: &mut VfsState,
+    path: &str,
+    msg: &HandleSender,
+    progress_range: (f32, f32),
+    capture: bool,
+) -> Result<Option<Bytes>, InternalError> {
+    // Local paths are resolved against the file system up front. Archives and remote paths are resolved
+    // by the drivers when loading
+    let path = if vfs.config.case_insensitive && !path_match::has_url_scheme(path) {
+        path_match::resolve_local_path(path)
+    } else {
+        path.to_owned()
+    };
 
     // first we look in the cache if we have data there and then send that back
     for e in &vfs.cached_data {
         if e.path == path {
             trace!("Sending data for path {} as cached", path);
-            msg.send(RecvMsg::ReadDone(Data::new(&e.data)))?;
-            return Ok(());
+
+            if capture {
+                return Ok(Some(e.data.clone()));
+            }
+
+            msg.send(RecvMsg::ReadDone(Data::with_hashes(e.data.clone(), e.hashes)))?;
+            return Ok(None);
         }
     }
 
     trace!("start processing {}", path);
-    //print_tree(vfs, 0, 0, 0);
 
-    loop {
-        //trace!("{:?}", loader.state);
+    let mut loader = Loader::new(&path, msg);
+    loader.progress_range = progress_range;
+    loader.capture = capture;
 
+    loop {
         match loader.state {
             LoadState::FindNode => loader.find_node(vfs),
             LoadState::FindDriverUrl => loader.find_driver_url(vfs),
@@ -812,18 +1139,156 @@ pub(crate) fn load(
             LoadState::LoadFromDriver => loader.load_from_driver(vfs)?,
             LoadState::LoadFromNode => loader.load_from_node(vfs)?,
             LoadState::Done => break,
-            LoadState::UnsupportedPath => break,
+            LoadState::UnsupportedPath => {
+                return match loader.driver_error.take() {
+                    Some((driver, e)) => {
+                        vfs.active_driver = Some(driver);
+                        Err(e)
+                    }
+                    None => {
+                        vfs.active_driver = None;
+                        Err(InternalError::UnsupportedPath)
+                    }
+                };
+            }
+        }
+    }
+
+    Ok(loader.captured)
+}
+
+// Finds the driver to write a url with. A driver mounted at the closest parent directory is reused. Otherwise
+// the url is walked backwards from the parent directory until a driver can be created for it (as the file and
+// its directories may not exist yet) and the new driver is mounted there. Returns the driver index and the
+// path relative to it.
+fn find_write_driver(vfs: &mut VfsState, path: &Path) -> Result<(usize, String), InternalError> {
+    let has_url_scheme = path_match::has_url_scheme(&path.to_string_lossy());
+    let relative = |dir: &Path| path.strip_prefix(dir).unwrap_or(path).to_string_lossy().into_owned();
+
+    for dir in path.ancestors().skip(1) {
+        let dir_path = dir.to_string_lossy();
+
+        if dir_path.is_empty() {
+            break;
+        }
+
+        if let Some(node) = tree::find_path(vfs, &dir_path) {
+            if node != 0 && vfs.nodes[node].driver_index != -1 {
+                return Ok((vfs.nodes[node].driver_index as usize, relative(dir)));
+            }
+        }
+    }
+
+    for dir in path.ancestors().skip(1) {
+        let dir_path = dir.to_string_lossy();
+
+        if dir_path.is_empty() {
+            break;
         }
+
+        let Some(d) = vfs.drivers.iter().find(|d| driver_supports_url(d, &dir_path, has_url_scheme) && d.can_load_from_url(&dir_path)) else {
+            continue;
+        };
+
+        vfs.active_driver = Some(d.name());
+
+        let driver = d.create_from_url(&dir_path)?;
+        let node = tree::add_path(vfs, &dir_path);
+        let index = mount_driver(vfs, node, driver);
+
+        return Ok((index, relative(dir)));
     }
 
+    Err(InternalError::UnsupportedPath)
+}
+
+// Drivers for local data never handle urls with a scheme (such as http://). has_url_scheme is checked on the
+// original url as the path passed to the driver may have been rebuilt from its components (ftp:/host)
+fn driver_supports_url(driver: &VfsDriverType, path: &str, has_url_scheme: bool) -> bool {
+    (!has_url_scheme || driver.is_remote()) && driver.supports_url(path)
+}
+
+// Writes data to a url with the driver for its parent directory
+fn write(
+    vfs: &mut VfsState,
+    url: &str,
+    data: &[u8],
+    msg: &HandleSender,
+    progress_start: f32,
+) -> Result<(), InternalError> {
+    // Existing directories are matched the same way as when loading so the file ends up next to the others
+    let url = if vfs.config.case_insensitive && !path_match::has_url_scheme(url) {
+        path_match::resolve_local_path(url)
+    } else {
+        url.to_owned()
+    };
+
+    let (driver, file_path) = find_write_driver(vfs, Path::new(&url))?;
+    let mut progress = Progress::new(progress_start, 1.0, msg);
+    let driver = use_driver(vfs, driver);
+
+    trace!("Writing {} with {}", file_path, driver.name());
+
+    driver.write_url(&file_path, data, &mut progress)?;
+    vfs.cached_data.retain(|e| e.path != url);
+
+    // The size and modification time from the listing and the hashes no longer match the file
+    if let Some(index) = tree::find_path(vfs, &url) {
+        vfs.nodes[index].info = None;
+        vfs.nodes[index].hashes = None;
+    }
+
+    tree::add_written_file(vfs, &url);
+
+    msg.send(RecvMsg::WriteDone)?;
     Ok(())
 }
 
-fn handle_msg(vfs: &mut VfsState, _name: &str, msg: &SendMsg) {
+// Copies by loading the source (first half of the progress) and writing it (second half). Errors are reported
+// with the url (source or destination) that failed
+fn copy(vfs: &mut VfsState, src: &str, dst: &str, msg: &HandleSender) {
+    match load_with(vfs, src, msg, (0.0, 0.5), true) {
+        Ok(Some(data)) => {
+            vfs.active_driver = None;
+
+            if let Err(e) = write(vfs, dst, &data, msg, 0.5) {
+                handle_error(vfs, e, dst, msg);
+            }
+        }
+        Ok(None) => (),
+        Err(e) => handle_error(vfs, e, src, msg),
+    }
+}
+
+fn handle_msg(vfs: &mut VfsState, _name: &str, msg: SendMsg) {
+    vfs.active_driver = None;
+
     match msg {
         SendMsg::LoadUrl(path, _node_index, msg) => {
-            if let Err(e) = load(vfs, path, msg) {
-                handle_error(e, msg);
+            if let Err(e) = load(vfs, &path, &msg) {
+                handle_error(vfs, e, &path, &msg);
+            }
+        }
+
+        SendMsg::WriteUrl(url, data, msg) => {
+            if let Err(e) = write(vfs, &url, &data, &msg, 0.0) {
+                handle_error(vfs, e, &url, &msg);
+            }
+        }
+
+        SendMsg::CopyUrl(src, dst, msg) => copy(vfs, &src, &dst, &msg),
+
+        SendMsg::AddDriver(driver) => {
+            trace!("Adding driver {}", driver.name());
+            vfs.drivers.insert(0, driver);
+        }
+
+        SendMsg::Unmount(path) => tree::unmount(vfs, &path),
+        SendMsg::EvictIdle(max_idle) => tree::evict_idle(vfs, max_idle),
+
+        SendMsg::Snapshot(reply) => {
+            if reply.send(tree::snapshot(vfs)).is_err() {
+                error!("evfs: Unable to send snapshot to main thread");
             }
         }
     }
@@ -832,16 +1297,40 @@ fn handle_msg(vfs: &mut VfsState, _name: &str, msg: &SendMsg) {
 impl Vfs {
     //pub fn new(vfs_drivers: Option<&[Box<dyn VfsDriver>]>) -> Vfs {
     pub fn new() -> Vfs {
-        let (main_send, thread_recv) = unbounded::<SendMsg>();
+        Self::new_with_config(VfsConfig::default())
+    }
+
+    pub fn new_with_config(config: VfsConfig) -> Vfs {
+        let (main_send, thread_recv) = crossbeam_channel::unbounded::<SendMsg>();
 
         // Setup worker thread
         thread::Builder::new()
             .name("vfs_worker".to_string())
             .spawn(move || {
-                let mut state = VfsState::new();
+                let idle_timeout = config.driver_idle_timeout;
+                let mut state = VfsState::new(config);
+
+                loop {
+                    // With an idle timeout we wake up periodically to evict unused drivers
+                    let msg = match idle_timeout {
+                        Some(timeout) => match thread_recv.recv_timeout(timeout) {
+                            Ok(msg) => Some(msg),
+                            Err(crossbeam_channel::RecvTimeoutError::Timeout) => None,
+                            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => break,
+                        },
+                        None => match thread_recv.recv() {
+                            Ok(msg) => Some(msg),
+                            Err(_) => break,
+                        },
+                    };
+
+                    if let Some(msg) = msg {
+                        handle_msg(&mut state, "vfs_worker", msg);
+                    }
 
-                while let Ok(msg) = thread_recv.recv() {
-                    handle_msg(&mut state, "vfs_worker", &msg);
+                    if let Some(timeout) = idle_timeout {
+                        tree::evict_idle(&mut state, timeout);
+                    }
                 }
             })
             .unwrap();
@@ -859,6 +1348,7 @@ impl Default for Vfs {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::{Arc, Mutex};
 
     #[test]
     fn vfs_load_zip() {
@@ -1055,64 +1545,1026 @@ mod tests {
     }
 
     #[test]
-    fn ftp_test_file() {
-        let vfs = Vfs::new();
-        let handle = vfs.load_url("ftp://ftp.modland.com/readme_welcome.txt");
+    fn vfs_case_insensitive_names() {
+        assert!(path_match::names_match("MOD.Foo", "mod.foo", true));
+        assert!(!path_match::names_match("MOD.Foo", "mod.foo", false));
+        // decomposed and composed forms of the same name
+        assert!(path_match::names_match("Cafe\u{301}.MOD", "caf\u{e9}.mod", true));
+        assert_eq!(path_match::normalize_separators("foo\\bar/baz.mod"), "foo/bar/baz.mod");
+        assert!(path_match::has_url_scheme("ftp://ftp.modland.com/pub"));
+        assert!(!path_match::has_url_scheme("C:/music/foo.mod"));
+        assert!(!path_match::has_url_scheme("C://music/foo.mod"));
+        assert!(!path_match::has_url_scheme("/music/foo:/bar.mod"));
+    }
+
+    #[test]
+    fn vfs_case_insensitive_nested_zip() {
+        let path = std::fs::canonicalize("data").unwrap();
+        let path = path.join("A.ZIP/Beat.Zip/FOO/6Beat.MOD");
+
+        let vfs = Vfs::new_with_config(VfsConfig { case_insensitive: true, ..Default::default() });
+        let handle = vfs.load_url(&path.to_string_lossy());
 
         for _ in 0..100 {
             if let Ok(RecvMsg::ReadDone(data)) = handle.recv.try_recv() {
-                let welcome = std::str::from_utf8(data.get()).unwrap();
-                assert!(welcome.contains("Welcome to Modland"));
+                assert_eq!(data.get().len(), 88480);
                 return;
             }
 
-            thread::sleep(std::time::Duration::from_millis(22));
+            thread::sleep(std::time::Duration::from_millis(10));
         }
 
         panic!();
     }
 
     #[test]
-    fn ftp_test_large_file() {
-        let vfs = Vfs::new();
-        let handle = vfs.load_url("ftp://ftp.modland.com/allmods.zip");
+    fn vfs_case_insensitive_zip_dir() {
+        let path = std::fs::canonicalize("data").unwrap();
+        let path = path.join("a.zip/BEAT.ZIP/Foo");
 
-        for _ in 0..1000 {
-            match handle.recv.try_recv() {
-                Ok(RecvMsg::Directory(data)) => {
-                    assert!(data.files.iter().any(|v| *v == "allmods.txt"));
-                    return;
-                }
-                Ok(RecvMsg::ReadProgress(_data)) => {
-                    //println!("Progress: {:?}", data);
-                }
-                _ => thread::sleep(std::time::Duration::from_millis(50)),
+        let vfs = Vfs::new_with_config(VfsConfig { case_insensitive: true, ..Default::default() });
+        let handle = vfs.load_url(&path.to_string_lossy());
+
+        for _ in 0..100 {
+            if let Ok(RecvMsg::Directory(data)) = handle.recv.try_recv() {
+                assert_eq!(data.files, vec!["6beat.mod".to_string()]);
+                assert_eq!(data.dirs, vec!["bar".to_string()]);
+                return;
             }
 
+            thread::sleep(std::time::Duration::from_millis(10));
         }
 
         panic!();
     }
 
     #[test]
-    fn ftp_test_directory_1() {
+    fn vfs_case_sensitive_nested_zip() {
+        let path = std::fs::canonicalize("data").unwrap();
+        let path = path.join("a.zip/Beat.Zip/FOO/6Beat.MOD");
+
         let vfs = Vfs::new();
-        let handle = vfs.load_url("ftp://ftp.modland.com");
+
+        match vfs.load_url(&path.to_string_lossy()).wait(Duration::from_secs(5)) {
+            Err(WaitError::NotFound) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    fn load_zip_name(archive: &str, name: &str, size: usize) {
+        let path = std::fs::canonicalize(archive).unwrap();
+
+        let vfs = Vfs::new();
+        let handle = vfs.load_url(&path.to_string_lossy());
+        let mut found_name = false;
 
         for _ in 0..100 {
-            match handle.recv.try_recv() {
-                Ok(RecvMsg::Directory(data)) => {
-                    assert!(data.dirs.iter().any(|v| *v == "pub"));
-                    assert!(data.dirs.iter().any(|v| *v == "incoming"));
-                    return;
-                }
-                _ => thread::sleep(std::time::Duration::from_millis(50)),
+            if let Ok(RecvMsg::Directory(data)) = handle.recv.try_recv() {
+                assert_eq!(data.files, vec![name.to_string()]);
+                found_name = true;
+                break;
             }
 
-            thread::sleep(std::time::Duration::from_millis(50));
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        assert!(found_name);
+
+        // The decoded name has to map back to the entry
+        let handle = vfs.load_url(&path.join(name).to_string_lossy());
+
+        for _ in 0..100 {
+            if let Ok(RecvMsg::ReadDone(data)) = handle.recv.try_recv() {
+                assert_eq!(data.get().len(), size);
+                return;
+            }
+
+            thread::sleep(std::time::Duration::from_millis(10));
         }
 
         panic!();
     }
 
+    #[test]
+    fn vfs_zip_shift_jis_names() {
+        load_zip_name("data/test_dir/sjis_names.zip", "東方.mod", 84);
+    }
+
+    #[test]
+    fn vfs_zip_cp437_names() {
+        load_zip_name("data/test_dir/cp437_names.zip", "Über.mod", 68);
+    }
+
+    fn wait_for_directory(handle: &Handle) -> Vec<String> {
+        for _ in 0..100 {
+            if let Ok(RecvMsg::Directory(data)) = handle.recv.try_recv() {
+                return data.files;
+            }
+
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        panic!();
+    }
+
+    // colliding_names.zip has Über.mod stored both as UTF-8 and as CP437. Both entries have to be reachable
+    #[test]
+    fn vfs_zip_colliding_names() {
+        let path = std::fs::canonicalize("data/test_dir/colliding_names.zip").unwrap();
+        let vfs = Vfs::new_with_config(VfsConfig {
+            archive_name_encoding: Some(encoding::LegacyEncoding::Cp437),
+            ..Default::default()
+        });
+
+        let files = wait_for_directory(&vfs.load_url(&path.to_string_lossy()));
+        assert_eq!(files, ["Über (2).mod", "Über.mod"]);
+
+        assert_eq!(wait_for_data(&vfs.load_url(&path.join("Über.mod").to_string_lossy())), 10);
+        assert_eq!(wait_for_data(&vfs.load_url(&path.join("Über (2).mod").to_string_lossy())), 29);
+    }
+
+    // Entries of data/test_dir/methods.zip. All have the same contents. zip64.txt has its sizes and offset in
+    // zip64 extra fields and the archive has a zip64 end of central directory record
+    const METHOD_ENTRIES: [&str; 7] =
+        ["deflate.txt", "deflate64.txt", "bzip2.txt", "lzma.txt", "zstd.txt", "zip64.txt", "stored.txt"];
+
+    #[test]
+    fn vfs_zip_compression_methods() {
+        let path = std::fs::canonicalize("data/test_dir/methods.zip").unwrap();
+        let expected: String = (0..64).map(|i| format!("retrovert compression test {:04}\n", i)).collect();
+
+        let vfs = Vfs::new();
+
+        for name in METHOD_ENTRIES {
+            let handle = vfs.load_url(&path.join(name).to_string_lossy());
+            let mut found = false;
+
+            for _ in 0..100 {
+                if let Ok(RecvMsg::ReadDone(data)) = handle.recv.try_recv() {
+                    assert_eq!(data.get(), expected.as_bytes(), "{}", name);
+                    found = true;
+                    break;
+                }
+
+                thread::sleep(std::time::Duration::from_millis(10));
+            }
+
+            assert!(found, "{}", name);
+        }
+    }
+
+    fn wait_for_data(handle: &Handle) -> usize {
+        for _ in 0..100 {
+            if let Ok(RecvMsg::ReadDone(data)) = handle.recv.try_recv() {
+                return data.get().len();
+            }
+
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        panic!();
+    }
+
+    #[test]
+    fn vfs_unmount_nested_zip() {
+        let root = std::fs::canonicalize("data").unwrap();
+        let path = root.join("a.zip/beat.zip/foo/6beat.mod");
+
+        let vfs = Vfs::new();
+        assert_eq!(wait_for_data(&vfs.load_url(&path.to_string_lossy())), 88480);
+
+        let snapshot = vfs.snapshot();
+        let names: Vec<&str> = root.iter().map(|c| c.to_str().unwrap()).collect();
+        let a_zip = snapshot.find(&names).unwrap().find(&["a.zip"]).unwrap();
+
+        assert_eq!(a_zip.driver.as_deref(), Some("zip_fs"));
+        assert_eq!(a_zip.find(&["beat.zip"]).unwrap().driver.as_deref(), Some("zip_fs"));
+        assert_eq!(snapshot.driver_count(), 2);
+
+        // The snapshot is meant to be sent to frontends
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(serde_json::from_str::<NodeSnapshot>(&json).unwrap(), snapshot);
+
+        vfs.unmount(&root.join("a.zip").to_string_lossy());
+
+        let snapshot = vfs.snapshot();
+        assert_eq!(snapshot.driver_count(), 0);
+        assert!(snapshot.children.is_empty());
+
+        // Loading again mounts the archives again
+        assert_eq!(wait_for_data(&vfs.load_url(&path.to_string_lossy())), 88480);
+        assert_eq!(vfs.snapshot().driver_count(), 2);
+    }
+
+    #[test]
+    fn vfs_evict_idle_drivers() {
+        let path = std::fs::canonicalize("data/a.zip").unwrap();
+        let path = path.join("beat.zip/foo/6beat.mod");
+
+        let vfs = Vfs::new();
+        wait_for_data(&vfs.load_url(&path.to_string_lossy()));
+
+        vfs.evict_idle(Duration::from_secs(60));
+        assert_eq!(vfs.snapshot().driver_count(), 2);
+
+        vfs.evict_idle(Duration::ZERO);
+        assert_eq!(vfs.snapshot().driver_count(), 0);
+
+        let vfs = Vfs::new_with_config(VfsConfig {
+            driver_idle_timeout: Some(Duration::from_millis(20)),
+            ..Default::default()
+        });
+
+        let path = std::fs::canonicalize("data/beat.zip").unwrap();
+        let path = path.join("foo/6beat.mod");
+
+        assert_eq!(wait_for_data(&vfs.load_url(&path.to_string_lossy())), 88480);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(vfs.snapshot().driver_count(), 0);
+
+        // Cached data is still served after the driver is gone
+        assert_eq!(wait_for_data(&vfs.load_url(&path.to_string_lossy())), 88480);
+    }
+
+    fn list_dir(vfs: &Vfs, path: &Path) -> FilesDirs {
+        let handle = vfs.load_url(&path.to_string_lossy());
+
+        for _ in 0..100 {
+            if let Ok(RecvMsg::Directory(data)) = handle.recv.try_recv() {
+                return data;
+            }
+
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        panic!();
+    }
+
+    // Creates the following tree in a temp directory:
+    // music/a.mod, music/link.mod -> a.mod, music/loop -> ., music/up -> .., music/other -> ../other,
+    // music/broken -> missing
+    // other/b.mod, other/back -> ../music
+    // .hidden, .DS_Store, Thumbs.db, __MACOSX/
+    #[cfg(unix)]
+    fn create_symlink_tree(name: &str) -> PathBuf {
+        use std::os::unix::fs::symlink;
+
+        let root = std::env::temp_dir().join(format!("vfs_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        std::fs::create_dir_all(root.join("music")).unwrap();
+        std::fs::create_dir_all(root.join("other")).unwrap();
+        std::fs::create_dir_all(root.join("__MACOSX")).unwrap();
+        std::fs::write(root.join("music/a.mod"), b"a").unwrap();
+        std::fs::write(root.join("other/b.mod"), b"b").unwrap();
+        std::fs::write(root.join(".hidden"), b"").unwrap();
+        std::fs::write(root.join(".DS_Store"), b"").unwrap();
+        std::fs::write(root.join("Thumbs.db"), b"").unwrap();
+
+        symlink("a.mod", root.join("music/link.mod")).unwrap();
+        symlink(".", root.join("music/loop")).unwrap();
+        symlink("..", root.join("music/up")).unwrap();
+        symlink("../other", root.join("music/other")).unwrap();
+        symlink("missing", root.join("music/broken")).unwrap();
+        symlink("../music", root.join("other/back")).unwrap();
+
+        std::fs::canonicalize(root).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn vfs_symlinks_not_followed() {
+        let root = create_symlink_tree("symlinks_not_followed");
+
+        let vfs = Vfs::new();
+        let data = list_dir(&vfs, &root.join("music"));
+
+        // Linked files are listed, linked directories aren't
+        assert_eq!(data.files, vec!["a.mod".to_string(), "link.mod".to_string()]);
+        assert!(data.dirs.is_empty());
+        assert_eq!(wait_for_data(&vfs.load_url(&root.join("music/link.mod").to_string_lossy())), 1);
+
+        // Paths through links can still be loaded
+        assert_eq!(wait_for_data(&vfs.load_url(&root.join("music/other/b.mod").to_string_lossy())), 1);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn vfs_symlink_loops() {
+        let root = create_symlink_tree("symlink_loops");
+
+        let vfs = Vfs::new_with_config(VfsConfig { follow_symlinks: true, ..Default::default() });
+        let data = list_dir(&vfs, &root.join("music"));
+
+        assert_eq!(data.files, vec!["a.mod".to_string(), "link.mod".to_string()]);
+        assert_eq!(data.dirs, vec!["other".to_string()]);
+
+        // other/back leads back to music which is a parent of music/other
+        let data = list_dir(&vfs, &root.join("music/other"));
+
+        assert_eq!(data.files, vec!["b.mod".to_string()]);
+        assert!(data.dirs.is_empty());
+
+        // Walking the tree the way random mode does must end
+        let mut pending = vec![root.clone()];
+        let mut visited = 0;
+
+        while let Some(dir) = pending.pop() {
+            visited += 1;
+            assert!(visited < 20);
+
+            for d in list_dir(&vfs, &dir).dirs {
+                pending.push(dir.join(d));
+            }
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn vfs_hide_junk() {
+        let root = create_symlink_tree("hide_junk");
+
+        let data = list_dir(&Vfs::new(), &root);
+
+        assert_eq!(data.files, vec![".DS_Store", ".hidden", "Thumbs.db"]);
+        assert_eq!(data.dirs, vec!["__MACOSX", "music", "other"]);
+
+        let vfs = Vfs::new_with_config(VfsConfig { hide_junk: true, ..Default::default() });
+        let data = list_dir(&vfs, &root);
+
+        assert!(data.files.is_empty());
+        assert_eq!(data.dirs, vec!["music", "other"]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn vfs_large_local_file() {
+        let path = std::env::temp_dir().join(format!("vfs_large_file_{}", std::process::id()));
+        let data: Vec<u8> = (0..6 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let vfs = Vfs::new();
+        let handle = vfs.load_url(&path.to_string_lossy());
+        let mut loaded = None;
+
+        for _ in 0..100 {
+            if let Ok(RecvMsg::ReadDone(d)) = handle.recv.try_recv() {
+                loaded = Some(d.into_bytes());
+                break;
+            }
+
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // The data has to stay valid after the vfs has dropped it from the cache
+        let archive = std::fs::canonicalize("data/test_dir/methods.zip").unwrap();
+
+        for name in METHOD_ENTRIES {
+            wait_for_data(&vfs.load_url(&archive.join(name).to_string_lossy()));
+        }
+
+        assert!(loaded.unwrap() == data);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Waits for a write to finish and returns the progress that was reported
+    fn wait_for_write(handle: &Handle) -> Vec<f32> {
+        let mut progress = Vec::new();
+
+        for _ in 0..100 {
+            match handle.recv.try_recv() {
+                Ok(RecvMsg::WriteDone) => return progress,
+                Ok(RecvMsg::ReadProgress(p)) => progress.push(p),
+                Ok(RecvMsg::Error(e)) => panic!("{:?}", e),
+                _ => thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+
+        panic!();
+    }
+
+    #[test]
+    fn vfs_write_url() {
+        let dir = std::env::temp_dir().join(format!("vfs_write_url_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let vfs = Vfs::new();
+        assert!(list_dir(&vfs, &dir).files.is_empty());
+
+        // Missing directories are created
+        let path = dir.join("new/playlist.m3u");
+        let progress = wait_for_write(&vfs.write_url(&path.to_string_lossy(), b"a.mod\n".to_vec()));
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"a.mod\n");
+        assert_eq!(progress.last(), Some(&1.0));
+        assert_eq!(list_dir(&vfs, &dir).dirs, vec!["new".to_string()]);
+
+        // Overwriting updates the cached data
+        assert_eq!(wait_for_data(&vfs.load_url(&path.to_string_lossy())), 6);
+        wait_for_write(&vfs.write_url(&path.to_string_lossy(), b"b.mod\nc.mod\n".to_vec()));
+        assert_eq!(wait_for_data(&vfs.load_url(&path.to_string_lossy())), 12);
+
+        let path = dir.join("config.toml");
+        wait_for_write(&vfs.write_url(&path.to_string_lossy(), b"a = 1".to_vec()));
+        assert_eq!(list_dir(&vfs, &dir).files, vec!["config.toml".to_string()]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn vfs_write_url_reuses_driver() {
+        let dir = std::env::temp_dir().join(format!("vfs_write_reuse_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("Music")).unwrap();
+
+        let vfs = Vfs::new_with_config(VfsConfig { case_insensitive: true, ..Default::default() });
+
+        for name in ["music/a.mod", "MUSIC/b.mod", "music/sub/c.mod"] {
+            wait_for_write(&vfs.write_url(&dir.join(name).to_string_lossy(), b"mod".to_vec()));
+        }
+
+        // All writes used the driver mounted for the first one
+        assert_eq!(vfs.snapshot().driver_count(), 1);
+
+        // Existing directories are matched without regard to case
+        assert_eq!(list_dir(&vfs, &dir).dirs, vec!["Music".to_string()]);
+        assert_eq!(std::fs::read(dir.join("Music/a.mod")).unwrap(), b"mod");
+        assert_eq!(std::fs::read(dir.join("Music/b.mod")).unwrap(), b"mod");
+        assert_eq!(std::fs::read(dir.join("Music/sub/c.mod")).unwrap(), b"mod");
+
+        // No temporary files are left
+        let mut names: Vec<_> = std::fs::read_dir(dir.join("Music")).unwrap().map(|e| e.unwrap().file_name()).collect();
+        names.sort();
+        assert_eq!(names, ["a.mod", "b.mod", "sub"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn vfs_file_infos() {
+        let dir = std::env::temp_dir().join(format!("vfs_file_infos_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.mod"), b"12345").unwrap();
+        std::fs::write(dir.join("sub/b.mod"), b"123").unwrap();
+
+        let vfs = Vfs::new();
+        let listing = list_dir(&vfs, &dir);
+        let info = listing.file_infos["a.mod"].clone();
+
+        assert_eq!(info.size, 5);
+        assert!(!info.modified.is_empty());
+        // Only files have infos
+        assert_eq!(listing.file_infos.len(), 1);
+
+        // Loading a file in a directory that hasn't been listed mounts a driver for it within the same tree
+        assert_eq!(wait_for_data(&vfs.load_url(&dir.join("sub/b.mod").to_string_lossy())), 3);
+        assert_eq!(vfs.snapshot().children.len(), 1);
+        assert_eq!(vfs.snapshot().driver_count(), 2);
+
+        // The listing is kept by the vfs so it's served from the tree the next time
+        std::fs::write(dir.join("a.mod"), b"123456").unwrap();
+        assert_eq!(list_dir(&vfs, &dir).file_infos["a.mod"], info);
+
+        // Unmounting drops it
+        vfs.unmount(&dir.to_string_lossy());
+        assert_eq!(vfs.snapshot().driver_count(), 0);
+        assert_eq!(list_dir(&vfs, &dir).file_infos["a.mod"].size, 6);
+
+        // Written files have no info until listed again
+        wait_for_write(&vfs.write_url(&dir.join("a.mod").to_string_lossy(), b"1".to_vec()));
+        assert!(list_dir(&vfs, &dir).file_infos.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn vfs_copy_url() {
+        let dir = std::env::temp_dir().join(format!("vfs_copy_url_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let src = std::fs::canonicalize("data/a.zip").unwrap().join("beat.zip/foo/6beat.mod");
+        let dst = dir.join("6beat.mod");
+
+        let vfs = Vfs::new();
+        let progress = wait_for_write(&vfs.copy_url(&src.to_string_lossy(), &dst.to_string_lossy()));
+
+        assert_eq!(std::fs::metadata(&dst).unwrap().len(), 88480);
+        // Loading reports the first half of the progress and writing the second
+        assert!(progress.iter().any(|p| *p <= 0.5));
+        assert!(progress.iter().any(|p| *p > 0.5 && *p < 1.0));
+        assert_eq!(progress.last(), Some(&1.0));
+
+        // Archives are read-only
+        let dst = std::fs::canonicalize("data/a.zip").unwrap().join("6beat.mod");
+        let handle = vfs.copy_url(&src.to_string_lossy(), &dst.to_string_lossy());
+        let mut got_error = false;
+
+        for _ in 0..100 {
+            if let Ok(RecvMsg::Error(_)) = handle.recv.try_recv() {
+                got_error = true;
+                break;
+            }
+
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        assert!(got_error);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn wait_for_error(handle: &Handle) -> VfsError {
+        for _ in 0..500 {
+            match handle.recv.try_recv() {
+                Ok(RecvMsg::Error(e)) => return e,
+                Ok(RecvMsg::ReadDone(_)) | Ok(RecvMsg::WriteDone) | Ok(RecvMsg::Directory(_)) => panic!("expected an error"),
+                _ => thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+
+        panic!();
+    }
+
+    #[test]
+    fn vfs_error_local_fs() {
+        let path = std::fs::canonicalize("data").unwrap().join("missing.mod");
+        let url = path.to_string_lossy();
+
+        let e = wait_for_error(&Vfs::new().load_url(&url));
+
+        assert_eq!(e.url, url);
+        assert_eq!(e.driver, Some("local_fs"));
+        assert!(matches!(e.cause, InternalError::FileError(ref e) if e.kind() == std::io::ErrorKind::NotFound));
+
+        // Writing below a file fails when creating the directory
+        let path = std::fs::canonicalize("Cargo.toml").unwrap().join("foo/bar.mod");
+        let e = wait_for_error(&Vfs::new().write_url(&path.to_string_lossy(), b"".to_vec()));
+
+        assert_eq!(e.driver, Some("local_fs"));
+        assert!(matches!(e.cause, InternalError::FileError(_)));
+    }
+
+    #[test]
+    fn vfs_error_zip_fs() {
+        let path = std::fs::canonicalize("data/test_dir/corrupt.zip").unwrap().join("broken.mod");
+        let url = path.to_string_lossy();
+
+        let e = wait_for_error(&Vfs::new().load_url(&url));
+
+        assert_eq!(e.url, url);
+        assert_eq!(e.driver, Some("zip_fs"));
+        assert!(matches!(e.cause, InternalError::FileError(_) | InternalError::ZipError(_)));
+
+        // Archives can't be written to
+        let path = std::fs::canonicalize("data/a.zip").unwrap().join("new.mod");
+        let e = wait_for_error(&Vfs::new().write_url(&path.to_string_lossy(), b"".to_vec()));
+
+        assert_eq!(e.driver, Some("zip_fs"));
+        assert!(matches!(e.cause, InternalError::FileError(ref e) if e.kind() == std::io::ErrorKind::Unsupported));
+    }
+
+    #[test]
+    fn vfs_error_ftp_fs_connect() {
+        // Nothing is listening on port 1
+        let e = wait_for_error(&Vfs::new().load_url("ftp://127.0.0.1:1/foo.mod"));
+
+        assert_eq!(e.url, "ftp://127.0.0.1:1/foo.mod");
+        assert_eq!(e.driver, Some("ftp_fs"));
+        assert!(matches!(e.cause, InternalError::FtpError(_)));
+    }
+
+    #[test]
+    fn vfs_error_unsupported_path() {
+        let e = wait_for_error(&Vfs::new().load_url("http://example.com/foo.mod"));
+
+        assert_eq!(e.driver, None);
+        assert!(matches!(e.cause, InternalError::UnsupportedPath));
+        assert_eq!(e.to_string(), "http://example.com/foo.mod (vfs): Unsupported path");
+    }
+
+    // Local driver that claims every url and records the urls it's asked to load from
+    #[derive(Debug, Default)]
+    struct ClaimAllDriver {
+        urls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl VfsDriver for ClaimAllDriver {
+        fn is_remote(&self) -> bool {
+            false
+        }
+
+        fn name(&self) -> &'static str {
+            "claim_all"
+        }
+
+        fn supports_url(&self, _url: &str) -> bool {
+            true
+        }
+
+        fn create_instance(&self) -> VfsDriverType {
+            Box::new(ClaimAllDriver { urls: self.urls.clone() })
+        }
+
+        fn can_load_from_data(&self, _data: &[u8]) -> bool {
+            false
+        }
+
+        fn create_from_data(&self, _data: Bytes) -> Result<VfsDriverType, InternalError> {
+            Err(InternalError::UnsupportedPath)
+        }
+
+        fn can_load_from_url(&self, url: &str) -> bool {
+            self.urls.lock().unwrap().push(url.to_owned());
+            false
+        }
+
+        fn create_from_url(&self, _url: &str) -> Result<VfsDriverType, InternalError> {
+            Err(InternalError::UnsupportedPath)
+        }
+
+        fn load_url(&mut self, _path: &str, _progress: &mut Progress) -> Result<LoadStatus, InternalError> {
+            Ok(LoadStatus::NotFound)
+        }
+
+        fn get_directory_list(&mut self, _path: &str, _progress: &mut Progress) -> Result<FilesDirs, InternalError> {
+            Ok(FilesDirs::default())
+        }
+    }
+
+    #[test]
+    fn vfs_scheme_urls_skip_local_drivers() {
+        let vfs = Vfs::new();
+        let driver = ClaimAllDriver::default();
+        let urls = driver.urls.clone();
+        vfs.add_driver(Box::new(driver));
+
+        let e = wait_for_error(&vfs.load_url("http://example.com/music/foo.mod"));
+        assert!(matches!(e.cause, InternalError::UnsupportedPath));
+        assert_eq!(e.driver, None);
+
+        let e = wait_for_error(&vfs.write_url("http://example.com/music/foo.mod", b"".to_vec()));
+        assert!(matches!(e.cause, InternalError::UnsupportedPath));
+
+        // Neither the local driver nor local_fs were asked to load from the url
+        assert!(urls.lock().unwrap().is_empty());
+        assert!(!local_fs::LocalFs::new(false).supports_url("http://example.com/music"));
+
+        // Paths without a scheme still go to the local drivers
+        let _ = wait_for_error(&vfs.load_url("/no/such/dir/foo.mod"));
+        assert!(!urls.lock().unwrap().is_empty());
+    }
+
+    // Recognizes data starting with BROKEN but always fails to mount it
+    #[derive(Debug)]
+    struct BrokenDriver;
+
+    impl VfsDriver for BrokenDriver {
+        fn is_remote(&self) -> bool {
+            false
+        }
+
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+
+        fn supports_url(&self, _url: &str) -> bool {
+            false
+        }
+
+        fn create_instance(&self) -> VfsDriverType {
+            Box::new(BrokenDriver)
+        }
+
+        fn can_load_from_data(&self, data: &[u8]) -> bool {
+            data.starts_with(b"BROKEN")
+        }
+
+        fn create_from_data(&self, _data: Bytes) -> Result<VfsDriverType, InternalError> {
+            Err(InternalError::DriverError("bad header".into()))
+        }
+
+        fn can_load_from_url(&self, _url: &str) -> bool {
+            false
+        }
+
+        fn create_from_url(&self, _url: &str) -> Result<VfsDriverType, InternalError> {
+            Err(InternalError::UnsupportedPath)
+        }
+
+        fn load_url(&mut self, _path: &str, _progress: &mut Progress) -> Result<LoadStatus, InternalError> {
+            Ok(LoadStatus::NotFound)
+        }
+
+        fn get_directory_list(&mut self, _path: &str, _progress: &mut Progress) -> Result<FilesDirs, InternalError> {
+            Ok(FilesDirs::default())
+        }
+    }
+
+    #[test]
+    fn vfs_error_mount_from_data() {
+        let path = std::env::temp_dir().join(format!("vfs_error_mount_{}.zip", std::process::id()));
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("pack.brk", options).unwrap();
+        std::io::Write::write_all(&mut zip, b"BROKEN pack").unwrap();
+        zip.finish().unwrap();
+
+        let vfs = Vfs::new();
+        vfs.add_driver(Box::new(BrokenDriver));
+
+        let url = path.join("pack.brk/song.mod").to_string_lossy().into_owned();
+        let e = wait_for_error(&vfs.load_url(&url));
+
+        assert_eq!(e.url, url);
+        assert_eq!(e.driver, Some("broken"));
+        assert!(matches!(e.cause, InternalError::DriverError(_)));
+
+        // The data itself can still be loaded
+        assert_eq!(wait_for_data(&vfs.load_url(&path.join("pack.brk").to_string_lossy())), 11);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn vfs_not_found_in_archive() {
+        let path = std::fs::canonicalize("data/a.zip").unwrap().join("missing.mod");
+        let handle = Vfs::new().load_url(&path.to_string_lossy());
+
+        for _ in 0..100 {
+            match handle.recv.try_recv() {
+                Ok(RecvMsg::NotFound) => return,
+                Ok(RecvMsg::ReadProgress(_)) | Err(_) => thread::sleep(std::time::Duration::from_millis(10)),
+                _ => panic!("expected not found"),
+            }
+        }
+
+        panic!();
+    }
+
+    #[test]
+    fn vfs_handle_wait() {
+        let vfs = Vfs::new();
+        let timeout = Duration::from_secs(5);
+
+        let path = std::fs::canonicalize("data/a.zip").unwrap().join("beat.zip/foo/6beat.mod");
+        let mut progress = Vec::new();
+
+        match vfs.load_url(&path.to_string_lossy()).wait_with_progress(timeout, |p| progress.push(p)) {
+            Ok(Response::Data(data)) => assert_eq!(data.get().len(), 88480),
+            r => panic!("{:?}", r),
+        }
+
+        assert!(!progress.is_empty());
+
+        let path = std::fs::canonicalize("data").unwrap();
+
+        match vfs.load_url(&path.to_string_lossy()).wait(timeout) {
+            Ok(Response::Directory(files_dirs)) => assert_eq!(files_dirs.dirs, vec!["test_dir".to_string()]),
+            r => panic!("{:?}", r),
+        }
+
+        let path = std::fs::canonicalize("data/a.zip").unwrap().join("missing.mod");
+        assert!(matches!(vfs.load_url(&path.to_string_lossy()).wait(timeout), Err(WaitError::NotFound)));
+
+        match vfs.load_url("http://example.com/foo.mod").wait(timeout) {
+            Err(WaitError::Vfs(e)) => assert!(matches!(e.cause, InternalError::UnsupportedPath)),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn vfs_handle_await() {
+        use std::future::Future;
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake};
+
+        struct ThreadWaker(thread::Thread);
+
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        // Minimal executor that parks the thread until the vfs wakes it
+        fn block_on<F: Future>(future: F) -> F::Output {
+            let mut future = std::pin::pin!(future);
+            let waker = Arc::new(ThreadWaker(thread::current())).into();
+            let mut cx = Context::from_waker(&waker);
+
+            loop {
+                match future.as_mut().poll(&mut cx) {
+                    Poll::Ready(res) => return res,
+                    Poll::Pending => thread::park(),
+                }
+            }
+        }
+
+        let vfs = Vfs::new();
+        let path = std::fs::canonicalize("data/a.zip").unwrap().join("beat.zip/foo/6beat.mod");
+
+        let res = block_on(async { vfs.load_url(&path.to_string_lossy()).await });
+
+        match res {
+            Ok(Response::Data(data)) => assert_eq!(data.get().len(), 88480),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn legacy_encoding_round_trip() {
+        use encoding::LegacyEncoding;
+
+        let texts = [
+            ("Über Ärger", LegacyEncoding::Cp437),
+            ("Ça été", LegacyEncoding::Latin1),
+            ("東方紅魔郷 ～ the Embodiment", LegacyEncoding::ShiftJis),
+        ];
+
+        for (text, enc) in texts {
+            let data = encoding::encode(text, enc).unwrap();
+            assert_eq!(encoding::detect(&data), enc);
+            assert_eq!(encoding::decode(&data, enc), text);
+        }
+
+        assert!(encoding::encode("東方", LegacyEncoding::Latin1).is_none());
+    }
+
+    #[test]
+    fn ftp_test_file() {
+        let vfs = Vfs::new();
+        let handle = vfs.load_url("ftp://ftp.modland.com/readme_welcome.txt");
+
+        for _ in 0..100 {
+            if let Ok(RecvMsg::ReadDone(data)) = handle.recv.try_recv() {
+                let welcome = std::str::from_utf8(data.get()).unwrap();
+                assert!(welcome.contains("Welcome to Modland"));
+                return;
+            }
+
+            thread::sleep(std::time::Duration::from_millis(22));
+        }
+
+        panic!();
+    }
+
+    #[test]
+    fn ftp_test_large_file() {
+        let vfs = Vfs::new();
+        let handle = vfs.load_url("ftp://ftp.modland.com/allmods.zip");
+
+        for _ in 0..1000 {
+            match handle.recv.try_recv() {
+                Ok(RecvMsg::Directory(data)) => {
+                    assert!(data.files.iter().any(|v| *v == "allmods.txt"));
+                    return;
+                }
+                Ok(RecvMsg::ReadProgress(_data)) => {
+                    //println!("Progress: {:?}", data);
+                }
+                _ => thread::sleep(std::time::Duration::from_millis(50)),
+            }
+
+        }
+
+        panic!();
+    }
+
+    #[test]
+    fn ftp_test_directory_1() {
+        let vfs = Vfs::new();
+        let handle = vfs.load_url("ftp://ftp.modland.com");
+
+        for _ in 0..100 {
+            match handle.recv.try_recv() {
+                Ok(RecvMsg::Directory(data)) => {
+                    assert!(data.dirs.iter().any(|v| *v == "pub"));
+                    assert!(data.dirs.iter().any(|v| *v == "incoming"));
+                    return;
+                }
+                _ => thread::sleep(std::time::Duration::from_millis(50)),
+            }
+
+            thread::sleep(std::time::Duration::from_millis(50));
+        }
+
+        panic!();
+    }
+
+    #[test]
+    fn vfs_content_hashes() {
+        let timeout = Duration::from_secs(5);
+        let path = std::fs::canonicalize("data/a.zip").unwrap().join("beat.zip/foo/6beat.mod");
+        let path = path.to_string_lossy();
+
+        let load_hashes = |vfs: &Vfs| match vfs.load_url(&path).wait(timeout) {
+            Ok(Response::Data(data)) => *data.hashes(),
+            r => panic!("{:?}", r),
+        };
+
+        assert_eq!(load_hashes(&Vfs::new()), ContentHashes::default());
+
+        let vfs = Vfs::new_with_config(VfsConfig {
+            hash_md5: true,
+            hash_sha1: true,
+            ..Default::default()
+        });
+
+        let hashes = load_hashes(&vfs);
+        assert_eq!(hashes.md5_hex().as_deref(), Some("1b0d06d04ec173257bd1f8c72d213606"));
+        assert_eq!(hashes.sha1_hex().as_deref(), Some("fa690e2dcdcdd40eaec80d060c29d070fb29c687"));
+
+        // from the cache
+        assert_eq!(load_hashes(&vfs), hashes);
+
+        // from the node once the data has been dropped from the cache
+        let archive = std::fs::canonicalize("data/test_dir/methods.zip").unwrap();
+
+        for name in METHOD_ENTRIES {
+            wait_for_data(&vfs.load_url(&archive.join(name).to_string_lossy()));
+        }
+
+        assert_eq!(load_hashes(&vfs), hashes);
+    }
+
+    #[test]
+    fn vfs_content_hashes_cached_per_node() {
+        let timeout = Duration::from_secs(5);
+        let dir = std::env::temp_dir().join(format!("vfs_hashes_cached_{}", std::process::id()));
+        let path = dir.join("song.mod");
+        let url = path.to_string_lossy().into_owned();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let vfs = Vfs::new_with_config(VfsConfig { hash_md5: true, ..Default::default() });
+
+        let load_md5 = || match vfs.load_url(&url).wait(timeout) {
+            Ok(Response::Data(data)) => data.hashes().md5_hex().unwrap(),
+            r => panic!("{:?}", r),
+        };
+
+        let archive = std::fs::canonicalize("data/test_dir/methods.zip").unwrap();
+        let drop_cached_data = || {
+            for name in METHOD_ENTRIES {
+                wait_for_data(&vfs.load_url(&archive.join(name).to_string_lossy()));
+            }
+        };
+
+        std::fs::write(&path, b"first").unwrap();
+        assert_eq!(load_md5(), "8b04d5e3775d298e78455efc5ca404d5");
+
+        // Same size but other contents. The file is loaded by the driver again, but as nothing tells the vfs it has
+        // changed the hashes at the node are used instead of hashing the data again
+        std::fs::write(&path, b"FIRST").unwrap();
+        drop_cached_data();
+        assert_eq!(load_md5(), "8b04d5e3775d298e78455efc5ca404d5");
+
+        // The listing gives the node another size and modification time so the file is hashed again
+        list_dir(&vfs, &dir);
+        drop_cached_data();
+        assert_eq!(load_md5(), "2c2624a5059934a947d6e25fe8332ade");
+
+        // Written through the vfs
+        wait_for_write(&vfs.write_url(&url, b"third".to_vec()));
+        assert_eq!(load_md5(), "dd5c8bf51558ffcbe5007071908e9524");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn vfs_content_hashes_rewritten_file() {
+        let timeout = Duration::from_secs(5);
+        let path = std::env::temp_dir().join(format!("vfs_hashes_rewritten_{}.mod", std::process::id()));
+        let url = path.to_string_lossy().into_owned();
+
+        let vfs = Vfs::new_with_config(VfsConfig { hash_md5: true, ..Default::default() });
+
+        let load_md5 = || match vfs.load_url(&url).wait(timeout) {
+            Ok(Response::Data(data)) => data.hashes().md5_hex().unwrap(),
+            r => panic!("{:?}", r),
+        };
+
+        std::fs::write(&path, b"first").unwrap();
+        assert_eq!(load_md5(), "8b04d5e3775d298e78455efc5ca404d5");
+
+        // Rewritten outside of the vfs and reloaded by the driver once dropped from the cache. The size differs so
+        // the hashes at the node aren't used
+        std::fs::write(&path, b"second").unwrap();
+        let archive = std::fs::canonicalize("data/test_dir/methods.zip").unwrap();
+
+        for name in METHOD_ENTRIES {
+            wait_for_data(&vfs.load_url(&archive.join(name).to_string_lossy()));
+        }
+
+        assert_eq!(load_md5(), "a9f0e61a137d86aa9db53465e0801612");
+
+        std::fs::remove_file(path).unwrap();
+    }
 }
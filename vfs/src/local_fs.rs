@@ -1,5 +1,5 @@
-use crate::{InternalError, LoadStatus, Progress, VfsDriver, VfsDriverType, FilesDirs};
-use std::{fs::File, io::Read, path::PathBuf};
+use crate::{Bytes, FileInfo, InternalError, LoadStatus, Progress, VfsDriver, VfsDriverType, FilesDirs};
+use std::{fs::File, io::{Read, Write}, path::{Path, PathBuf}, time::UNIX_EPOCH};
 use walkdir::WalkDir;
 
 #[cfg(not(test))]
@@ -8,16 +8,61 @@ use log::{trace};
 #[cfg(test)]
 use std::{println as trace};
 
+// Files of at least this size are memory mapped instead of read
+const MAP_MIN_SIZE: usize = 5 * 1024 * 1024;
+
 #[derive(Debug)]
 pub struct LocalFs {
     pub root: PathBuf,
+    /// If symlinked directories should be included in directory listings. See [`crate::VfsConfig::follow_symlinks`]
+    follow_symlinks: bool,
 }
 
 impl LocalFs {
-    pub fn new() -> LocalFs {
+    pub fn new(follow_symlinks: bool) -> LocalFs {
         LocalFs {
             root: PathBuf::new(),
+            follow_symlinks,
+        }
+    }
+
+    // Returns true if following the symlink would lead back to the directory being listed or any of its parents.
+    // The logical path is used (not the canonical one) as the directory may itself have been reached through
+    // symlinks and those have to be checked as well.
+    fn is_symlink_loop(dir: &Path, target: &Path, ancestors: &mut Option<Vec<PathBuf>>) -> bool {
+        let ancestors = ancestors.get_or_insert_with(|| {
+            dir.ancestors().filter_map(|p| std::fs::canonicalize(p).ok()).collect()
+        });
+
+        ancestors.iter().any(|p| p == target)
+    }
+
+    fn write_file(path: &Path, data: &[u8], progress: &mut Progress) -> Result<(), InternalError> {
+        let mut file = File::create(path)?;
+
+        // Write in 10 chunks so we get some progress for larger files
+        let loop_count = 10;
+        let block_len = usize::max(data.len() / loop_count, 1);
+        progress.set_step(data.len().div_ceil(block_len));
+
+        for block in data.chunks(block_len) {
+            file.write_all(block)?;
+            progress.step()?;
         }
+
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn file_info(metadata: &std::fs::Metadata) -> FileInfo {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| format!("{}.{:09}", d.as_secs(), d.subsec_nanos()))
+            .unwrap_or_default();
+
+        FileInfo { size: metadata.len(), modified }
     }
 }
 
@@ -34,7 +79,7 @@ impl VfsDriver for LocalFs {
     fn supports_url(&self, path: &str) -> bool {
         trace!("supports_url: {}", path);
         // we don't support url style paths like ftp:// http:// etc.
-        !path.contains(":/")
+        !crate::path_match::has_url_scheme(path)
     }
     // Get some data in and returns true if driver can be mounted from it
     fn can_load_from_url(&self, url: &str) -> bool {
@@ -49,17 +94,17 @@ impl VfsDriver for LocalFs {
     }
 
     fn create_instance(&self) -> VfsDriverType {
-        Box::new(LocalFs { root: "".into() })
+        Box::new(LocalFs::new(self.follow_symlinks))
     }
 
-    fn create_from_url(&self, path: &str) -> Option<VfsDriverType> {
+    fn create_from_url(&self, path: &str) -> Result<VfsDriverType, InternalError> {
         trace!("Created driver at {}", path);
-        Some(Box::new(LocalFs { root: path.into() }))
+        Ok(Box::new(LocalFs { root: path.into(), follow_symlinks: self.follow_symlinks }))
     }
 
-    // As above function will always return true this will never be called
-    fn create_from_data(&self, _data: Box<[u8]>) -> Option<VfsDriverType> {
-        None
+    // As can_load_from_data always returns false this will never be called
+    fn create_from_data(&self, _data: Bytes) -> Result<VfsDriverType, InternalError> {
+        Err(InternalError::DriverError("local_fs can't be created from data".into()))
     }
 
     /// Read a file from the local filesystem.
@@ -87,8 +132,21 @@ impl VfsDriver for LocalFs {
         let len = metadata.len() as usize;
         let mut file = File::open(&path)?;
 
+        // Large files are mapped so they don't use any memory until read and isn't copied when delivered
+        if len >= MAP_MIN_SIZE {
+            match crate::map_file(&file) {
+                Ok(data) => {
+                    progress.set_step(1);
+                    progress.step()?;
+                    trace!("load_url: Mapped file {:?}", path);
+                    return Ok(LoadStatus::Data(data));
+                }
+                Err(e) => trace!("load_url: Unable to map {:?}: {:?}, reading it instead", path, e),
+            }
+        }
+
         // if file is small than 5 meg we just load it fully directly to memory
-        if len < 5 * 1024 * 1024 {
+        if len < MAP_MIN_SIZE {
             progress.set_step(1);
             file.read_to_end(&mut output_data)?;
             progress.step()?;
@@ -109,7 +167,7 @@ impl VfsDriver for LocalFs {
 
         trace!("load_url: Loaded file {:?} to memory", path);
 
-        Ok(LoadStatus::Data(output_data.into_boxed_slice()))
+        Ok(LoadStatus::Data(output_data.into()))
     }
 
     fn get_directory_list(
@@ -118,31 +176,88 @@ impl VfsDriver for LocalFs {
         progress: &mut Progress,
     ) -> Result<FilesDirs, InternalError> {
         progress.set_step(1);
-        let mut dirs = Vec::with_capacity(256);
-        let mut files = Vec::with_capacity(256);
+        let mut files_dirs = FilesDirs::new(Vec::with_capacity(256), Vec::with_capacity(256));
 
-        trace!("Getting directory listing for {:?}", &self.root.join(path));
+        let dir = self.root.join(path);
+        let mut ancestors = None;
+
+        trace!("Getting directory listing for {:?}", &dir);
 
         // skip 1 skips the inital directory as it's included otherwise
-        for e in WalkDir::new(self.root.join(path)).max_depth(1).into_iter().skip(1) {
+        for e in WalkDir::new(&dir).max_depth(1).into_iter().skip(1) {
             let file = e?;
-            let metadata = file.metadata()?;
+
+            let metadata = if !file.path_is_symlink() {
+                file.metadata()?
+            } else {
+                // Broken links are skipped
+                let Ok(metadata) = std::fs::metadata(file.path()) else {
+                    trace!("Skipping broken symlink {:?}", file.path());
+                    continue;
+                };
+
+                if metadata.is_dir() && !self.follow_symlinks {
+                    trace!("Skipping symlinked directory {:?}", file.path());
+                    continue;
+                }
+
+                if metadata.is_dir() {
+                    let target = std::fs::canonicalize(file.path())?;
+
+                    if Self::is_symlink_loop(&dir, &target, &mut ancestors) {
+                        trace!("Skipping symlink {:?} as it loops back to {:?}", file.path(), target);
+                        continue;
+                    }
+                }
+
+                metadata
+            };
 
             if let Some(filename) = file.path().file_name() {
-                let name = filename.to_string_lossy().into();
+                let name: String = filename.to_string_lossy().into();
                 if metadata.is_file() {
-                    files.push(name);
+                    files_dirs.file_infos.insert(name.clone(), Self::file_info(&metadata));
+                    files_dirs.files.push(name);
                 } else {
-                    dirs.push(name);
+                    files_dirs.dirs.push(name);
                 }
             }
         }
 
         progress.step()?;
 
-        dirs.sort();
-        files.sort();
+        files_dirs.dirs.sort();
+        files_dirs.files.sort();
+
+        Ok(files_dirs)
+    }
+
+    /// Write a file to the local filesystem. Missing directories are created. The data is first written to a
+    /// temporary file next to the target which then replaces it, so a failed write never leaves a partial file
+    fn write_url(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        progress: &mut Progress,
+    ) -> Result<(), InternalError> {
+        let path = self.root.join(path);
+
+        trace!("write_url: writing {} bytes to {:?}", data.len(), path);
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp_path = path.with_file_name(temp_name);
+
+        let res = Self::write_file(&temp_path, data, progress).and_then(|_| Ok(std::fs::rename(&temp_path, &path)?));
+
+        if res.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
 
-        Ok(FilesDirs::new(files, dirs))
+        res
     }
 }
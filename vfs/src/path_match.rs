@@ -0,0 +1,114 @@
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf};
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+/// Converts all `\` separators to `/`. Archives and remote file systems always use `/` internally
+/// while paths coming from DOS/Windows style playlists may use either.
+pub(crate) fn normalize_separators(path: &str) -> Cow<'_, str> {
+    if path.contains('\\') {
+        path.replace('\\', "/").into()
+    } else {
+        path.into()
+    }
+}
+
+/// Returns the name in the form used for comparisons. In case insensitive mode the name is converted to
+/// Unicode NFC and lowercased so `MOD.Foo`, `mod.foo` and decomposed/composed accents all compare equal.
+pub(crate) fn normalize_name(name: &str, case_insensitive: bool) -> Cow<'_, str> {
+    if !case_insensitive {
+        return name.into();
+    }
+
+    let is_nfc = is_nfc_quick(name.chars()) == IsNormalized::Yes;
+
+    if is_nfc && !name.chars().any(char::is_uppercase) {
+        name.into()
+    } else if is_nfc {
+        name.to_lowercase().into()
+    } else {
+        name.nfc().collect::<String>().to_lowercase().into()
+    }
+}
+
+/// Compare two path components given the lookup mode
+pub(crate) fn names_match(a: &str, b: &str, case_insensitive: bool) -> bool {
+    if a == b {
+        return true;
+    }
+
+    case_insensitive && normalize_name(a, true) == normalize_name(b, true)
+}
+
+/// Returns true for dotfiles and files created by operating systems (such as `.DS_Store`, `Thumbs.db` and
+/// `__MACOSX` in zips from macOS) that shouldn't be shown in listings.
+pub(crate) fn is_hidden_name(name: &str) -> bool {
+    const JUNK_NAMES: [&str; 3] = ["thumbs.db", "desktop.ini", "__macosx"];
+
+    name.starts_with('.') || JUNK_NAMES.iter().any(|n| name.eq_ignore_ascii_case(n))
+}
+
+/// Splits a driver relative path into its components. Both `/` and `\` are accepted as separators.
+pub(crate) fn split_components(path: &str) -> impl Iterator<Item = &str> {
+    path.split(['/', '\\']).filter(|c| !c.is_empty())
+}
+
+/// Returns true if the path starts with a url scheme such as `ftp://`. Windows drive letters (`C:/music`)
+/// aren't schemes as they are a single letter and aren't followed by `//`.
+pub(crate) fn has_url_scheme(path: &str) -> bool {
+    match path.find("://") {
+        Some(pos) if pos > 1 => {
+            let scheme = &path[..pos];
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        }
+        _ => false,
+    }
+}
+
+/// Resolves a local path against the file system without regard to case. Each component is first tried as is
+/// and if it doesn't exist the parent directory is searched for a matching entry. Once a component can't be
+/// found the remaining components are kept as is as they may refer to data inside an archive.
+pub(crate) fn resolve_local_path(path: &str) -> String {
+    let path = normalize_separators(path);
+    let mut resolved = PathBuf::new();
+    let mut components = Path::new(path.as_ref()).components();
+
+    for c in components.by_ref() {
+        let name = match c {
+            Component::Normal(name) => name,
+            _ => {
+                resolved.push(c);
+                continue;
+            }
+        };
+
+        let exact = resolved.join(name);
+
+        if std::fs::symlink_metadata(&exact).is_ok() {
+            resolved = exact;
+            continue;
+        }
+
+        let name = name.to_string_lossy();
+        let dir = if resolved.as_os_str().is_empty() { Path::new(".") } else { resolved.as_path() };
+        let found = std::fs::read_dir(dir).ok().and_then(|entries| {
+            entries
+                .flatten()
+                .find(|e| names_match(&e.file_name().to_string_lossy(), &name, true))
+        });
+
+        match found {
+            Some(entry) => resolved.push(entry.file_name()),
+            None => {
+                resolved.push(name.as_ref());
+                break;
+            }
+        }
+    }
+
+    for c in components {
+        resolved.push(c);
+    }
+
+    resolved.to_string_lossy().into_owned()
+}
@@ -0,0 +1,289 @@
+//! Management of the node tree: mounting of driver instances, unmounting/eviction of sub trees and
+//! snapshots of the tree for frontends. Removed nodes and driver slots are put on free lists and reused
+//! so long sessions (such as randomizing over a large collection) doesn't grow the tree forever.
+
+use crate::{find_entry_in_node, get_component_name, Node, NodeType, VfsDriverType, VfsState};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[cfg(not(test))]
+use log::trace;
+
+#[cfg(test)]
+use std::println as trace;
+
+/// A driver instance that has been mounted at a node in the tree
+#[derive(Debug)]
+pub(crate) struct NodeDriver {
+    pub(crate) driver: VfsDriverType,
+    /// Node the driver is mounted at. Removing this node will also drop the driver
+    node: u32,
+    last_used: Instant,
+}
+
+/// Serializable copy of (a part of) the node tree. Can be requested with [`crate::Vfs::snapshot`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NodeSnapshot {
+    pub name: String,
+    pub node_type: NodeType,
+    /// Name of the driver mounted at this node (if any)
+    pub driver: Option<String>,
+    pub children: Vec<NodeSnapshot>,
+}
+
+impl NodeSnapshot {
+    /// Find a node given the names of the path components from this node
+    pub fn find(&self, names: &[&str]) -> Option<&NodeSnapshot> {
+        match names.split_first() {
+            None => Some(self),
+            Some((name, rest)) => self.children.iter().find(|c| c.name == *name)?.find(rest),
+        }
+    }
+
+    /// Total number of driver instances mounted in this part of the tree
+    pub fn driver_count(&self) -> usize {
+        self.driver.is_some() as usize + self.children.iter().map(|c| c.driver_count()).sum::<usize>()
+    }
+
+    fn fmt_indent(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        match &self.driver {
+            Some(driver) => writeln!(f, "{:indent$}{} ({})", "", self.name, driver, indent = indent)?,
+            None => writeln!(f, "{:indent$}{}", "", self.name, indent = indent)?,
+        }
+
+        for c in &self.children {
+            c.fmt_indent(f, indent + 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Prints the tree with one node per line (indented by depth) which is useful for debugging
+impl fmt::Display for NodeSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indent(f, 0)
+    }
+}
+
+// Add a new node to the vfs at a specific index and get the new node index back
+pub(crate) fn add_new_node(state: &mut VfsState, index: usize, new_node: Node) -> usize {
+    let new_index = match state.free_nodes.pop() {
+        Some(free) => {
+            state.nodes[free as usize] = new_node;
+            free as usize
+        }
+        None => {
+            state.nodes.push(new_node);
+            state.nodes.len() - 1
+        }
+    };
+
+    state.nodes[index].nodes.push(new_index as u32);
+    new_index
+}
+
+/// Mounts a driver instance at a node and returns the driver index. A driver already mounted at the node is dropped
+pub(crate) fn mount_driver(state: &mut VfsState, node: usize, driver: VfsDriverType) -> usize {
+    let old_index = state.nodes[node].driver_index;
+
+    if old_index != -1 {
+        state.node_drivers[old_index as usize] = None;
+        state.free_drivers.push(old_index as usize);
+    }
+
+    let entry = Some(NodeDriver {
+        driver,
+        node: node as u32,
+        last_used: Instant::now(),
+    });
+
+    let index = match state.free_drivers.pop() {
+        Some(free) => {
+            state.node_drivers[free] = entry;
+            free
+        }
+        None => {
+            state.node_drivers.push(entry);
+            state.node_drivers.len() - 1
+        }
+    };
+
+    state.nodes[node].driver_index = index as _;
+    index
+}
+
+/// Get a mounted driver and mark it as used (and as the driver errors are reported for)
+pub(crate) fn use_driver(state: &mut VfsState, index: usize) -> &mut VfsDriverType {
+    let entry = state.node_drivers[index].as_mut().expect("driver index refers to an unmounted driver");
+    entry.last_used = Instant::now();
+    state.active_driver = Some(entry.driver.name());
+    &mut entry.driver
+}
+
+// Frees a node and everything below it. Drivers mounted in the sub tree are dropped
+fn free_subtree(state: &mut VfsState, index: usize) {
+    clear_node(state, index);
+    state.nodes[index] = Node::default();
+    state.free_nodes.push(index as u32);
+}
+
+// Frees everything below a node and the driver mounted at it, but keeps the node itself
+fn clear_node(state: &mut VfsState, index: usize) {
+    let node = &mut state.nodes[index];
+    let children = std::mem::take(&mut node.nodes);
+    let driver_index = node.driver_index;
+
+    node.driver_index = -1;
+
+    // The directory has to be listed again as the entries are gone
+    if node.node_type == NodeType::Directory {
+        node.node_type = NodeType::Unknown;
+    }
+
+    for child in children {
+        free_subtree(state, child as usize);
+    }
+
+    if driver_index != -1 {
+        state.node_drivers[driver_index as usize] = None;
+        state.free_drivers.push(driver_index as usize);
+    }
+}
+
+/// Removes the sub tree of a node and the node itself. Entries of listed directories are kept (without
+/// children and driver) so the listing stays complete. Parents that are left without children and driver
+/// are removed as well as they will be created again on the next load.
+pub(crate) fn remove_node(state: &mut VfsState, index: usize) {
+    let mut index = index;
+
+    clear_node(state, index);
+
+    // never remove the root
+    while index != 0 {
+        let parent = state.nodes[index].parent as usize;
+        let node = &state.nodes[index];
+        let is_listed = parent != 0 && state.nodes[parent].node_type == NodeType::Directory;
+
+        if is_listed || !node.nodes.is_empty() || node.driver_index != -1 {
+            break;
+        }
+
+        state.nodes[parent].nodes.retain(|n| *n as usize != index);
+        free_subtree(state, index);
+        index = parent;
+    }
+}
+
+/// Find the node for a full path. Returns None if the path isn't (fully) present in the tree
+pub(crate) fn find_path(state: &VfsState, path: &str) -> Option<usize> {
+    let mut index = 0;
+    let mut had_prefix = false;
+
+    for c in Path::new(path).components() {
+        let name = get_component_name(&c, &mut had_prefix);
+        index = find_entry_in_node(&state.nodes[index], &state.nodes, &name, state.config.case_insensitive)?;
+    }
+
+    Some(index)
+}
+
+/// Find the node for a full path. Missing nodes along the path are added (with unknown type)
+pub(crate) fn add_path(state: &mut VfsState, path: &str) -> usize {
+    let mut index = 0;
+    let mut had_prefix = false;
+
+    for c in Path::new(path).components() {
+        let name = get_component_name(&c, &mut had_prefix);
+
+        index = match find_entry_in_node(&state.nodes[index], &state.nodes, &name, state.config.case_insensitive) {
+            Some(entry) => entry,
+            None => add_new_node(state, index, Node::new_unknown_node(name.into_owned(), index as _)),
+        };
+    }
+
+    index
+}
+
+/// Removes the node at the path with all drivers mounted below it and drops cached data loaded from it
+pub(crate) fn unmount(state: &mut VfsState, path: &str) {
+    state.cached_data.retain(|e| !Path::new(&e.path).starts_with(path));
+
+    match find_path(state, path) {
+        Some(0) | None => trace!("Unmount: {} isn't mounted", path),
+        Some(index) => remove_node(state, index),
+    }
+}
+
+/// Adds a newly written file to the listing of the directory it was written to. If the file was written to
+/// new directories the first of those is added instead. Directories that haven't been listed are left as is.
+pub(crate) fn add_written_file(state: &mut VfsState, url: &str) {
+    let path = Path::new(url);
+
+    for dir in path.ancestors().skip(1) {
+        let Some(index) = find_path(state, &dir.to_string_lossy()) else {
+            continue;
+        };
+
+        let Some(name) = path.strip_prefix(dir).ok().and_then(|p| p.iter().next()) else {
+            return;
+        };
+
+        let name = name.to_string_lossy();
+        let node = &state.nodes[index];
+
+        if index == 0 || node.node_type != NodeType::Directory || node.nodes.iter().any(|n| state.nodes[*n as usize].name == name) {
+            return;
+        }
+
+        let new_node = if dir == path.parent().unwrap_or(dir) {
+            Node::new_file_node(name.into_owned(), index as _)
+        } else {
+            Node::new_unknown_node(name.into_owned(), index as _)
+        };
+
+        add_new_node(state, index, new_node);
+        return;
+    }
+}
+
+/// Drops all driver instances that hasn't been used for the given duration. The nodes they were mounted at are
+/// removed so the next load of a path within them will mount the driver again.
+pub(crate) fn evict_idle(state: &mut VfsState, max_idle: Duration) {
+    let now = Instant::now();
+
+    for i in 0..state.node_drivers.len() {
+        // The slot may already have been freed if the driver was mounted inside an evicted driver
+        let node = match &state.node_drivers[i] {
+            Some(d) if now.duration_since(d.last_used) >= max_idle => d.node as usize,
+            _ => continue,
+        };
+
+        trace!("Evicting driver {} at node {}", i, state.nodes[node].name);
+        remove_node(state, node);
+    }
+}
+
+fn snapshot_node(state: &VfsState, index: usize) -> NodeSnapshot {
+    let node = &state.nodes[index];
+
+    let driver = if node.driver_index != -1 {
+        state.node_drivers[node.driver_index as usize].as_ref().map(|d| d.driver.name().to_owned())
+    } else {
+        None
+    };
+
+    NodeSnapshot {
+        name: node.name.clone(),
+        node_type: node.node_type.clone(),
+        driver,
+        children: node.nodes.iter().map(|n| snapshot_node(state, *n as usize)).collect(),
+    }
+}
+
+/// Creates a snapshot of the whole tree
+pub(crate) fn snapshot(state: &VfsState) -> NodeSnapshot {
+    snapshot_node(state, 0)
+}
@@ -1,9 +1,10 @@
-use crate::{InternalError, LoadStatus, Progress, VfsDriver, VfsDriverType, FilesDirs};
-use std::collections::HashSet;
+use crate::{Bytes, InternalError, LoadStatus, Progress, VfsDriver, VfsDriverType, FilesDirs};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{Cursor, Read};
-use zip::ZipArchive;
-use std::borrow::Cow;
+use std::io::{Cursor, Read, Seek};
+use zip::{CompressionMethod, ZipArchive};
+use crate::encoding::{self, LegacyEncoding};
+use crate::path_match::normalize_separators;
 
 // This is kinda ugly, but better than testing non-supported paths on a remote server
 #[cfg(target_os = "windows")]
@@ -21,22 +22,132 @@ use std::{println as trace, println as error};
 #[derive(Debug)]
 enum ZipInternal {
     FileReader(ZipArchive<File>),
-    MemReader(ZipArchive<Cursor<Box<[u8]>>>),
+    /// Archive in memory (loaded or memory mapped). The buffer is kept so stored entries can be returned without copying
+    MemReader(ZipArchive<Cursor<Bytes>>, Bytes),
     None,
 }
 
 #[derive(Debug)]
 pub struct ZipFs {
     data: ZipInternal,
+    /// Encoding used for names that aren't UTF-8. If None it's detected per archive
+    name_encoding: Option<LegacyEncoding>,
+    /// Decoded names of the entries in archive order
+    names: Vec<String>,
+    /// Maps the decoded names back to the index in the archive so they can be used for loading
+    name_lookup: HashMap<String, usize>,
+    /// All directories in the archive (without trailing `/`). Many archives only have them implicitly as
+    /// part of the file names so they are collected from all names
+    dirs: HashSet<String>,
+}
+
+// Decodes the names of all entries. Names that aren't valid UTF-8 are decoded with the given encoding or if
+// there is none, the encoding detected from all the non UTF-8 names in the archive. Names that end up the same as
+// an earlier one are made unique (see unique_names) so every entry can be loaded by its name.
+fn decode_names<R: Read + Seek>(archive: &mut ZipArchive<R>, encoding: Option<LegacyEncoding>) -> Vec<String> {
+    let raw_names: Vec<Vec<u8>> = (0..archive.len())
+        .map(|i| archive.by_index_raw(i).map(|f| f.name_raw().to_vec()).unwrap_or_default())
+        .collect();
+
+    let encoding = encoding.unwrap_or_else(|| {
+        let mut legacy_names = Vec::new();
+
+        for name in raw_names.iter().filter(|n| std::str::from_utf8(n).is_err()) {
+            legacy_names.extend_from_slice(name);
+            legacy_names.push(b'/');
+        }
+
+        encoding::detect(&legacy_names)
+    });
+
+    let names = raw_names
+        .iter()
+        .map(|name| {
+            let name = match std::str::from_utf8(name) {
+                Ok(name) => name.to_owned(),
+                Err(_) => encoding::decode(name, encoding),
+            };
+
+            normalize_separators(&name).into_owned()
+        })
+        .collect();
+
+    unique_names(names)
+}
+
+// Decoding can map different raw names to the same text (such as a UTF-8 name and a CP437 name for the same text
+// or invalid sequences that all become U+FFFD). Later files with a name already used get " (2)", " (3)" ... added
+// before the extension. Directory entries are left as is as they don't refer to any data.
+fn unique_names(mut names: Vec<String>) -> Vec<String> {
+    let all: HashSet<String> = names.iter().cloned().collect();
+
+    if all.len() == names.len() {
+        return names;
+    }
+
+    let mut used = HashSet::with_capacity(names.len());
+
+    for name in names.iter_mut() {
+        if name.ends_with('/') || used.insert(name.clone()) {
+            continue;
+        }
+
+        let file_start = name.rfind('/').map_or(0, |pos| pos + 1);
+        let (stem, ext) = match name[file_start..].rfind('.') {
+            Some(pos) if pos > 0 => name.split_at(file_start + pos),
+            _ => (name.as_str(), ""),
+        };
+
+        let unique = (2..)
+            .map(|n| format!("{} ({}){}", stem, n, ext))
+            .find(|candidate| !all.contains(candidate) && !used.contains(candidate))
+            .unwrap();
+
+        trace!("Zip entry name {} is already used, using {}", name, unique);
+        used.insert(unique.clone());
+        *name = unique;
+    }
+
+    names
 }
 
 impl ZipFs {
-    pub fn new() -> ZipFs {
+    pub fn new(name_encoding: Option<LegacyEncoding>) -> ZipFs {
         ZipFs {
             data: ZipInternal::None,
+            name_encoding,
+            names: Vec::new(),
+            name_lookup: HashMap::new(),
+            dirs: HashSet::new(),
         }
     }
 
+    fn from_archive(data: ZipInternal, name_encoding: Option<LegacyEncoding>, names: Vec<String>) -> ZipFs {
+        let mut name_lookup = HashMap::with_capacity(names.len());
+        let mut dirs = HashSet::new();
+
+        for (index, name) in names.iter().enumerate() {
+            // Names are unique apart from directory entries (see unique_names)
+            name_lookup.entry(name.clone()).or_insert(index);
+
+            for (pos, _) in name.match_indices('/').filter(|(pos, _)| *pos > 0) {
+                dirs.insert(name[..pos].to_owned());
+            }
+        }
+
+        ZipFs {
+            data,
+            name_encoding,
+            names,
+            name_lookup,
+            dirs,
+        }
+    }
+
+    fn is_directory(&self, path: &str) -> bool {
+        self.dirs.contains(path.trim_end_matches('/'))
+    }
+
     fn get_dirs(
         path: &str,
         progress: &mut Progress,
@@ -62,6 +173,11 @@ impl ZipFs {
 
             let t = &p[dir_len..];
 
+            // the entry for the directory itself
+            if t.is_empty() {
+                continue;
+            }
+
             if let Some(pos) = t.find('/') {
                 if pos <= t.len() {
                     paths.insert(t[..pos].to_owned());
@@ -113,7 +229,7 @@ impl VfsDriver for ZipFs {
 
     // Create a new instance given data. The VfsDriver will take ownership of the data
     fn create_instance(&self) -> VfsDriverType {
-        Box::new(ZipFs::new())
+        Box::new(ZipFs::new(self.name_encoding))
     }
 
     // Get some data in and returns true if driver can be mounted from it
@@ -123,16 +239,11 @@ impl VfsDriver for ZipFs {
     }
 
     // Create a new instance given data. The VfsDriver will take ownership of the data
-    fn create_from_data(&self, data: Box<[u8]>) -> Option<VfsDriverType> {
-        let a = match ZipArchive::new(std::io::Cursor::new(data)) {
-            Ok(a) => a,
-            Err(e) => {
-                error!("ZipFs Error: {:}", e);
-                return None;
-            }
-        };
+    fn create_from_data(&self, data: Bytes) -> Result<VfsDriverType, InternalError> {
+        let mut a = ZipArchive::new(std::io::Cursor::new(data.clone()))?;
+        let names = decode_names(&mut a, self.name_encoding);
 
-        Some(Box::new(ZipFs { data: ZipInternal::MemReader(a) }))
+        Ok(Box::new(ZipFs::from_archive(ZipInternal::MemReader(a, data), self.name_encoding, names)))
     }
 
     // Get some data in and returns true if driver can be mounted from it
@@ -155,26 +266,20 @@ impl VfsDriver for ZipFs {
     }
 
     /// Used when creating an instance of the driver with a path to load from
-    fn create_from_url(&self, url: &str) -> Option<VfsDriverType> {
-        let read_file = match File::open(url) {
-            Ok(f) => f,
-            Err(e) => {
-                error!("ZipFs File Error: {:}", e);
-                return None;
-            }
-        };
+    fn create_from_url(&self, url: &str) -> Result<VfsDriverType, InternalError> {
+        let read_file = File::open(url)?;
+
+        // Map the archive so entries can be read without seeking and stored entries can be shared. If the file
+        // can't be mapped (for example archives over 4GB on 32-bit targets) we read from the file instead
+        match crate::map_file(&read_file) {
+            Ok(data) => return self.create_from_data(data),
+            Err(e) => trace!("zip_fs: unable to map {}: {:?}, reading from file", url, e),
+        }
 
-        let a = match ZipArchive::new(read_file) {
-            Ok(a) => a,
-            Err(e) => {
-                error!("ZipFs Error: {:}", e);
-                return None;
-            }
-        };
+        let mut a = ZipArchive::new(read_file)?;
+        let names = decode_names(&mut a, self.name_encoding);
 
-        Some(Box::new(ZipFs {
-            data: ZipInternal::FileReader(a),
-        }))
+        Ok(Box::new(ZipFs::from_archive(ZipInternal::FileReader(a), self.name_encoding, names)))
     }
 
     /// Returns a handle which updates the progress and returns the loaded data. This will try to
@@ -188,65 +293,89 @@ impl VfsDriver for ZipFs {
         }
 
         // The zip loader doesn't support \ for internal paths so replace with /
-        let path: Cow<str> = if !path.contains('\\') {
-            path.into()
-        } else {
-            path.replace('\\', "/").into()
-        };
+        let path = normalize_separators(path);
 
-        let read_file = match &mut self.data {
-            ZipInternal::FileReader(a) => a.by_name(&path),
-            ZipInternal::MemReader(a) => a.by_name(&path),
-            ZipInternal::None => return Ok(LoadStatus::NotFound),
-        };
+        // Directories may only exist implicitly as part of the file names so check for those first
+        if self.is_directory(&path) {
+            return Ok(LoadStatus::Directory);
+        }
 
-        let mut file = match read_file {
-            Err(_) => {
+        // Look up the entry by the decoded name so names shown in listings can be loaded back
+        let index = match self.name_lookup.get(path.as_ref()) {
+            Some(index) => *index,
+            None => {
                 trace!("file not found: {}", path);
                 return Ok(LoadStatus::NotFound);
             }
-            Ok(f) => f,
         };
 
-        if file.is_dir() {
-            return Ok(LoadStatus::Directory);
+        match &mut self.data {
+            ZipInternal::FileReader(a) => read_entry(a, None, index, &path, progress),
+            ZipInternal::MemReader(a, data) => read_entry(a, Some(data), index, &path, progress),
+            ZipInternal::None => Ok(LoadStatus::NotFound),
         }
+    }
 
-        let len = file.size() as usize;
-        let mut output_data = vec![0u8; len];
+    fn get_directory_list(
+        &mut self,
+        path: &str,
+        progress: &mut Progress,
+    ) -> Result<FilesDirs, InternalError> {
+        let path = normalize_separators(path);
+        Self::get_dirs(&path, progress, &mut self.names.iter().map(|n| n.as_str()))
+    }
+}
+
+// Unpacks an entry. Generic over the reader as the archive is either backed by a file or by shared memory.
+// Uncompressed entries in memory backed archives are returned as a slice of the archive data.
+fn read_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    shared: Option<&Bytes>,
+    index: usize,
+    path: &str,
+    progress: &mut Progress,
+) -> Result<LoadStatus, InternalError> {
+    // The entry is known to exist so failing here means that the archive is broken
+    let mut file = archive.by_index(index)?;
+    trace!("zip_fs: reading {}", path);
+
+    if file.is_dir() {
+        return Ok(LoadStatus::Directory);
+    }
+
+    let len = file.size() as usize;
 
-        // if file is small than 10k we just unpack it directly without progress
-        if len < 10 * 1024 {
+    if let (Some(data), Some(start)) = (shared, file.data_start()) {
+        let start = start as usize;
+
+        if file.compression() == CompressionMethod::Stored && !file.encrypted() && start + len <= data.len() {
             progress.set_step(1);
-            file.read_to_end(&mut output_data)?;
             progress.step()?;
-        } else {
-            // above 10k we read in 10 chunks
-            let loop_count = 10;
-            let block_len = len / loop_count;
+            return Ok(LoadStatus::Data(data.slice(start..start + len)));
+        }
+    }
 
-            progress.set_step(loop_count);
+    let mut output_data = vec![0u8; len];
 
-            for i in 0..loop_count + 1 {
-                let block_offset = i * block_len;
-                let read_amount = usize::min(len - block_offset, block_len);
-                file.read_exact(&mut output_data[block_offset..block_offset + read_amount])?;
-                progress.step()?;
-            }
-        }
+    // if file is small than 10k we just unpack it directly without progress
+    if len < 10 * 1024 {
+        progress.set_step(1);
+        file.read_exact(&mut output_data)?;
+        progress.step()?;
+    } else {
+        // above 10k we read in 10 chunks
+        let loop_count = 10;
+        let block_len = len / loop_count;
 
-        Ok(LoadStatus::Data(output_data.into_boxed_slice()))
-    }
+        progress.set_step(loop_count);
 
-    fn get_directory_list(
-        &mut self,
-        path: &str,
-        progress: &mut Progress,
-    ) -> Result<FilesDirs, InternalError> {
-        match &self.data {
-            ZipInternal::FileReader(a) => Self::get_dirs(path, progress, &mut a.file_names()),
-            ZipInternal::MemReader(a) => Self::get_dirs(path, progress, &mut a.file_names()),
-            ZipInternal::None => Ok(FilesDirs::default()),
+        for i in 0..loop_count + 1 {
+            let block_offset = i * block_len;
+            let read_amount = usize::min(len - block_offset, block_len);
+            file.read_exact(&mut output_data[block_offset..block_offset + read_amount])?;
+            progress.step()?;
         }
     }
+
+    Ok(LoadStatus::Data(output_data.into()))
 }